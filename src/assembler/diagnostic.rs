@@ -0,0 +1,178 @@
+// structured assembler messages, rendered by the CLI or handed to the web
+// editor as markers
+use crate::utils::json_escape;
+
+use std::fmt;
+
+// location of a token in the source: 1-based line, 0-based byte column
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+    pub len: usize,
+}
+
+impl Span {
+    pub fn new(line: usize, col: usize, len: usize) -> Span {
+        Span { line, col, len }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub span: Span,
+    pub message: String,
+    pub hint: Option<String>,
+}
+
+// ANSI colours used when rendering to a terminal
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const CYAN: &str = "\x1b[1;36m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+impl Diagnostic {
+
+    pub fn error(span: Span, message: &str) -> Diagnostic {
+        Diagnostic { severity: Severity::Error, span, message: message.to_string(), hint: None }
+    }
+
+    pub fn warning(span: Span, message: &str) -> Diagnostic {
+        Diagnostic { severity: Severity::Warning, span, message: message.to_string(), hint: None }
+    }
+
+    pub fn with_hint(mut self, hint: &str) -> Diagnostic {
+        self.hint = Some(hint.to_string());
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /**
+     * render as `file:line:col: severity: message` followed by the source line
+     * with a caret under the offending token
+     */
+    pub fn render(&self, file_name: &str, source: &str, color: bool) -> String {
+        let paint = |code: &'static str| if color { code } else { "" };
+        let severity_color = match self.severity {
+            Severity::Error => RED,
+            Severity::Warning => YELLOW,
+        };
+        let mut out = format!(
+            "{}{}:{}:{}:{} {}{}:{} {}{}{}\n",
+            paint(BOLD), file_name, self.span.line, self.span.col + 1, paint(RESET),
+            paint(severity_color), self.severity, paint(RESET),
+            paint(BOLD), self.message, paint(RESET),
+        );
+        if let Some(text) = source.lines().nth(self.span.line.wrapping_sub(1)) {
+            // keep tabs so the caret lines up with the echoed source
+            let pad: String = text.get(..self.span.col).unwrap_or(text).chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+            out.push_str(&format!("    {}\n", text));
+            out.push_str(&format!(
+                "    {}{}{}{}\n",
+                pad, paint(severity_color), "^".repeat(self.span.len.max(1)), paint(RESET),
+            ));
+        }
+        if let Some(hint) = &self.hint {
+            out.push_str(&format!("    {}hint:{} {}\n", paint(CYAN), paint(RESET), hint));
+        }
+        out
+    }
+
+    /**
+     * a Monaco-style editor marker for a diagnostic in `source`; columns are
+     * 1-based and count UTF-16 code units, as JS strings do
+     */
+    pub fn to_json(&self, source: &str) -> String {
+        let start = utf16_column(source, self.span.line, self.span.col);
+        let end = utf16_column(source, self.span.line, self.span.col + self.span.len).max(start + 1);
+        format!(
+            "{{\"severity\":\"{}\",\"line\":{},\"startColumn\":{},\"endColumn\":{},\"message\":\"{}\",\"hint\":{}}}",
+            self.severity, self.span.line, start + 1, end + 1,
+            json_escape(&self.message),
+            match &self.hint {
+                Some(hint) => format!("\"{}\"", json_escape(hint)),
+                None => "null".to_string(),
+            },
+        )
+    }
+}
+
+// UTF-16 offset of a byte column in a 1-based source line, the byte column itself past the source
+fn utf16_column(source: &str, line: usize, col: usize) -> usize {
+    match source.lines().nth(line.wrapping_sub(1)).and_then(|text| text.get(..col)) {
+        Some(prefix) => prefix.encode_utf16().count(),
+        None => col,
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}: {}", self.span.line, self.span.col + 1, self.severity, self.message)
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+#[cfg(test)]
+mod diagnostic_test {
+
+    use super::*;
+
+    #[test]
+    fn render_with_caret_and_hint() {
+        let source = ".ORIG x3000\nADD R1, R1, #40\n.END\n";
+        let diagnostic = Diagnostic::error(Span::new(2, 12, 3), "immediate #40 does not fit in imm5 (range -16..15)")
+            .with_hint("load larger constants with LD from a .FILL");
+        let text = diagnostic.render("simple.asm", source, false);
+        assert_eq!(text, "simple.asm:2:13: error: immediate #40 does not fit in imm5 (range -16..15)\n    \
+                          ADD R1, R1, #40\n                \
+                          ^^^\n    \
+                          hint: load larger constants with LD from a .FILL\n");
+    }
+
+    #[test]
+    fn render_in_colour() {
+        let diagnostic = Diagnostic::warning(Span::new(1, 0, 5), "missing .END");
+        let text = diagnostic.render("a.asm", "HALT", true);
+        assert!(text.contains("\x1b[1;33mwarning"));
+    }
+
+    #[test]
+    fn json_marker() {
+        let diagnostic = Diagnostic::error(Span::new(3, 4, 2), "undefined label `X\"`");
+        assert_eq!(
+            diagnostic.to_json("\n\nBR X\"\n"),
+            "{\"severity\":\"error\",\"line\":3,\"startColumn\":5,\"endColumn\":7,\"message\":\"undefined label `X\\\"`\",\"hint\":null}",
+        );
+    }
+
+    #[test]
+    fn json_marker_counts_utf16_columns() {
+        // "é" is two bytes but one UTF-16 unit, "😀" four bytes and two units
+        let source = ".STRINGZ \"é😀\" LOOP";
+        let diagnostic = Diagnostic::error(Span::new(1, 18, 4), "undefined label `LOOP`");
+        assert!(diagnostic.to_json(source).contains("\"startColumn\":16,\"endColumn\":20"));
+        let text = diagnostic.render("a.asm", source, false);
+        assert!(text.ends_with(&format!("\n    {}^^^^\n", " ".repeat(14))));
+    }
+}
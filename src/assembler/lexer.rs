@@ -0,0 +1,177 @@
+// splits one line of LC-3 assembly into tokens
+use super::{Diagnostic, Span};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    // opcodes, directives (with their leading '.') and labels
+    Ident(String),
+    Register(u16),
+    Number(i32),
+    Str(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub text: String,
+    pub span: Span,
+}
+
+fn is_separator(c: char) -> bool {
+    c.is_whitespace() || c == ','
+}
+
+fn parse_digits(digits: &str, radix: u32) -> Option<i32> {
+    let (negative, digits) = match digits.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, digits),
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    let value = i32::from_str_radix(digits, radix).ok()?;
+    Some(if negative { -value } else { value })
+}
+
+/**
+ * parse a numeric literal: #decimal, plain decimal, xHEX / 0xHEX or bBINARY
+ */
+pub fn parse_number(text: &str) -> Option<i32> {
+    if let Some(rest) = text.strip_prefix('#') {
+        return parse_digits(rest, 10);
+    }
+    if let Some(rest) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        return parse_digits(rest, 16);
+    }
+    match text.chars().next() {
+        Some('x') | Some('X') => parse_digits(&text[1..], 16),
+        Some('b') | Some('B') => parse_digits(&text[1..], 2),
+        Some(c) if c.is_ascii_digit() || c == '-' => parse_digits(text, 10),
+        _ => None,
+    }
+}
+
+// `xAB` or `B1` read as numbers but are also valid label names
+pub fn is_label_like(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+}
+
+fn parse_register(text: &str) -> Option<u16> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('r'), Some(d), None) | (Some('R'), Some(d), None) => {
+            d.to_digit(8).map(|n| n as u16)
+        },
+        _ => None,
+    }
+}
+
+fn classify(text: &str) -> TokenKind {
+    if let Some(reg) = parse_register(text) {
+        TokenKind::Register(reg)
+    } else if let Some(value) = parse_number(text) {
+        TokenKind::Number(value)
+    } else {
+        TokenKind::Ident(text.to_string())
+    }
+}
+
+/**
+ * tokenize a single source line (1-based `line` number), dropping comments
+ */
+pub fn tokenize(source: &str, line: usize) -> Result<Vec<Token>, Diagnostic> {
+    let chars: Vec<(usize, char)> = source.char_indices().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let (start, c) = chars[i];
+        if is_separator(c) {
+            i += 1;
+        } else if c == ';' {
+            break;
+        } else if c == '"' {
+            let mut value = String::new();
+            let mut closed = false;
+            i += 1;
+            while i < chars.len() {
+                let (_, c) = chars[i];
+                i += 1;
+                match c {
+                    '"' => {
+                        closed = true;
+                        break;
+                    },
+                    '\\' if i < chars.len() => {
+                        let (_, escaped) = chars[i];
+                        i += 1;
+                        value.push(match escaped {
+                            'n' => '\n',
+                            't' => '\t',
+                            'r' => '\r',
+                            'e' => '\x1b',
+                            '0' => '\0',
+                            other => other,
+                        });
+                    },
+                    _ => value.push(c),
+                }
+            }
+            let end = if i < chars.len() { chars[i].0 } else { source.len() };
+            let span = Span::new(line, start, end - start);
+            if !closed {
+                return Err(Diagnostic::error(span, "unterminated string literal"));
+            }
+            tokens.push(Token { kind: TokenKind::Str(value), text: source[start..end].to_string(), span });
+        } else {
+            while i < chars.len() && !is_separator(chars[i].1) && chars[i].1 != ';' {
+                i += 1;
+            }
+            let end = if i < chars.len() { chars[i].0 } else { source.len() };
+            let text = &source[start..end];
+            tokens.push(Token { kind: classify(text), text: text.to_string(), span: Span::new(line, start, end - start) });
+        }
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod lexer_test {
+
+    use super::*;
+
+    #[test]
+    fn numeric_literals() {
+        assert_eq!(parse_number("#15"), Some(15));
+        assert_eq!(parse_number("#-16"), Some(-16));
+        assert_eq!(parse_number("x3000"), Some(0x3000));
+        assert_eq!(parse_number("0xFE00"), Some(0xFE00));
+        assert_eq!(parse_number("b1010"), Some(10));
+        assert_eq!(parse_number("42"), Some(42));
+        assert_eq!(parse_number("xLOOP"), None);
+        assert_eq!(parse_number("BACK"), None);
+        assert_eq!(parse_number("BAD"), None);
+        assert!(is_label_like("xAB") && is_label_like("B1"));
+        assert!(!is_label_like("#1") && !is_label_like("0x10") && !is_label_like("42"));
+    }
+
+    #[test]
+    fn tokenize_instruction_with_comment() {
+        let tokens = tokenize("LOOP ADD R1, R1, #-1 ; count down", 3).unwrap();
+        let kinds: Vec<TokenKind> = tokens.iter().map(|t| t.kind.clone()).collect();
+        assert_eq!(kinds, vec![
+            TokenKind::Ident("LOOP".to_string()),
+            TokenKind::Ident("ADD".to_string()),
+            TokenKind::Register(1),
+            TokenKind::Register(1),
+            TokenKind::Number(-1),
+        ]);
+        assert_eq!(tokens[4].span, Span::new(3, 17, 3));
+    }
+
+    #[test]
+    fn tokenize_string_with_escapes() {
+        let tokens = tokenize(r#"MSG .STRINGZ "a;b\n""#, 1).unwrap();
+        assert_eq!(tokens[2].kind, TokenKind::Str("a;b\n".to_string()));
+        assert!(tokenize(r#".STRINGZ "oops"#, 1).is_err());
+    }
+}
@@ -0,0 +1,650 @@
+/*
+This is the LC-3 Assembler Module, a two-pass assembler turning .asm sources
+into an object image plus a symbol table
+*/
+pub mod lexer;
+pub mod diagnostic;
+
+use crate::instruction::decode::*;
+use crate::instruction::trap::*;
+use crate::loader::ObjectImage;
+use lexer::*;
+pub use diagnostic::{Diagnostic, Severity, Span};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+// label name => address
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    symbols: BTreeMap<String, u16>,
+}
+
+impl SymbolTable {
+
+    pub fn new() -> SymbolTable {
+        SymbolTable { symbols: BTreeMap::new() }
+    }
+
+    pub fn insert(&mut self, name: &str, address: u16) {
+        self.symbols.insert(name.to_string(), address);
+    }
+
+    pub fn get(&self, name: &str) -> Option<u16> {
+        self.symbols.get(name).copied()
+    }
+
+    // first label (alphabetically) bound to an address
+    pub fn name_of(&self, address: u16) -> Option<&str> {
+        self.symbols.iter().find(|(_, a)| **a == address).map(|(name, _)| name.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u16)> {
+        self.symbols.iter().map(|(name, address)| (name.as_str(), *address))
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    // read back a .sym file as written by `Display`, skipping lines it does not understand
+    pub fn parse(text: &str) -> SymbolTable {
+        let mut table = SymbolTable::new();
+        for line in text.lines() {
+            let mut fields = line.trim_start_matches("//").split_whitespace();
+            if let (Some(name), Some(address), None) = (fields.next(), fields.next(), fields.next()) {
+                if let Ok(address) = u16::from_str_radix(address, 16) {
+                    table.insert(name, address);
+                }
+            }
+        }
+        table
+    }
+}
+
+// render in the same layout as the classic lc3as .sym file
+impl fmt::Display for SymbolTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "// Symbol table")?;
+        writeln!(f, "// Scope level 0:")?;
+        writeln!(f, "//\tSymbol Name       Page Address")?;
+        writeln!(f, "//\t----------------  ------------")?;
+        let mut by_address: Vec<(&str, u16)> = self.iter().collect();
+        by_address.sort_by_key(|(name, address)| (*address, *name));
+        for (name, address) in by_address {
+            writeln!(f, "//\t{:<16}  {:04X}", name, address)?;
+        }
+        Ok(())
+    }
+}
+
+// the result of assembling a source file
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub image: ObjectImage,
+    pub symbols: SymbolTable,
+    // address => 1-based source line that produced the word
+    pub source_map: BTreeMap<u16, usize>,
+    // addresses holding instructions rather than directive data
+    pub instructions: BTreeSet<u16>,
+    // warnings produced while assembling
+    pub warnings: Vec<Diagnostic>,
+}
+
+// one parsed source line
+struct Statement {
+    label: Option<Token>,
+    op: Option<Token>,
+    operands: Vec<Token>,
+    line: usize,
+}
+
+const MNEMONICS: [&str; 23] = [
+    "ADD", "AND", "NOT", "JMP", "JSR", "JSRR", "LD", "LDI", "LDR", "LEA", "ST", "STI", "STR",
+    "RET", "RTI", "TRAP", "GETC", "OUT", "PUTS", "IN", "PUTSP", "HALT", "BR",
+];
+
+// BR, BRn, BRzp, ... => (n, z, p)
+fn branch_flags(name: &str) -> Option<(bool, bool, bool)> {
+    let flags = name.strip_prefix("BR")?;
+    if flags.is_empty() {
+        return Some((true, true, true));
+    }
+    let order = ["N", "Z", "P"];
+    let mut rest = flags;
+    let mut nzp = [false; 3];
+    for (i, flag) in order.iter().enumerate() {
+        if let Some(r) = rest.strip_prefix(flag) {
+            nzp[i] = true;
+            rest = r;
+        }
+    }
+    if rest.is_empty() {
+        Some((nzp[0], nzp[1], nzp[2]))
+    } else {
+        None
+    }
+}
+
+fn is_operation(token: &Token) -> bool {
+    match &token.kind {
+        TokenKind::Ident(name) => {
+            let upper = name.to_uppercase();
+            upper.starts_with('.') || MNEMONICS.contains(&upper.as_str()) || branch_flags(&upper).is_some()
+        },
+        _ => false,
+    }
+}
+
+fn parse_line(source: &str, line: usize) -> Result<Statement, Diagnostic> {
+    let mut tokens = tokenize(source, line)?.into_iter().peekable();
+    let mut statement = Statement { label: None, op: None, operands: Vec::new(), line };
+    if let Some(first) = tokens.peek() {
+        if !is_operation(first) {
+            let mut label = tokens.next().unwrap();
+            // a literal only counts as one in operand position
+            if matches!(label.kind, TokenKind::Number(_)) && is_label_like(&label.text) {
+                label.kind = TokenKind::Ident(label.text.clone());
+            }
+            if !matches!(label.kind, TokenKind::Ident(_)) {
+                return Err(Diagnostic::error(label.span, &format!("expected a label or an instruction, found `{}`", label.text)));
+            }
+            // `FOO R1` is a misspelled instruction rather than a label
+            if let Some(next) = tokens.peek() {
+                if !matches!(next.kind, TokenKind::Ident(_)) {
+                    return Err(Diagnostic::error(label.span, &format!("unknown instruction `{}`", label.text)));
+                }
+            }
+            statement.label = Some(label);
+        }
+    }
+    statement.op = tokens.next();
+    statement.operands = tokens.collect();
+    Ok(statement)
+}
+
+fn op_name(token: &Token) -> String {
+    token.text.to_uppercase()
+}
+
+fn expect_operands(stmt: &Statement, op: &Token, count: usize) -> Result<(), Diagnostic> {
+    if stmt.operands.len() != count {
+        let span = stmt.operands.get(count).map(|t| t.span).unwrap_or(op.span);
+        return Err(Diagnostic::error(span, &format!(
+            "{} expects {} operand{}, found {}",
+            op_name(op), count, if count == 1 { "" } else { "s" }, stmt.operands.len(),
+        )));
+    }
+    Ok(())
+}
+
+fn register(token: &Token) -> Result<u16, Diagnostic> {
+    match token.kind {
+        TokenKind::Register(r) => Ok(r),
+        _ => Err(Diagnostic::error(token.span, &format!("expected a register R0-R7, found `{}`", token.text))),
+    }
+}
+
+fn number(token: &Token) -> Result<i32, Diagnostic> {
+    match token.kind {
+        TokenKind::Number(n) => Ok(n),
+        _ => Err(Diagnostic::error(token.span, &format!("expected a number, found `{}`", token.text))),
+    }
+}
+
+// signed immediate that must fit in `bits` bits
+fn immediate(token: &Token, bits: u32, what: &str) -> Result<i16, Diagnostic> {
+    let value = number(token)?;
+    let (min, max) = (-(1 << (bits - 1)), (1 << (bits - 1)) - 1);
+    if value < min || value > max {
+        return Err(Diagnostic::error(token.span, &format!(
+            "immediate #{} does not fit in {} (range {}..{})", value, what, min, max,
+        )));
+    }
+    Ok(value as i16)
+}
+
+// an operand such as `xAB` that lexed as a number but names a defined label is the label
+fn prefer_label(token: &Token, symbols: &SymbolTable) -> Token {
+    let mut token = token.clone();
+    if matches!(token.kind, TokenKind::Number(_)) && is_label_like(&token.text) && symbols.get(&token.text).is_some() {
+        token.kind = TokenKind::Ident(token.text.clone());
+    }
+    token
+}
+
+// a label (resolved relative to the incremented PC) or a raw offset
+fn pc_offset(token: &Token, address: u16, bits: u32, symbols: &SymbolTable) -> Result<i16, Diagnostic> {
+    let (min, max) = (-(1 << (bits - 1)), (1 << (bits - 1)) - 1);
+    let token = &prefer_label(token, symbols);
+    match &token.kind {
+        TokenKind::Ident(name) => {
+            let target = symbols.get(name).ok_or_else(|| undefined_label(token, name, symbols))?;
+            let offset = target as i32 - (address as i32 + 1);
+            if offset < min || offset > max {
+                return Err(Diagnostic::error(token.span, &format!(
+                    "label `{}` is {} words away, out of range for PCoffset{} ({}..{})", name, offset, bits, min, max,
+                )).with_hint("keep the label's address in a nearby .FILL and use LDI, or LEA/LDR through a register"));
+            }
+            Ok(offset as i16)
+        },
+        TokenKind::Number(_) => immediate(token, bits, &format!("PCoffset{}", bits)),
+        _ => Err(Diagnostic::error(token.span, &format!("expected a label or an offset, found `{}`", token.text))),
+    }
+}
+
+// reference to a label that was never defined, suggesting a near miss
+fn undefined_label(token: &Token, name: &str, symbols: &SymbolTable) -> Diagnostic {
+    let diagnostic = Diagnostic::error(token.span, &format!("undefined label `{}`", name));
+    match symbols.iter().find(|(other, _)| other.eq_ignore_ascii_case(name)) {
+        Some((other, _)) => diagnostic.with_hint(&format!("did you mean `{}`? labels are case-sensitive", other)),
+        None => diagnostic,
+    }
+}
+
+fn alu_operand(token: &Token) -> Result<Operand, Diagnostic> {
+    match token.kind {
+        TokenKind::Register(r) => Ok(Operand::Reg(r)),
+        _ => immediate(token, 5, "imm5")
+            .map(Operand::Imm5)
+            .map_err(|d| d.with_hint("load larger constants with LD from a .FILL")),
+    }
+}
+
+// number of words a statement occupies
+fn size_of(stmt: &Statement, op: &Token) -> Result<u32, Diagnostic> {
+    match op_name(op).as_str() {
+        ".FILL" => Ok(1),
+        ".BLKW" => {
+            expect_operands(stmt, op, 1)?;
+            let count = number(&stmt.operands[0])?;
+            if count < 0 {
+                return Err(Diagnostic::error(stmt.operands[0].span, ".BLKW needs a non-negative word count"));
+            }
+            Ok(count as u32)
+        },
+        ".STRINGZ" => {
+            expect_operands(stmt, op, 1)?;
+            match &stmt.operands[0].kind {
+                TokenKind::Str(s) => Ok(s.chars().count() as u32 + 1),
+                _ => Err(Diagnostic::error(stmt.operands[0].span, ".STRINGZ expects a string literal")),
+            }
+        },
+        name if name.starts_with('.') => Err(Diagnostic::error(op.span, &format!("unknown directive `{}`", op.text))),
+        _ => Ok(1),
+    }
+}
+
+// encode a single instruction statement placed at `address`
+fn encode_statement(stmt: &Statement, op: &Token, address: u16, symbols: &SymbolTable) -> Result<u16, Diagnostic> {
+    let name = op_name(op);
+    let ops = &stmt.operands;
+    let trap = |vector: u16| -> Result<u16, Diagnostic> {
+        expect_operands(stmt, op, 0)?;
+        Ok(encode(&Instruction::Trap { vector: vector as u8 }))
+    };
+    let instr = match name.as_str() {
+        "ADD" | "AND" => {
+            expect_operands(stmt, op, 3)?;
+            let (dr, sr1, operand) = (register(&ops[0])?, register(&ops[1])?, alu_operand(&ops[2])?);
+            if name == "ADD" {
+                Instruction::Add { dr, sr1, operand }
+            } else {
+                Instruction::And { dr, sr1, operand }
+            }
+        },
+        "NOT" => {
+            expect_operands(stmt, op, 2)?;
+            Instruction::Not { dr: register(&ops[0])?, sr: register(&ops[1])? }
+        },
+        "JMP" => {
+            expect_operands(stmt, op, 1)?;
+            let base_r = register(&ops[0])?;
+            if base_r == 7 { Instruction::Ret } else { Instruction::Jmp { base_r } }
+        },
+        "RET" => {
+            expect_operands(stmt, op, 0)?;
+            Instruction::Ret
+        },
+        "RTI" => {
+            expect_operands(stmt, op, 0)?;
+            Instruction::Rti
+        },
+        "JSR" => {
+            expect_operands(stmt, op, 1)?;
+            Instruction::Jsr { offset11: pc_offset(&ops[0], address, 11, symbols)? }
+        },
+        "JSRR" => {
+            expect_operands(stmt, op, 1)?;
+            Instruction::Jsrr { base_r: register(&ops[0])? }
+        },
+        "LD" | "LDI" | "LEA" | "ST" | "STI" => {
+            expect_operands(stmt, op, 2)?;
+            let r = register(&ops[0])?;
+            let offset9 = pc_offset(&ops[1], address, 9, symbols)?;
+            match name.as_str() {
+                "LD" => Instruction::Ld { dr: r, offset9 },
+                "LDI" => Instruction::Ldi { dr: r, offset9 },
+                "LEA" => Instruction::Lea { dr: r, offset9 },
+                "ST" => Instruction::St { sr: r, offset9 },
+                _ => Instruction::Sti { sr: r, offset9 },
+            }
+        },
+        "LDR" | "STR" => {
+            expect_operands(stmt, op, 3)?;
+            let (r, base_r) = (register(&ops[0])?, register(&ops[1])?);
+            let offset6 = immediate(&ops[2], 6, "offset6")?;
+            if name == "LDR" {
+                Instruction::Ldr { dr: r, base_r, offset6 }
+            } else {
+                Instruction::Str { sr: r, base_r, offset6 }
+            }
+        },
+        "TRAP" => {
+            expect_operands(stmt, op, 1)?;
+            let vector = number(&ops[0])?;
+            if !(0..=0xFF).contains(&vector) {
+                return Err(Diagnostic::error(ops[0].span, &format!("trap vector {} does not fit in trapvect8 (range 0..255)", vector)));
+            }
+            Instruction::Trap { vector: vector as u8 }
+        },
+        "GETC" => return trap(GETC),
+        "OUT" => return trap(OUT),
+        "PUTS" => return trap(PUTS),
+        "IN" => return trap(IN),
+        "PUTSP" => return trap(PUTSP),
+        "HALT" => return trap(HALT),
+        _ => match branch_flags(&name) {
+            Some((n, z, p)) => {
+                expect_operands(stmt, op, 1)?;
+                Instruction::Br { n, z, p, offset9: pc_offset(&ops[0], address, 9, symbols)? }
+            },
+            None => return Err(Diagnostic::error(op.span, &format!("unknown instruction `{}`", op.text))),
+        },
+    };
+    Ok(encode(&instr))
+}
+
+// emit the words for a directive statement placed at `address`
+fn emit_directive(stmt: &Statement, op: &Token, symbols: &SymbolTable, words: &mut Vec<u16>) -> Result<(), Diagnostic> {
+    match op_name(op).as_str() {
+        ".FILL" => {
+            expect_operands(stmt, op, 1)?;
+            let operand = &prefer_label(&stmt.operands[0], symbols);
+            let value = match &operand.kind {
+                TokenKind::Number(n) if (-0x8000..=0xFFFF).contains(n) => *n as u16,
+                TokenKind::Number(n) => {
+                    return Err(Diagnostic::error(operand.span, &format!("value {} does not fit in 16 bits", n)));
+                },
+                TokenKind::Ident(name) => symbols.get(name).ok_or_else(|| undefined_label(operand, name, symbols))?,
+                _ => return Err(Diagnostic::error(operand.span, &format!("expected a number or a label, found `{}`", operand.text))),
+            };
+            words.push(value);
+        },
+        ".BLKW" => {
+            let count = number(&stmt.operands[0])? as usize;
+            words.extend(std::iter::repeat_n(0, count));
+        },
+        ".STRINGZ" => {
+            if let TokenKind::Str(s) = &stmt.operands[0].kind {
+                words.extend(s.chars().map(|c| c as u16));
+                words.push(0);
+            }
+        },
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/**
+ * assemble a complete source file (a single .ORIG block is supported); every
+ * problem found is reported rather than stopping at the first one
+ */
+pub fn assemble(source: &str) -> Result<Program, Vec<Diagnostic>> {
+    let mut diagnostics = Vec::new();
+    let mut statements = Vec::new();
+    for (i, text) in source.lines().enumerate() {
+        match parse_line(text, i + 1) {
+            Ok(stmt) => statements.push(stmt),
+            Err(d) => diagnostics.push(d),
+        }
+    }
+
+    // pass 1: lay out the program and collect the symbol table
+    let mut symbols = SymbolTable::new();
+    let mut origin: Option<u16> = None;
+    let mut placed: Vec<(&Statement, &Token, u16, u32)> = Vec::new();
+    let mut lc: u32 = 0;
+    let mut end: Option<&Token> = None;
+    for stmt in statements.iter() {
+        if let Some(end) = end {
+            if let Some(token) = stmt.label.as_ref().or(stmt.op.as_ref()) {
+                diagnostics.push(Diagnostic::warning(token.span, "statement after .END is ignored")
+                    .with_hint(&format!(".END is on line {}", end.span.line)));
+                break;
+            }
+            continue;
+        }
+        let op = match (&stmt.op, &stmt.label) {
+            (Some(op), _) => op,
+            (None, Some(label)) if origin.is_none() => {
+                diagnostics.push(Diagnostic::error(label.span, "label before .ORIG"));
+                continue;
+            },
+            (None, _) => {
+                if let Some(label) = &stmt.label {
+                    define_label(&mut symbols, label, lc).unwrap_or_else(|d| diagnostics.push(d));
+                }
+                continue;
+            },
+        };
+        let name = op_name(op);
+        if name == ".ORIG" {
+            if origin.is_some() {
+                diagnostics.push(Diagnostic::error(op.span, "only one .ORIG block is supported")
+                    .with_hint("split the program into one file per .ORIG and load the object files together"));
+                continue;
+            }
+            let value = match expect_operands(stmt, op, 1).and_then(|_| number(&stmt.operands[0])) {
+                Ok(value) if (0..=0xFFFF).contains(&value) => value,
+                Ok(value) => {
+                    diagnostics.push(Diagnostic::error(stmt.operands[0].span, &format!("origin {} is not a valid address", value)));
+                    0x3000
+                },
+                Err(d) => {
+                    diagnostics.push(d);
+                    0x3000
+                },
+            };
+            origin = Some(value as u16);
+            lc = value as u32;
+            continue;
+        }
+        if origin.is_none() {
+            diagnostics.push(Diagnostic::error(op.span, &format!("`{}` before .ORIG", op.text))
+                .with_hint("start the program with a directive such as `.ORIG x3000`"));
+            continue;
+        }
+        if let Some(label) = &stmt.label {
+            define_label(&mut symbols, label, lc).unwrap_or_else(|d| diagnostics.push(d));
+        }
+        if name == ".END" {
+            end = Some(op);
+            continue;
+        }
+        let size = match size_of(stmt, op) {
+            Ok(size) => size,
+            Err(d) => {
+                diagnostics.push(d);
+                continue;
+            },
+        };
+        placed.push((stmt, op, lc as u16, size));
+        lc += size;
+        if lc > 0x10000 {
+            diagnostics.push(Diagnostic::error(op.span, "program runs past the end of memory"));
+            break;
+        }
+    }
+    let origin = match origin {
+        Some(origin) => origin,
+        None => {
+            diagnostics.push(Diagnostic::error(Span::new(1, 0, 0), "missing .ORIG directive"));
+            0x3000
+        },
+    };
+    if end.is_none() {
+        let last = source.lines().count().max(1);
+        diagnostics.push(Diagnostic::warning(Span::new(last, 0, 0), "missing .END directive"));
+    }
+
+    // pass 2: encode every statement now that all labels are known
+    let mut words = Vec::new();
+    let mut source_map = BTreeMap::new();
+    let mut instructions = BTreeSet::new();
+    for (stmt, op, address, size) in placed {
+        let before = words.len();
+        let result = if op_name(op).starts_with('.') {
+            emit_directive(stmt, op, &symbols, &mut words)
+        } else {
+            instructions.insert(address);
+            encode_statement(stmt, op, address, &symbols).map(|word| words.push(word))
+        };
+        if let Err(d) = result {
+            // keep later addresses right so their errors are still accurate
+            diagnostics.push(d);
+            words.truncate(before);
+            words.extend(std::iter::repeat_n(0, size as usize));
+        }
+        for i in before..words.len() {
+            source_map.insert(origin.wrapping_add(i as u16), stmt.line);
+        }
+    }
+
+    diagnostics.sort_by_key(|d| (d.span.line, d.span.col));
+    if diagnostics.iter().any(|d| d.is_error()) {
+        return Err(diagnostics);
+    }
+    Ok(Program { image: ObjectImage::new(origin, words), symbols, source_map, instructions, warnings: diagnostics })
+}
+
+fn define_label(symbols: &mut SymbolTable, label: &Token, lc: u32) -> Result<(), Diagnostic> {
+    if symbols.get(&label.text).is_some() {
+        return Err(Diagnostic::error(label.span, &format!("label `{}` is defined more than once", label.text)));
+    }
+    if (lexer::parse_number(&label.text).is_some() && !is_label_like(&label.text)) || label.text.starts_with('.') {
+        return Err(Diagnostic::error(label.span, &format!("`{}` is not a valid label", label.text)));
+    }
+    symbols.insert(&label.text, lc as u16);
+    Ok(())
+}
+
+#[cfg(test)]
+mod assembler_test {
+
+    use super::*;
+
+    #[test]
+    fn assemble_simple_trace() {
+        let program = assemble(".ORIG x3000\nADD R1, R1, #1\nADD R2, R2, #2\nADD R3, R3, #3\n.END\n").unwrap();
+        assert_eq!(program.image.origin, 0x3000);
+        assert_eq!(program.image.words, vec![0x1261, 0x14A2, 0x16E3]);
+        assert_eq!(program.source_map.get(&0x3001), Some(&3));
+        assert!(program.instructions.contains(&0x3001));
+    }
+
+    #[test]
+    fn labels_and_branches() {
+        let source = "
+            .ORIG x3000
+            AND R0, R0, #0
+    LOOP    ADD R0, R0, #1
+            BRnp LOOP
+            LD R1, DATA
+            JSR SUB
+            HALT
+    SUB     RET
+    DATA    .FILL x1234
+            .END
+        ";
+        let program = assemble(source).unwrap();
+        assert_eq!(program.symbols.get("LOOP"), Some(0x3001));
+        assert_eq!(program.symbols.get("SUB"), Some(0x3006));
+        assert_eq!(program.image.words, vec![
+            0x5020, 0x1021, 0x0BFE, 0x2203, 0x4801, 0xF025, 0xC1C0, 0x1234,
+        ]);
+    }
+
+    #[test]
+    fn labels_that_read_as_numbers() {
+        let source = "
+            .ORIG x3000
+    xAB     ADD R0, R0, #1
+            BRp xAB
+            LD R1, B1
+            BR x5
+    B1      .FILL xAB
+            .FILL B1
+            .END
+        ";
+        let program = assemble(source).unwrap();
+        assert_eq!(program.symbols.get("xAB"), Some(0x3000));
+        assert_eq!(program.symbols.get("B1"), Some(0x3004));
+        // with no label of that name `x5` is still the offset 5
+        assert_eq!(program.image.words, vec![0x1021, 0x03FE, 0x2201, 0x0E05, 0x3000, 0x3004]);
+    }
+
+    #[test]
+    fn directives_and_trap_aliases() {
+        let source = ".ORIG x3000\nLEA R0, MSG\nPUTS\nHALT\nMSG .STRINGZ \"hi\"\nBUF .BLKW 2\nEND .FILL BUF\n.END";
+        let program = assemble(source).unwrap();
+        assert_eq!(program.image.words, vec![
+            0xE002, 0xF022, 0xF025, 'h' as u16, 'i' as u16, 0, 0, 0, 0x3006,
+        ]);
+    }
+
+    #[test]
+    fn immediate_out_of_range() {
+        let errors = assemble(".ORIG x3000\nADD R1, R1, #40\n.END").unwrap_err();
+        let err = &errors[0];
+        assert_eq!(err.span, Span::new(2, 12, 3));
+        assert_eq!(err.message, "immediate #40 does not fit in imm5 (range -16..15)");
+    }
+
+    #[test]
+    fn undefined_and_duplicate_labels() {
+        assert!(assemble(".ORIG x3000\nBR NOWHERE\n.END").unwrap_err()[0].message.contains("undefined label"));
+        assert!(assemble(".ORIG x3000\nA ADD R1, R1, #1\nA HALT\n.END").unwrap_err()[0].message.contains("more than once"));
+    }
+
+    #[test]
+    fn reports_every_error() {
+        let source = ".ORIG x3000\nADD R1, R1, #40\nLD R2, loop\nFOO R1\nLOOP HALT\n.END";
+        let errors = assemble(source).unwrap_err();
+        let lines: Vec<usize> = errors.iter().map(|d| d.span.line).collect();
+        assert_eq!(lines, vec![2, 3, 4]);
+        assert_eq!(errors[1].hint.as_deref(), Some("did you mean `LOOP`? labels are case-sensitive"));
+        assert_eq!(errors[2].message, "unknown instruction `FOO`");
+    }
+
+    #[test]
+    fn warnings_do_not_fail() {
+        let program = assemble(".ORIG x3000\nHALT").unwrap();
+        assert_eq!(program.warnings.len(), 1);
+        assert_eq!(program.warnings[0].severity, Severity::Warning);
+        assert_eq!(program.warnings[0].message, "missing .END directive");
+    }
+
+    #[test]
+    fn symbol_table_file() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("LOOP", 0x3002);
+        let text = symbols.to_string();
+        assert!(text.ends_with("//\tLOOP              3002\n"));
+        assert_eq!(SymbolTable::parse(&text), symbols);
+    }
+}
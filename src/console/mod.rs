@@ -0,0 +1,281 @@
+/*
+This is the LC-3 Console Module, the character device behind the TRAP
+routines (and later the keyboard / display registers)
+*/
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+pub trait Console {
+    /**
+     * read one character; `Ok(None)` means no input is available yet and the
+     * caller should retry later rather than block
+     */
+    fn read_char(&mut self) -> io::Result<Option<u8>>;
+
+    fn write_char(&mut self, c: u8) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()>;
+
+    // whether a character can be read right now without waiting
+    fn poll(&mut self) -> bool;
+
+    // whether `read_char` waits for input instead of returning `Ok(None)`
+    fn blocks(&self) -> bool {
+        false
+    }
+
+    fn write_str(&mut self, s: &str) -> io::Result<()> {
+        for c in s.bytes() {
+            self.write_char(c)?;
+        }
+        Ok(())
+    }
+
+    // characters received but not read yet, saved with machine snapshots
+    fn pending_input(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    // replace the characters waiting to be read, when restoring a snapshot
+    fn replace_input(&mut self, _input: &[u8]) {}
+}
+
+/**
+ * the process' stdin / stdout; stdin is read on a background thread so that
+ * `poll` never blocks
+ */
+pub struct StdConsole {
+    input: Option<Receiver<u8>>,
+    peeked: Option<u8>,
+}
+
+impl StdConsole {
+
+    pub fn new() -> StdConsole {
+        StdConsole { input: None, peeked: None }
+    }
+
+    fn input(&mut self) -> &Receiver<u8> {
+        self.input.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || {
+                let stdin = io::stdin();
+                let mut buffer = [0; 1];
+                while let Ok(1) = stdin.lock().read(&mut buffer) {
+                    if sender.send(buffer[0]).is_err() {
+                        break;
+                    }
+                }
+            });
+            receiver
+        })
+    }
+}
+
+impl Default for StdConsole {
+    fn default() -> StdConsole {
+        StdConsole::new()
+    }
+}
+
+impl Console for StdConsole {
+    fn read_char(&mut self) -> io::Result<Option<u8>> {
+        if let Some(c) = self.peeked.take() {
+            return Ok(Some(c));
+        }
+        match self.input().recv() {
+            Ok(c) => Ok(Some(c)),
+            Err(_) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "end of input")),
+        }
+    }
+
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        io::stdout().write_all(&[c])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+
+    fn poll(&mut self) -> bool {
+        if self.peeked.is_none() {
+            match self.input().try_recv() {
+                Ok(c) => self.peeked = Some(c),
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => {},
+            }
+        }
+        self.peeked.is_some()
+    }
+
+    fn blocks(&self) -> bool {
+        true
+    }
+
+    // only the character already taken off the channel is known
+    fn pending_input(&self) -> Vec<u8> {
+        self.peeked.into_iter().collect()
+    }
+}
+
+#[derive(Default)]
+struct Buffers {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+/**
+ * in-memory console for scripting input and capturing output; clones share
+ * the same buffers so a test can keep one handle and give one to the machine
+ */
+#[derive(Clone, Default)]
+pub struct BufferConsole {
+    buffers: Rc<RefCell<Buffers>>,
+}
+
+impl BufferConsole {
+
+    pub fn new(input: &str) -> BufferConsole {
+        let console = BufferConsole::default();
+        console.push_input(input);
+        console
+    }
+
+    pub fn push_input(&self, input: &str) {
+        self.buffers.borrow_mut().input.extend(input.bytes());
+    }
+
+    // everything written so far, as (lossy) UTF-8
+    pub fn output(&self) -> String {
+        String::from_utf8_lossy(&self.buffers.borrow().output).into_owned()
+    }
+
+    // drain everything written so far
+    pub fn take_output(&self) -> String {
+        let bytes = std::mem::take(&mut self.buffers.borrow_mut().output);
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+impl Console for BufferConsole {
+    fn read_char(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.buffers.borrow_mut().input.pop_front())
+    }
+
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        self.buffers.borrow_mut().output.push(c);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn poll(&mut self) -> bool {
+        !self.buffers.borrow().input.is_empty()
+    }
+
+    fn pending_input(&self) -> Vec<u8> {
+        self.buffers.borrow().input.iter().copied().collect()
+    }
+
+    fn replace_input(&mut self, input: &[u8]) {
+        self.buffers.borrow_mut().input = input.iter().copied().collect();
+    }
+}
+
+// host-side handle for pushing keyboard input into a `CallbackConsole`
+#[derive(Clone, Default)]
+pub struct InputQueue {
+    queue: Rc<RefCell<VecDeque<u8>>>,
+}
+
+impl InputQueue {
+    pub fn push(&self, input: &str) {
+        self.queue.borrow_mut().extend(input.bytes());
+    }
+}
+
+/**
+ * console that hands every output character to a callback (e.g. a JS
+ * function in the web front end) and takes input pushed by the host
+ */
+pub struct CallbackConsole {
+    on_output: Box<dyn FnMut(u8)>,
+    input: InputQueue,
+}
+
+impl CallbackConsole {
+
+    pub fn new<F: FnMut(u8) + 'static>(on_output: F) -> CallbackConsole {
+        CallbackConsole { on_output: Box::new(on_output), input: InputQueue::default() }
+    }
+
+    // a handle that stays usable after the console is given to a machine
+    pub fn input(&self) -> InputQueue {
+        self.input.clone()
+    }
+}
+
+impl Console for CallbackConsole {
+    fn read_char(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.input.queue.borrow_mut().pop_front())
+    }
+
+    fn write_char(&mut self, c: u8) -> io::Result<()> {
+        (self.on_output)(c);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn poll(&mut self) -> bool {
+        !self.input.queue.borrow().is_empty()
+    }
+
+    fn pending_input(&self) -> Vec<u8> {
+        self.input.queue.borrow().iter().copied().collect()
+    }
+
+    fn replace_input(&mut self, input: &[u8]) {
+        *self.input.queue.borrow_mut() = input.iter().copied().collect();
+    }
+}
+
+#[cfg(test)]
+mod console_test {
+
+    use super::*;
+
+    #[test]
+    fn buffer_console_shares_state() {
+        let console = BufferConsole::new("ab");
+        let mut handle = console.clone();
+        assert!(handle.poll());
+        assert_eq!(handle.read_char().unwrap(), Some(b'a'));
+        assert_eq!(handle.read_char().unwrap(), Some(b'b'));
+        assert_eq!(handle.read_char().unwrap(), None);
+        assert!(!handle.poll());
+        handle.write_str("hi").unwrap();
+        assert_eq!(console.take_output(), "hi");
+        assert_eq!(console.output(), "");
+        handle.replace_input(b"xy");
+        assert_eq!(console.pending_input(), b"xy".to_vec());
+    }
+
+    #[test]
+    fn callback_console_forwards_output() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let sink = seen.clone();
+        let mut console = CallbackConsole::new(move |c| sink.borrow_mut().push(c));
+        console.write_str("ok").unwrap();
+        console.input().push("x");
+        assert_eq!(console.read_char().unwrap(), Some(b'x'));
+        assert_eq!(*seen.borrow(), b"ok".to_vec());
+    }
+}
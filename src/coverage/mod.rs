@@ -0,0 +1,277 @@
+/*
+This is the LC-3 Coverage Module, counting which instructions a run executed
+and which way its conditional branches went, reported as an annotated
+listing or an lcov tracefile
+*/
+use crate::assembler::{Program, SymbolTable};
+use crate::disassembler::disassemble_image;
+use crate::error::VmError;
+use crate::instruction::decode::*;
+use crate::loader::ObjectImage;
+use crate::machine::{Machine, StepOutcome};
+use crate::register::RegFile;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// how often a conditional BR went each way
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BranchCoverage {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+impl BranchCoverage {
+    // "both", "taken", "not taken", or "" if the branch never ran
+    pub fn describe(&self) -> &'static str {
+        match (self.taken > 0, self.not_taken > 0) {
+            (true, true) => "both",
+            (true, false) => "taken",
+            (false, true) => "not taken",
+            (false, false) => "",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    // address => times the instruction there was executed
+    hits: BTreeMap<u16, u64>,
+    branches: BTreeMap<u16, BranchCoverage>,
+}
+
+// a BR testing some but not all of n, z and p, so it can go either way
+fn conditional_branch(word: u16) -> Option<u16> {
+    match decode(word) {
+        Ok(Instruction::Br { n, z, p, .. }) if (n || z || p) && !(n && z && p) => {
+            Some(((n as u16) << 2) | ((z as u16) << 1) | p as u16)
+        },
+        _ => None,
+    }
+}
+
+fn percent(part: usize, whole: usize) -> f64 {
+    if whole == 0 { 100.0 } else { part as f64 * 100.0 / whole as f64 }
+}
+
+impl Coverage {
+
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    // count the step the machine just made, given the registers from before it
+    pub fn record(&mut self, machine: &Machine, before: &RegFile) {
+        if let Some((pc, word)) = machine.last_instruction() {
+            *self.hits.entry(pc).or_insert(0) += 1;
+            if let Some(nzp) = conditional_branch(word) {
+                let branch = self.branches.entry(pc).or_default();
+                if before.r_cond & nzp != 0 {
+                    branch.taken += 1;
+                } else {
+                    branch.not_taken += 1;
+                }
+            }
+        }
+    }
+
+    // step the machine once, counting the instruction it executed
+    pub fn step(&mut self, machine: &mut Machine) -> Result<StepOutcome, VmError> {
+        let before = machine.reg_file.clone();
+        let outcome = machine.step()?;
+        if outcome != StepOutcome::WaitingForInput {
+            self.record(machine, &before);
+        }
+        Ok(outcome)
+    }
+
+    // like `Machine::run`, counting every instruction
+    pub fn run(&mut self, machine: &mut Machine) -> Result<StepOutcome, VmError> {
+        loop {
+            let outcome = self.step(machine)?;
+            if outcome != StepOutcome::Continue {
+                return Ok(outcome);
+            }
+        }
+    }
+
+    pub fn hits(&self, address: u16) -> u64 {
+        self.hits.get(&address).copied().unwrap_or(0)
+    }
+
+    // `None` if the instruction at `address` never ran as a conditional branch
+    pub fn branch(&self, address: u16) -> Option<BranchCoverage> {
+        self.branches.get(&address).copied()
+    }
+
+    /**
+     * source line => (times executed, the line's conditional branch if any)
+     * for every line of `program` holding an instruction
+     */
+    fn lines(&self, program: &Program) -> BTreeMap<usize, (u64, Option<BranchCoverage>)> {
+        let mut lines = BTreeMap::new();
+        for address in program.instructions.iter() {
+            let line = match program.source_map.get(address) {
+                Some(line) => *line,
+                None => continue,
+            };
+            let entry = lines.entry(line).or_insert((0, None));
+            entry.0 = entry.0.max(self.hits(*address));
+            if let Some(word) = program.image.words.get(address.wrapping_sub(program.image.origin) as usize) {
+                if conditional_branch(*word).is_some() {
+                    entry.1 = Some(self.branch(*address).unwrap_or_default());
+                }
+            }
+        }
+        lines
+    }
+
+    /**
+     * the assembly source with each line prefixed by its execution count,
+     * `#####` for instructions that never ran and `-` for lines without
+     * instructions, and which way its branch went; ends with a summary
+     */
+    pub fn annotate_source(&self, source: &str, program: &Program) -> String {
+        let lines = self.lines(program);
+        let mut out = String::new();
+        for (i, text) in source.lines().enumerate() {
+            let (hits, branch) = match lines.get(&(i + 1)) {
+                Some((0, branch)) => ("#####".to_string(), branch.map_or("", |_| "never")),
+                Some((hits, branch)) => (hits.to_string(), branch.as_ref().map_or("", BranchCoverage::describe)),
+                None => ("-".to_string(), ""),
+            };
+            writeln!(out, "{:>9}  {:<9}  {:>4}: {}", hits, branch, i + 1, text).unwrap();
+        }
+        let executed = lines.values().filter(|(hits, _)| *hits > 0).count();
+        let branches: Vec<BranchCoverage> = lines.values().filter_map(|(_, branch)| *branch).collect();
+        let directions = branches.iter().map(|b| (b.taken > 0) as usize + (b.not_taken > 0) as usize).sum();
+        writeln!(
+            out,
+            "lines executed: {} of {} ({:.1}%), branch directions taken: {} of {} ({:.1}%)",
+            executed, lines.len(), percent(executed, lines.len()),
+            directions, branches.len() * 2, percent(directions, branches.len() * 2),
+        ).unwrap();
+        out
+    }
+
+    // the disassembly of an image with no source, annotated the same way
+    pub fn annotate_image(&self, image: &ObjectImage, symbols: Option<&SymbolTable>) -> String {
+        let mut out = String::new();
+        for line in disassemble_image(image, symbols) {
+            let hits = match self.hits(line.address) {
+                0 => "#####".to_string(),
+                hits => hits.to_string(),
+            };
+            let branch = self.branch(line.address).as_ref().map_or("", BranchCoverage::describe);
+            writeln!(out, "{:>9}  {:<9}  {}", hits, branch, line).unwrap();
+        }
+        out
+    }
+
+    /**
+     * one lcov record for the source file at `path`: a BRDA entry for each
+     * direction of every conditional branch and a DA entry for every line
+     * holding an instruction
+     */
+    pub fn lcov(&self, path: &str, program: &Program) -> String {
+        let lines = self.lines(program);
+        let mut out = format!("TN:\nSF:{}\n", path);
+        let (mut found, mut hit) = (0, 0);
+        for (line, (hits, branch)) in lines.iter() {
+            if let Some(branch) = branch {
+                for (i, count) in [branch.taken, branch.not_taken].iter().enumerate() {
+                    let taken = if *hits == 0 { "-".to_string() } else { count.to_string() };
+                    writeln!(out, "BRDA:{},0,{},{}", line, i, taken).unwrap();
+                    found += 1;
+                    hit += (*count > 0) as usize;
+                }
+            }
+        }
+        writeln!(out, "BRF:{}\nBRH:{}", found, hit).unwrap();
+        for (line, (hits, _)) in lines.iter() {
+            writeln!(out, "DA:{},{}", line, hits).unwrap();
+        }
+        let executed = lines.values().filter(|(hits, _)| *hits > 0).count();
+        writeln!(out, "LF:{}\nLH:{}\nend_of_record", lines.len(), executed).unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod coverage_test {
+
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::console::BufferConsole;
+
+    const SOURCE: &str = "\
+.ORIG x3000
+        ADD R1, R1, #2
+LOOP    ADD R1, R1, #-1
+        BRp LOOP
+        BRn NEVER
+        HALT
+NEVER   ADD R2, R2, #1
+        HALT
+.END";
+
+    fn covered() -> (Program, Coverage) {
+        let program = assemble(SOURCE).unwrap();
+        let mut machine = Machine::with_console(Box::new(BufferConsole::new("")));
+        machine.load_image(&program.image).unwrap();
+        let mut coverage = Coverage::new();
+        assert_eq!(coverage.run(&mut machine).unwrap(), StepOutcome::Halted);
+        (program, coverage)
+    }
+
+    #[test]
+    fn counts_hits_and_branches() {
+        let (_, coverage) = covered();
+        assert_eq!(coverage.hits(0x3000), 1);
+        assert_eq!(coverage.hits(0x3001), 2);
+        assert_eq!(coverage.hits(0x3005), 0);
+        assert_eq!(coverage.branch(0x3002), Some(BranchCoverage { taken: 1, not_taken: 1 }));
+        assert_eq!(coverage.branch(0x3003), Some(BranchCoverage { taken: 0, not_taken: 1 }));
+        assert_eq!(coverage.branch(0x3004), None);
+    }
+
+    #[test]
+    fn annotated_listing() {
+        let (program, coverage) = covered();
+        let listing = coverage.annotate_source(SOURCE, &program);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[0], "        -                1: .ORIG x3000");
+        assert_eq!(lines[2], "        2                3: LOOP    ADD R1, R1, #-1");
+        assert_eq!(lines[3], "        2  both          4:         BRp LOOP");
+        assert_eq!(lines[4], "        1  not taken     5:         BRn NEVER");
+        assert_eq!(lines[6], "    #####                7: NEVER   ADD R2, R2, #1");
+        assert_eq!(lines[9], "lines executed: 5 of 7 (71.4%), branch directions taken: 3 of 4 (75.0%)");
+        let disassembly = coverage.annotate_image(&program.image, Some(&program.symbols));
+        assert!(disassembly.lines().nth(2).unwrap().starts_with("        2  both       x3002  x03FE"));
+    }
+
+    #[test]
+    fn lcov_record() {
+        let (program, coverage) = covered();
+        assert_eq!(coverage.lcov("loop.asm", &program), "\
+TN:
+SF:loop.asm
+BRDA:4,0,0,1
+BRDA:4,0,1,1
+BRDA:5,0,0,0
+BRDA:5,0,1,1
+BRF:4
+BRH:3
+DA:2,1
+DA:3,2
+DA:4,2
+DA:5,1
+DA:6,1
+DA:7,0
+DA:8,0
+LF:7
+LH:5
+end_of_record
+");
+    }
+}
@@ -0,0 +1,729 @@
+/*
+This is the LC-3 Datapath Module, a second execution engine running every
+instruction through the control state machine of Patt & Patel (appendix C)
+one state per cycle, recording the control signals and register transfers
+*/
+use crate::device::MemoryBus;
+use crate::error::VmError;
+use crate::instruction::decode::*;
+use crate::instruction::exception::{service_routine, ILLEGAL_OPCODE, INTERRUPT_VECTOR_TABLE, PRIVILEGE_VIOLATION};
+use crate::instruction::trap::instr_trap;
+use crate::interrupt::Interrupt;
+use crate::machine::{StepOutcome, TrapMode};
+use crate::memory::Bus;
+use crate::register::{Privilege, RegFile};
+use crate::utils::json_escape;
+
+const REG_NAMES: [&str; 8] = ["R0", "R1", "R2", "R3", "R4", "R5", "R6", "R7"];
+
+/**
+ * the control signals a state asserts: load enables, bus gates, multiplexer
+ * selections and the memory enable; `None` means the mux is not used
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ControlSignals {
+    pub ld_mar: bool,
+    pub ld_mdr: bool,
+    pub ld_ir: bool,
+    pub ld_ben: bool,
+    pub ld_reg: bool,
+    pub ld_cc: bool,
+    pub ld_pc: bool,
+    pub ld_psr: bool,
+    pub ld_saved_usp: bool,
+    pub ld_saved_ssp: bool,
+    pub gate_pc: bool,
+    pub gate_mdr: bool,
+    pub gate_alu: bool,
+    pub gate_marmux: bool,
+    pub gate_psr: bool,
+    pub gate_sp: bool,
+    pub gate_vector: bool,
+    // PC+1, BUS or ADDER
+    pub pcmux: Option<&'static str>,
+    // 11.9, R7 or SP
+    pub drmux: Option<&'static str>,
+    // 11.9, 8.6 or SP
+    pub sr1mux: Option<&'static str>,
+    // PC or BaseR
+    pub addr1mux: Option<&'static str>,
+    // ZERO, offset6, PCoffset9 or PCoffset11
+    pub addr2mux: Option<&'static str>,
+    // ZEXT or ADDER
+    pub marmux: Option<&'static str>,
+    // SP+1, SP-1 or Saved.SSP
+    pub spmux: Option<&'static str>,
+    // ADD, AND, NOT or PASSA
+    pub aluk: Option<&'static str>,
+    pub mio_en: bool,
+    // R.W: a memory write rather than a read
+    pub write: bool,
+}
+
+impl ControlSignals {
+    // the names of the asserted signals, muxes as `NAME=selection`
+    pub fn asserted(&self) -> Vec<String> {
+        let flags = [
+            (self.ld_mar, "LD.MAR"), (self.ld_mdr, "LD.MDR"), (self.ld_ir, "LD.IR"), (self.ld_ben, "LD.BEN"),
+            (self.ld_reg, "LD.REG"), (self.ld_cc, "LD.CC"), (self.ld_pc, "LD.PC"), (self.ld_psr, "LD.PSR"),
+            (self.ld_saved_usp, "LD.SavedUSP"), (self.ld_saved_ssp, "LD.SavedSSP"),
+            (self.gate_pc, "GatePC"), (self.gate_mdr, "GateMDR"), (self.gate_alu, "GateALU"),
+            (self.gate_marmux, "GateMARMUX"), (self.gate_psr, "GatePSR"), (self.gate_sp, "GateSP"),
+            (self.gate_vector, "GateVector"), (self.mio_en, "MIO.EN"), (self.write, "R.W=WR"),
+        ];
+        let muxes = [
+            ("PCMUX", self.pcmux), ("DRMUX", self.drmux), ("SR1MUX", self.sr1mux), ("ADDR1MUX", self.addr1mux),
+            ("ADDR2MUX", self.addr2mux), ("MARMUX", self.marmux), ("SPMUX", self.spmux), ("ALUK", self.aluk),
+        ];
+        flags.iter().filter(|(on, _)| *on).map(|(_, name)| name.to_string())
+            .chain(muxes.iter().filter_map(|(name, select)| select.map(|select| format!("{}={}", name, select))))
+            .collect()
+    }
+}
+
+// one register transfer or memory access made during a cycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transfer {
+    // a register (MAR, MDR, IR, BEN, PC, CC, PSR, SavedUSP, SavedSSP or R0-R7) latched a value
+    Latch { register: &'static str, value: u16 },
+    Read { address: u16, value: u16 },
+    Write { address: u16, value: u16 },
+    // the built-in service routine ran in place of LC-3 code
+    NativeTrap { vector: u16 },
+}
+
+// one state of the control state machine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cycle {
+    pub state: u8,
+    // what the state does, in register transfer notation
+    pub rtl: &'static str,
+    pub signals: ControlSignals,
+    // the value driven onto the bus, if any gate was open
+    pub bus: Option<u16>,
+    pub transfers: Vec<Transfer>,
+}
+
+impl Cycle {
+
+    fn new(state: u8, rtl: &'static str, signals: ControlSignals) -> Cycle {
+        Cycle { state, rtl, signals, bus: None, transfers: Vec::new() }
+    }
+
+    fn drive(&mut self, value: u16) -> u16 {
+        self.bus = Some(value);
+        value
+    }
+
+    fn latch(&mut self, register: &'static str, value: u16) -> u16 {
+        self.transfers.push(Transfer::Latch { register, value });
+        value
+    }
+
+    // drive `value` onto the bus and latch it into `register`
+    fn transfer(&mut self, register: &'static str, value: u16) -> u16 {
+        self.drive(value);
+        self.latch(register, value)
+    }
+
+    fn read(&mut self, mem: &mut MemoryBus<'_>, address: u16) -> u16 {
+        let value = mem.read(address);
+        self.transfers.push(Transfer::Read { address, value });
+        value
+    }
+
+    fn write(&mut self, mem: &mut MemoryBus<'_>, address: u16, value: u16) {
+        mem.write(address, value);
+        self.transfers.push(Transfer::Write { address, value });
+    }
+
+    // whether the state waits for memory
+    pub fn is_memory(&self) -> bool {
+        self.signals.mio_en
+    }
+
+    /**
+     * one JSON object: `{"state":18,"rtl":"..","signals":[".."],"bus":12288,
+     * "transfers":[{"latch":"MAR","value":12288},{"read":12288,"value":..}]}`
+     */
+    pub fn to_json(&self) -> String {
+        let signals = self.signals.asserted().iter()
+            .map(|signal| format!("\"{}\"", signal))
+            .collect::<Vec<_>>().join(",");
+        let transfers = self.transfers.iter()
+            .map(|transfer| match *transfer {
+                Transfer::Latch { register, value } => format!("{{\"latch\":\"{}\",\"value\":{}}}", register, value),
+                Transfer::Read { address, value } => format!("{{\"read\":{},\"value\":{}}}", address, value),
+                Transfer::Write { address, value } => format!("{{\"write\":{},\"value\":{}}}", address, value),
+                Transfer::NativeTrap { vector } => format!("{{\"native_trap\":{}}}", vector),
+            })
+            .collect::<Vec<_>>().join(",");
+        format!(
+            "{{\"state\":{},\"rtl\":\"{}\",\"signals\":[{}],\"bus\":{},\"transfers\":[{}]}}",
+            self.state, json_escape(self.rtl), signals,
+            self.bus.map_or("null".to_string(), |bus| bus.to_string()), transfers,
+        )
+    }
+}
+
+/**
+ * the microarchitectural registers and the cycles of the last step; the
+ * architectural state (R0-R7, PC, PSR, memory) is the machine's own
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Datapath {
+    pub mar: u16,
+    pub mdr: u16,
+    pub ir: u16,
+    pub ben: bool,
+    cycles: Vec<Cycle>,
+}
+
+fn alu(signals: ControlSignals) -> ControlSignals {
+    ControlSignals { ld_reg: true, ld_cc: true, gate_alu: true, drmux: Some("11.9"), sr1mux: Some("8.6"), ..signals }
+}
+
+fn mar_from_adder(addr1mux: &'static str, addr2mux: &'static str) -> ControlSignals {
+    ControlSignals {
+        ld_mar: true, gate_marmux: true, marmux: Some("ADDER"),
+        addr1mux: Some(addr1mux), addr2mux: Some(addr2mux),
+        sr1mux: if addr1mux == "BaseR" { Some("8.6") } else { None },
+        ..Default::default()
+    }
+}
+
+// a memory state: MDR <- M[MAR] or, with R.W, M[MAR] <- MDR
+fn memory(write: bool) -> ControlSignals {
+    ControlSignals { ld_mdr: !write, mio_en: true, write, ..Default::default() }
+}
+
+impl Datapath {
+
+    pub fn new() -> Datapath {
+        Datapath::default()
+    }
+
+    // the states the last step went through, in order
+    pub fn cycles(&self) -> &[Cycle] {
+        &self.cycles
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.cycles.clear();
+    }
+
+    fn reg(&self, reg_file: &RegFile, reg: u16) -> u16 {
+        reg_file.read_reg(reg).expect("R0-R7 are valid registers")
+    }
+
+    fn set_reg(&self, cycle: &mut Cycle, reg_file: &mut RegFile, reg: u16, value: u16) {
+        reg_file.update_reg(reg, cycle.latch(REG_NAMES[reg as usize], value)).expect("R0-R7 are valid registers");
+    }
+
+    // write DR and set the condition codes from it
+    fn set_reg_cc(&self, cycle: &mut Cycle, reg_file: &mut RegFile, reg: u16, value: u16) {
+        self.set_reg(cycle, reg_file, reg, value);
+        reg_file.update_cond_flag(reg).expect("R0-R7 are valid registers");
+        cycle.latch("CC", reg_file.r_cond);
+    }
+
+    // 25 / 24 / 29 / 33: MDR <- M[MAR]
+    fn memory_read(&mut self, state: u8, mem: &mut MemoryBus<'_>) {
+        let mut cycle = Cycle::new(state, "MDR<-M[MAR]", memory(false));
+        self.mdr = cycle.read(mem, self.mar);
+        cycle.latch("MDR", self.mdr);
+        self.cycles.push(cycle);
+    }
+
+    // 16 / 41 / 48: M[MAR] <- MDR
+    fn memory_write(&mut self, state: u8, mem: &mut MemoryBus<'_>) {
+        let mut cycle = Cycle::new(state, "M[MAR]<-MDR", memory(true));
+        cycle.write(mem, self.mar, self.mdr);
+        self.cycles.push(cycle);
+    }
+
+    // 26 / 31: MAR <- MDR
+    fn mar_from_mdr(&mut self, state: u8) {
+        let mut cycle = Cycle::new(state, "MAR<-MDR", ControlSignals { ld_mar: true, gate_mdr: true, ..Default::default() });
+        self.mar = cycle.transfer("MAR", self.mdr);
+        self.cycles.push(cycle);
+    }
+
+    // 37 / 47: MAR, SP <- SP - 1
+    fn push_address(&mut self, state: u8, reg_file: &mut RegFile) {
+        let mut cycle = Cycle::new(state, "MAR,SP<-SP-1", ControlSignals {
+            ld_mar: true, ld_reg: true, gate_sp: true, spmux: Some("SP-1"), sr1mux: Some("SP"), drmux: Some("SP"),
+            ..Default::default()
+        });
+        let sp = cycle.drive(self.reg(reg_file, 6).wrapping_sub(1));
+        self.mar = cycle.latch("MAR", sp);
+        self.set_reg(&mut cycle, reg_file, 6, sp);
+        self.cycles.push(cycle);
+    }
+
+    // 39 / 42: SP <- SP + 1, also latched by MAR in 39
+    fn pop_address(&mut self, state: u8, reg_file: &mut RegFile, ld_mar: bool) {
+        let rtl = if ld_mar { "MAR,SP<-SP+1" } else { "SP<-SP+1" };
+        let mut cycle = Cycle::new(state, rtl, ControlSignals {
+            ld_mar, ld_reg: true, gate_sp: true, spmux: Some("SP+1"), sr1mux: Some("SP"), drmux: Some("SP"),
+            ..Default::default()
+        });
+        let sp = cycle.drive(self.reg(reg_file, 6).wrapping_add(1));
+        if ld_mar {
+            self.mar = cycle.latch("MAR", sp);
+        }
+        self.set_reg(&mut cycle, reg_file, 6, sp);
+        self.cycles.push(cycle);
+    }
+
+    // 18, 33, 35 and 32: fetch the instruction at PC and decode it
+    pub(crate) fn fetch(&mut self, reg_file: &mut RegFile, mem: &mut MemoryBus<'_>) -> u16 {
+        let mut cycle = Cycle::new(18, "MAR<-PC, PC<-PC+1", ControlSignals {
+            ld_mar: true, ld_pc: true, gate_pc: true, pcmux: Some("PC+1"), ..Default::default()
+        });
+        let pc = cycle.drive(reg_file.r_pc);
+        self.mar = cycle.latch("MAR", pc);
+        reg_file.r_pc = cycle.latch("PC", pc.wrapping_add(1));
+        self.cycles.push(cycle);
+
+        self.memory_read(33, mem);
+
+        let mut cycle = Cycle::new(35, "IR<-MDR", ControlSignals { ld_ir: true, gate_mdr: true, ..Default::default() });
+        self.ir = cycle.transfer("IR", self.mdr);
+        self.cycles.push(cycle);
+
+        let mut cycle = Cycle::new(32, "BEN<-IR[11]&N+IR[10]&Z+IR[9]&P, [IR[15:12]]", ControlSignals {
+            ld_ben: true, ..Default::default()
+        });
+        self.ben = (self.ir >> 9) & 0x7 & reg_file.r_cond != 0;
+        cycle.latch("BEN", self.ben as u16);
+        self.cycles.push(cycle);
+        self.ir
+    }
+
+    // 49 and the entry sequence, taken at an instruction boundary before the fetch
+    pub(crate) fn interrupt(&mut self, irq: Interrupt, reg_file: &mut RegFile, mem: &mut MemoryBus<'_>) {
+        self.enter(49, irq.vector, Some(irq.priority), reg_file, mem);
+    }
+
+    /**
+     * the entry sequence shared by interrupts and exceptions: save PSR and PC
+     * on the supervisor stack, then 50 / 52 / 54 load PC from the interrupt
+     * vector table
+     */
+    fn enter(&mut self, state: u8, vector: u16, priority: Option<u16>, reg_file: &mut RegFile, mem: &mut MemoryBus<'_>) {
+        self.save_context(state, priority, reg_file, mem);
+
+        let mut cycle = Cycle::new(50, "MAR<-x01'Vector", ControlSignals { ld_mar: true, gate_vector: true, ..Default::default() });
+        self.mar = cycle.transfer("MAR", INTERRUPT_VECTOR_TABLE.wrapping_add(vector & 0xff));
+        self.cycles.push(cycle);
+
+        self.memory_read(52, mem);
+
+        let mut cycle = Cycle::new(54, "PC<-MDR", ControlSignals { ld_pc: true, gate_mdr: true, pcmux: Some("BUS"), ..Default::default() });
+        reg_file.r_pc = cycle.transfer("PC", self.mdr);
+        self.cycles.push(cycle);
+    }
+
+    /**
+     * the first state saves PSR in MDR and switches to the supervisor stack,
+     * then 37 / 41 push PSR and 43 / 47 / 48 push PC
+     */
+    fn save_context(&mut self, state: u8, priority: Option<u16>, reg_file: &mut RegFile, mem: &mut MemoryBus<'_>) {
+        let mut cycle = Cycle::new(state, "MDR<-PSR, PSR[15]<-0, [PSR[15]]", ControlSignals {
+            ld_mdr: true, ld_psr: true, gate_psr: true, ..Default::default()
+        });
+        self.mdr = cycle.transfer("MDR", reg_file.psr());
+        if reg_file.privilege == Privilege::User {
+            cycle.signals.ld_saved_usp = true;
+            cycle.signals.ld_reg = true;
+            cycle.signals.spmux = Some("Saved.SSP");
+            cycle.signals.drmux = Some("SP");
+            cycle.latch("SavedUSP", self.reg(reg_file, 6));
+            cycle.latch("R6", reg_file.saved_ssp);
+            reg_file.switch_to_supervisor();
+        }
+        if let Some(priority) = priority {
+            reg_file.priority = priority;
+        }
+        cycle.latch("PSR", reg_file.psr());
+        self.cycles.push(cycle);
+
+        self.push_address(37, reg_file);
+        self.memory_write(41, mem);
+
+        let mut cycle = Cycle::new(43, "MDR<-PC", ControlSignals { ld_mdr: true, gate_pc: true, ..Default::default() });
+        self.mdr = cycle.transfer("MDR", reg_file.r_pc);
+        self.cycles.push(cycle);
+
+        self.push_address(47, reg_file);
+        self.memory_write(48, mem);
+    }
+
+    /**
+     * with no service routine installed there is nothing to vector to, so
+     * as with the instruction handlers the error goes to the embedder
+     */
+    fn exception(&mut self, state: u8, vector: u16, error: VmError, reg_file: &mut RegFile, mem: &mut MemoryBus<'_>) -> Result<(), VmError> {
+        if service_routine(vector, mem) == 0 {
+            return Err(error);
+        }
+        self.enter(state, vector, None, reg_file, mem);
+        Ok(())
+    }
+
+    /**
+     * run the states after decode for the instruction in IR; exceptions push
+     * the PC of the next instruction, as the instruction handlers do
+     */
+    pub(crate) fn execute(&mut self, trap_mode: TrapMode, reg_file: &mut RegFile, mem: &mut MemoryBus<'_>) -> Result<StepOutcome, VmError> {
+        let ir = self.ir;
+        let pc = reg_file.r_pc;
+        let decoded = match decode(ir) {
+            Ok(decoded) => decoded,
+            Err(_) => {
+                let error = VmError::IllegalOpcode { pc: pc.wrapping_sub(1), word: ir };
+                self.exception(13, ILLEGAL_OPCODE, error, reg_file, mem)?;
+                return Ok(StepOutcome::Continue);
+            },
+        };
+        match decoded {
+            Instruction::Add { dr, sr1, operand } | Instruction::And { dr, sr1, operand } => {
+                let add = matches!(decoded, Instruction::Add { .. });
+                let (state, rtl, aluk) = if add { (1, "DR<-SR1+OP2, set CC", "ADD") } else { (5, "DR<-SR1&OP2, set CC", "AND") };
+                let mut cycle = Cycle::new(state, rtl, alu(ControlSignals { aluk: Some(aluk), ..Default::default() }));
+                let a = self.reg(reg_file, sr1);
+                let b = match operand {
+                    Operand::Reg(sr2) => self.reg(reg_file, sr2),
+                    Operand::Imm5(imm5) => imm5 as u16,
+                };
+                let value = cycle.drive(if add { a.wrapping_add(b) } else { a & b });
+                self.set_reg_cc(&mut cycle, reg_file, dr, value);
+                self.cycles.push(cycle);
+            },
+            Instruction::Not { dr, sr } => {
+                let mut cycle = Cycle::new(9, "DR<-NOT(SR), set CC", alu(ControlSignals { aluk: Some("NOT"), ..Default::default() }));
+                let value = cycle.drive(!self.reg(reg_file, sr));
+                self.set_reg_cc(&mut cycle, reg_file, dr, value);
+                self.cycles.push(cycle);
+            },
+            Instruction::Lea { dr, offset9 } => {
+                let mut cycle = Cycle::new(14, "DR<-PC+off9, set CC", ControlSignals {
+                    ld_reg: true, ld_cc: true, gate_marmux: true, drmux: Some("11.9"), marmux: Some("ADDER"),
+                    addr1mux: Some("PC"), addr2mux: Some("PCoffset9"), ..Default::default()
+                });
+                let value = cycle.drive(pc.wrapping_add(offset9 as u16));
+                self.set_reg_cc(&mut cycle, reg_file, dr, value);
+                self.cycles.push(cycle);
+            },
+            Instruction::Ld { dr, offset9 } | Instruction::Ldi { dr, offset9 } => {
+                let indirect = matches!(decoded, Instruction::Ldi { .. });
+                let mut cycle = Cycle::new(if indirect { 10 } else { 2 }, "MAR<-PC+off9", mar_from_adder("PC", "PCoffset9"));
+                self.mar = cycle.transfer("MAR", pc.wrapping_add(offset9 as u16));
+                self.cycles.push(cycle);
+                if indirect {
+                    self.memory_read(24, mem);
+                    self.mar_from_mdr(26);
+                }
+                self.load(dr, reg_file, mem);
+            },
+            Instruction::Ldr { dr, base_r, offset6 } => {
+                let mut cycle = Cycle::new(6, "MAR<-B+off6", mar_from_adder("BaseR", "offset6"));
+                self.mar = cycle.transfer("MAR", self.reg(reg_file, base_r).wrapping_add(offset6 as u16));
+                self.cycles.push(cycle);
+                self.load(dr, reg_file, mem);
+            },
+            Instruction::St { sr, offset9 } | Instruction::Sti { sr, offset9 } => {
+                let indirect = matches!(decoded, Instruction::Sti { .. });
+                let mut cycle = Cycle::new(if indirect { 11 } else { 3 }, "MAR<-PC+off9", mar_from_adder("PC", "PCoffset9"));
+                self.mar = cycle.transfer("MAR", pc.wrapping_add(offset9 as u16));
+                self.cycles.push(cycle);
+                if indirect {
+                    self.memory_read(29, mem);
+                    self.mar_from_mdr(31);
+                }
+                self.store(sr, reg_file, mem);
+            },
+            Instruction::Str { sr, base_r, offset6 } => {
+                let mut cycle = Cycle::new(7, "MAR<-B+off6", mar_from_adder("BaseR", "offset6"));
+                self.mar = cycle.transfer("MAR", self.reg(reg_file, base_r).wrapping_add(offset6 as u16));
+                self.cycles.push(cycle);
+                self.store(sr, reg_file, mem);
+            },
+            Instruction::Br { offset9, .. } => {
+                self.cycles.push(Cycle::new(0, "[BEN]", ControlSignals::default()));
+                if self.ben {
+                    let mut cycle = Cycle::new(22, "PC<-PC+off9", ControlSignals {
+                        ld_pc: true, pcmux: Some("ADDER"), addr1mux: Some("PC"), addr2mux: Some("PCoffset9"), ..Default::default()
+                    });
+                    reg_file.r_pc = cycle.latch("PC", pc.wrapping_add(offset9 as u16));
+                    self.cycles.push(cycle);
+                }
+            },
+            Instruction::Jmp { .. } | Instruction::Ret => {
+                let base_r = if let Instruction::Jmp { base_r } = decoded { base_r } else { 7 };
+                let mut cycle = Cycle::new(12, "PC<-BaseR", ControlSignals {
+                    ld_pc: true, pcmux: Some("ADDER"), addr1mux: Some("BaseR"), addr2mux: Some("ZERO"), sr1mux: Some("8.6"),
+                    ..Default::default()
+                });
+                reg_file.r_pc = cycle.latch("PC", self.reg(reg_file, base_r));
+                self.cycles.push(cycle);
+            },
+            Instruction::Jsr { .. } | Instruction::Jsrr { .. } => {
+                self.cycles.push(Cycle::new(4, "[IR[11]]", ControlSignals::default()));
+                // R7 and PC latch on the same clock edge, so BaseR is read before R7 changes
+                let (state, rtl, addr1mux, addr2mux, target) = match decoded {
+                    Instruction::Jsr { offset11 } => (21, "R7<-PC, PC<-PC+off11", "PC", "PCoffset11", pc.wrapping_add(offset11 as u16)),
+                    Instruction::Jsrr { base_r } => (20, "R7<-PC, PC<-BaseR", "BaseR", "ZERO", self.reg(reg_file, base_r)),
+                    _ => unreachable!("only JSR and JSRR reach state 4"),
+                };
+                let mut cycle = Cycle::new(state, rtl, ControlSignals {
+                    ld_reg: true, ld_pc: true, gate_pc: true, drmux: Some("R7"), pcmux: Some("ADDER"),
+                    addr1mux: Some(addr1mux), addr2mux: Some(addr2mux),
+                    sr1mux: if addr1mux == "BaseR" { Some("8.6") } else { None },
+                    ..Default::default()
+                });
+                cycle.drive(pc);
+                self.set_reg(&mut cycle, reg_file, 7, pc);
+                reg_file.r_pc = cycle.latch("PC", target);
+                self.cycles.push(cycle);
+            },
+            Instruction::Trap { vector } => {
+                if trap_mode == TrapMode::Native {
+                    let mut cycle = Cycle::new(15, "MAR<-ZEXT[IR[7:0]]", ControlSignals {
+                        ld_mar: true, gate_marmux: true, marmux: Some("ZEXT"), ..Default::default()
+                    });
+                    self.mar = cycle.transfer("MAR", vector as u16);
+                    cycle.transfers.push(Transfer::NativeTrap { vector: vector as u16 });
+                    self.cycles.push(cycle);
+                    return instr_trap(vector, reg_file, mem.mem, mem.console);
+                }
+                // as with the instruction handlers an empty entry goes to the embedder
+                if mem.read(vector as u16) == 0 {
+                    return Err(VmError::UnknownTrap { vector: vector as u16 });
+                }
+                // 15 and the context save, then 46 / 28 / 30 save R7 and load PC from the trap vector table
+                self.save_context(15, None, reg_file, mem);
+
+                let mut cycle = Cycle::new(46, "MAR<-ZEXT[IR[7:0]]", ControlSignals {
+                    ld_mar: true, gate_marmux: true, marmux: Some("ZEXT"), ..Default::default()
+                });
+                self.mar = cycle.transfer("MAR", vector as u16);
+                self.cycles.push(cycle);
+
+                let mut cycle = Cycle::new(28, "MDR<-M[MAR], R7<-PC", ControlSignals {
+                    ld_reg: true, gate_pc: true, drmux: Some("R7"), ..memory(false)
+                });
+                self.mdr = cycle.read(mem, self.mar);
+                cycle.latch("MDR", self.mdr);
+                cycle.drive(pc);
+                self.set_reg(&mut cycle, reg_file, 7, pc);
+                self.cycles.push(cycle);
+
+                let mut cycle = Cycle::new(30, "PC<-MDR", ControlSignals { ld_pc: true, gate_mdr: true, pcmux: Some("BUS"), ..Default::default() });
+                reg_file.r_pc = cycle.transfer("PC", self.mdr);
+                self.cycles.push(cycle);
+            },
+            Instruction::Rti => self.rti(reg_file, mem)?,
+        }
+        Ok(StepOutcome::Continue)
+    }
+
+    // 25 and 27: DR <- M[MAR], set CC
+    fn load(&mut self, dr: u16, reg_file: &mut RegFile, mem: &mut MemoryBus<'_>) {
+        self.memory_read(25, mem);
+        let mut cycle = Cycle::new(27, "DR<-MDR, set CC", ControlSignals {
+            ld_reg: true, ld_cc: true, gate_mdr: true, drmux: Some("11.9"), ..Default::default()
+        });
+        let value = cycle.drive(self.mdr);
+        self.set_reg_cc(&mut cycle, reg_file, dr, value);
+        self.cycles.push(cycle);
+    }
+
+    // 23 and 16: M[MAR] <- SR
+    fn store(&mut self, sr: u16, reg_file: &mut RegFile, mem: &mut MemoryBus<'_>) {
+        let mut cycle = Cycle::new(23, "MDR<-SR", ControlSignals {
+            ld_mdr: true, gate_alu: true, sr1mux: Some("11.9"), aluk: Some("PASSA"), ..Default::default()
+        });
+        self.mdr = cycle.transfer("MDR", self.reg(reg_file, sr));
+        self.cycles.push(cycle);
+        self.memory_write(16, mem);
+    }
+
+    /**
+     * 8, 36, 38, 39, 40, 42 and 34: pop PC then PSR off the supervisor
+     * stack, then 59 goes back to the user stack or 51 stays; RTI in user
+     * mode raises a privilege mode violation through 44 instead
+     */
+    fn rti(&mut self, reg_file: &mut RegFile, mem: &mut MemoryBus<'_>) -> Result<(), VmError> {
+        let mut cycle = Cycle::new(8, "MAR<-SP, [PSR[15]]", ControlSignals {
+            ld_mar: true, gate_sp: true, sr1mux: Some("SP"), ..Default::default()
+        });
+        self.mar = cycle.transfer("MAR", self.reg(reg_file, 6));
+        self.cycles.push(cycle);
+        if reg_file.privilege == Privilege::User {
+            let error = VmError::PrivilegeViolation { pc: reg_file.r_pc.wrapping_sub(1) };
+            return self.exception(44, PRIVILEGE_VIOLATION, error, reg_file, mem);
+        }
+
+        self.memory_read(36, mem);
+        let mut cycle = Cycle::new(38, "PC<-MDR", ControlSignals { ld_pc: true, gate_mdr: true, pcmux: Some("BUS"), ..Default::default() });
+        reg_file.r_pc = cycle.transfer("PC", self.mdr);
+        self.cycles.push(cycle);
+
+        self.pop_address(39, reg_file, true);
+        self.memory_read(40, mem);
+        self.pop_address(42, reg_file, false);
+        self.cycles.push(Cycle::new(34, "[MDR[15]]", ControlSignals::default()));
+
+        let psr = self.mdr;
+        let mut cycle = if psr & 0x8000 != 0 {
+            let mut cycle = Cycle::new(59, "PSR<-MDR, Saved.SSP<-SP, SP<-Saved.USP", ControlSignals {
+                ld_psr: true, ld_saved_ssp: true, ld_reg: true, gate_mdr: true, drmux: Some("SP"), ..Default::default()
+            });
+            cycle.latch("SavedSSP", self.reg(reg_file, 6));
+            cycle.latch("R6", reg_file.saved_usp);
+            cycle
+        } else {
+            Cycle::new(51, "PSR<-MDR", ControlSignals { ld_psr: true, gate_mdr: true, ..Default::default() })
+        };
+        reg_file.set_psr(psr);
+        cycle.transfer("PSR", psr);
+        self.cycles.push(cycle);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod datapath_test {
+
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::console::BufferConsole;
+    use crate::interrupt::Timer;
+    use crate::machine::{Engine, Machine};
+    use crate::timing::TimingModel;
+
+    // every opcode, TRAPs through the bundled OS, both exceptions and a timer interrupt
+    const PROGRAM: &str = "
+        .ORIG x3000
+                LD R6, STACK
+                LEA R0, MSG
+                PUTS
+                AND R1, R1, #0
+                ADD R1, R1, #3
+        LOOP    NOT R2, R1
+                ADD R1, R1, #-1
+                BRp LOOP
+                LDI R3, PTR
+                STI R3, PTR
+                LEA R5, DATA
+                LDR R4, R5, #0
+                STR R4, R5, #1
+                ST R4, COPY
+                JSR SUB
+                LEA R7, SUB
+                JSRR R7
+                LEA R2, NEXT
+                JMP R2
+                HALT
+        NEXT    .FILL xD000
+                RTI
+                LEA R0, MSG
+                LDR R0, R0, #0
+                ADD R0, R0, R1
+                OUT
+                HALT
+        SUB     ADD R1, R1, #1
+                RET
+        STACK   .FILL x4000
+        PTR     .FILL DATA
+        DATA    .FILL x0041
+                .FILL x0000
+        COPY    .FILL x0000
+        MSG     .STRINGZ \"hi\"
+        .END
+    ";
+
+    fn machine(engine: Engine) -> (Machine, BufferConsole) {
+        let console = BufferConsole::new("");
+        let mut machine = Machine::with_console(Box::new(console.clone()));
+        machine.load_os().unwrap();
+        machine.load_image(&assemble(PROGRAM).unwrap().image).unwrap();
+        // exceptions count in R1 and return, the timer returns straight away
+        machine.mem.load_slice(0x1000, &[0x1261, 0x8000]);
+        machine.mem.load_slice(0x0100, &[0x1000, 0x1000]);
+        machine.mem.write(0x0181, 0x1001);
+        machine.interrupts.timer = Some(Timer::new(7, 0x81, 6));
+        machine.engine = engine;
+        (machine, console)
+    }
+
+    #[test]
+    fn matches_the_handlers() {
+        let (mut handlers, expected) = machine(Engine::Handlers);
+        let (mut datapath, actual) = machine(Engine::Datapath);
+        let mut entries = Vec::new();
+        loop {
+            let outcome = handlers.step().unwrap();
+            assert_eq!(datapath.step().unwrap(), outcome);
+            assert_eq!(datapath.reg_file, handlers.reg_file);
+            assert_eq!(datapath.last_accesses(), handlers.last_accesses());
+            assert_eq!(datapath.last_instruction(), handlers.last_instruction());
+            assert!(handlers.last_cycles().is_empty());
+            entries.extend(datapath.last_cycles().iter().map(|cycle| cycle.state).filter(|state| [13, 44, 49].contains(state)));
+            if outcome == StepOutcome::Halted {
+                break;
+            }
+        }
+        assert_eq!(datapath.mem.cells, handlers.mem.cells);
+        assert_eq!(actual.output(), expected.output());
+        // 'h' plus SUB twice and both exceptions
+        assert_eq!(expected.output(), "hilHalt the program.\n");
+        assert_eq!(entries.iter().filter(|state| **state != 49).count(), 2);
+        assert!(entries.contains(&49));
+    }
+
+    #[test]
+    fn cycles_agree_with_the_timing_model() {
+        let model = TimingModel::new(3);
+        for trap_mode in [TrapMode::VectorTable, TrapMode::Native] {
+            let (mut machine, _) = machine(Engine::Datapath);
+            machine.trap_mode = trap_mode;
+            machine.enable_timing(model);
+            let mut cycles = 0;
+            loop {
+                let outcome = machine.step().unwrap();
+                cycles += machine.last_cycles().iter()
+                    .map(|cycle| if cycle.is_memory() { model.memory_latency } else { 1 })
+                    .sum::<u64>();
+                if outcome == StepOutcome::Halted {
+                    break;
+                }
+            }
+            assert_eq!(cycles, machine.cycles());
+        }
+    }
+
+    #[test]
+    fn register_transfers() {
+        let mut machine = Machine::with_console(Box::new(BufferConsole::new("")));
+        machine.load_image(&assemble(".ORIG x3000\nLDI R1, PTR\nPTR .FILL x3002\n.FILL x1234\n.END").unwrap().image).unwrap();
+        machine.engine = Engine::Datapath;
+        machine.step().unwrap();
+        let states: Vec<u8> = machine.last_cycles().iter().map(|cycle| cycle.state).collect();
+        assert_eq!(states, vec![18, 33, 35, 32, 10, 24, 26, 25, 27]);
+        let fetch = &machine.last_cycles()[0];
+        assert_eq!(fetch.signals.asserted(), vec!["LD.MAR", "LD.PC", "GatePC", "PCMUX=PC+1"]);
+        assert_eq!(fetch.bus, Some(0x3000));
+        assert_eq!(machine.last_cycles()[5].transfers, vec![
+            Transfer::Read { address: 0x3001, value: 0x3002 },
+            Transfer::Latch { register: "MDR", value: 0x3002 },
+        ]);
+        assert_eq!(machine.last_cycles()[8].to_json(), "{\"state\":27,\"rtl\":\"DR<-MDR, set CC\",\
+            \"signals\":[\"LD.REG\",\"LD.CC\",\"GateMDR\",\"DRMUX=11.9\"],\"bus\":4660,\
+            \"transfers\":[{\"latch\":\"R1\",\"value\":4660},{\"latch\":\"CC\",\"value\":1}]}");
+        assert_eq!((machine.datapath().mar, machine.datapath().mdr, machine.datapath().ir), (0x3002, 0x1234, 0xA200));
+        assert_eq!(machine.reg_file.read_reg(1).unwrap(), 0x1234);
+    }
+}
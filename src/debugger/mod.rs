@@ -0,0 +1,593 @@
+/*
+This is the LC-3 Debugger Module, a command interpreter over a machine for
+setting breakpoints, stepping and inspecting or changing state
+*/
+use crate::assembler::lexer::parse_number;
+use crate::assembler::SymbolTable;
+use crate::console::BufferConsole;
+use crate::disassembler::disassemble_word;
+use crate::instruction::decode::*;
+use crate::machine::{Machine, StepOutcome, TrapMode};
+use crate::register::Privilege;
+use crate::snapshot::Snapshot;
+use crate::watch::{register_index, WatchAccess, Watchpoint};
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+pub const HELP: &str = "\
+break <addr|label>        stop when PC reaches an address
+delete [addr|label]       remove one breakpoint, or all of them
+step [n]                  execute n instructions (default 1)
+next                      step over JSR / JSRR / TRAP
+finish                    run until the current subroutine returns
+continue                  run until a breakpoint, watchpoint, HALT or the program waits for input
+watch <addr[-addr]|label> stop after a store to an address or range
+rwatch / awatch <addr>    stop after a load / any access
+watch <reg> [op value]    stop when a register changes, or starts matching e.g. `R6 < x2F00`
+watches                   list watchpoints
+unwatch [n]               remove watchpoint n, or all of them
+step-back [n]             undo n instructions or `set` edits (default 1)
+reverse-continue          undo instructions until a breakpoint or the start of the history
+rewind <count>            undo instructions until only `count` have executed
+regs                      show the register file
+mem <addr|label> [count]  show memory
+set reg <R0-R7|PC|COND|PSR> <value>
+set mem <addr|label> <value>
+list [addr|label]         disassemble around PC (or an address)
+save <file>               write a snapshot of the machine to a file
+load <file>               restore the machine from a snapshot file
+input <text>              queue keyboard input for the program (\\n for newline)
+quit                      leave the debugger
+an empty line repeats the previous command
+";
+
+// instructions kept in the undo log for stepping back, a record takes about
+// 150 bytes so a full log stays around 15 MB
+pub const HISTORY_LIMIT: usize = 100_000;
+
+const START_OF_HISTORY: &str = "Reached the start of the recorded history\n";
+
+// a parsed debugger command, addresses are resolved against the symbol table when executed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Break(String),
+    Delete(Option<String>),
+    Step(usize),
+    Next,
+    Finish,
+    Continue,
+    StepBack(usize),
+    ReverseContinue,
+    Rewind(u64),
+    Regs,
+    Mem(String, usize),
+    SetReg(String, String),
+    SetMem(String, String),
+    List(Option<String>),
+    Input(String),
+    Save(String),
+    Load(String),
+    Watch(WatchAccess, String),
+    Watches,
+    Unwatch(Option<usize>),
+    Help,
+    Quit,
+}
+
+impl Command {
+
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let count = |text: &str| -> Result<usize, String> {
+            match parse_number(text) {
+                Some(n) if n > 0 => Ok(n as usize),
+                _ => Err(format!("expected a positive count, found `{}`", text)),
+            }
+        };
+        let command = match words.as_slice() {
+            ["break", at] | ["b", at] => Command::Break(at.to_string()),
+            ["delete"] | ["d"] => Command::Delete(None),
+            ["delete", at] | ["d", at] => Command::Delete(Some(at.to_string())),
+            ["step"] | ["s"] => Command::Step(1),
+            ["step", n] | ["s", n] => Command::Step(count(n)?),
+            ["next"] | ["n"] => Command::Next,
+            ["finish"] | ["f"] => Command::Finish,
+            ["continue"] | ["c"] => Command::Continue,
+            ["step-back"] | ["sb"] => Command::StepBack(1),
+            ["step-back", n] | ["sb", n] => Command::StepBack(count(n)?),
+            ["reverse-continue"] | ["rc"] => Command::ReverseContinue,
+            ["rewind", n] => match parse_number(n) {
+                Some(n) if n >= 0 => Command::Rewind(n as u64),
+                _ => return Err(format!("expected an instruction count, found `{}`", n)),
+            },
+            ["regs"] | ["r"] => Command::Regs,
+            ["mem", at] | ["m", at] => Command::Mem(at.to_string(), 1),
+            ["mem", at, n] | ["m", at, n] => Command::Mem(at.to_string(), count(n)?),
+            ["set", "reg", reg, value] => Command::SetReg(reg.to_string(), value.to_string()),
+            ["set", "mem", at, value] => Command::SetMem(at.to_string(), value.to_string()),
+            ["list"] | ["l"] => Command::List(None),
+            ["list", at] | ["l", at] => Command::List(Some(at.to_string())),
+            ["input", ..] | ["i", ..] => {
+                // everything after the command word, spaces included
+                let text = line.trim_start().split_once(char::is_whitespace).map(|(_, rest)| rest).unwrap_or("");
+                Command::Input(text.replace("\\n", "\n"))
+            },
+            ["watch", _, ..] | ["rwatch", _, ..] | ["awatch", _, ..] => {
+                let access = match words[0] {
+                    "rwatch" => WatchAccess::Read,
+                    "awatch" => WatchAccess::Any,
+                    _ => WatchAccess::Write,
+                };
+                Command::Watch(access, words[1..].join(" "))
+            },
+            ["save", path] => Command::Save(path.to_string()),
+            ["load", path] => Command::Load(path.to_string()),
+            ["watches"] => Command::Watches,
+            ["unwatch"] => Command::Unwatch(None),
+            ["unwatch", n] => match n.parse() {
+                Ok(n) => Command::Unwatch(Some(n)),
+                Err(_) => return Err(format!("expected a watchpoint number, found `{}`", n)),
+            },
+            ["help"] | ["h"] => Command::Help,
+            ["quit"] | ["q"] => Command::Quit,
+            [] => return Err("empty command".to_string()),
+            [name, ..] => return Err(format!("unknown command or wrong arguments for `{}`, try `help`", name)),
+        };
+        Ok(command)
+    }
+}
+
+pub struct Debugger {
+    pub machine: Machine,
+    pub symbols: SymbolTable,
+    console: BufferConsole,
+    breakpoints: BTreeSet<u16>,
+    last_command: String,
+    quit: bool,
+}
+
+impl Debugger {
+
+    // take over a loaded machine, program input and output go through the debugger
+    pub fn new(mut machine: Machine, symbols: SymbolTable) -> Debugger {
+        let console = BufferConsole::new("");
+        machine.set_console(Box::new(console.clone()));
+        machine.enable_history(HISTORY_LIMIT);
+        Debugger {
+            machine,
+            symbols,
+            console,
+            breakpoints: BTreeSet::new(),
+            last_command: String::new(),
+            quit: false,
+        }
+    }
+
+    // whether `quit` was entered
+    pub fn quit_requested(&self) -> bool {
+        self.quit
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /**
+     * run one line of input and return what to show the user: the program's
+     * output followed by the debugger's response
+     */
+    pub fn execute(&mut self, line: &str) -> String {
+        let line = if line.trim().is_empty() { self.last_command.clone() } else { line.trim().to_string() };
+        if line.is_empty() {
+            return String::new();
+        }
+        self.last_command = line.clone();
+        let response = match Command::parse(&line) {
+            Ok(command) => self.run_command(command).unwrap_or_else(|e| format!("error: {}\n", e)),
+            Err(e) => format!("error: {}\n", e),
+        };
+        self.console.take_output() + &response
+    }
+
+    fn run_command(&mut self, command: Command) -> Result<String, String> {
+        let mut out = String::new();
+        match command {
+            Command::Break(at) => {
+                let address = self.resolve(&at)?;
+                self.breakpoints.insert(address);
+                writeln!(out, "Breakpoint at x{:04X}", address).unwrap();
+            },
+            Command::Delete(None) => {
+                self.breakpoints.clear();
+                writeln!(out, "Deleted all breakpoints").unwrap();
+            },
+            Command::Delete(Some(at)) => {
+                let address = self.resolve(&at)?;
+                if !self.breakpoints.remove(&address) {
+                    return Err(format!("no breakpoint at x{:04X}", address));
+                }
+                writeln!(out, "Deleted breakpoint at x{:04X}", address).unwrap();
+            },
+            Command::Step(n) => out += &self.resume(Some(n), |_| false),
+            Command::Next => {
+                let mut depth = 0;
+                out += &self.resume(None, |delta| {
+                    depth += delta;
+                    depth <= 0
+                });
+            },
+            Command::Finish => {
+                let mut depth = 0;
+                out += &self.resume(None, |delta| {
+                    depth += delta;
+                    depth < 0
+                });
+            },
+            Command::Continue => out += &self.resume(None, |_| false),
+            Command::StepBack(n) => {
+                let mut undone = 0;
+                while undone < n && self.machine.step_back() {
+                    undone += 1;
+                }
+                if undone < n {
+                    out += START_OF_HISTORY;
+                }
+                out += &self.line_at(self.machine.reg_file.r_pc);
+            },
+            Command::ReverseContinue => {
+                loop {
+                    if !self.machine.step_back() {
+                        out += START_OF_HISTORY;
+                        break;
+                    }
+                    let pc = self.machine.reg_file.r_pc;
+                    if self.breakpoints.contains(&pc) {
+                        writeln!(out, "Breakpoint at x{:04X}", pc).unwrap();
+                        break;
+                    }
+                }
+                out += &self.line_at(self.machine.reg_file.r_pc);
+            },
+            Command::Rewind(count) => {
+                if !self.machine.rewind_to(count) {
+                    out += START_OF_HISTORY;
+                }
+                writeln!(out, "After {} instructions", self.machine.instruction_count()).unwrap();
+                out += &self.line_at(self.machine.reg_file.r_pc);
+            },
+            Command::Regs => write!(out, "{}", self.machine.reg_file).unwrap(),
+            Command::Mem(at, count) => {
+                let start = self.resolve(&at)?;
+                for i in 0..count {
+                    let address = start.wrapping_add(i as u16);
+                    let label = self.symbols.name_of(address).unwrap_or("");
+                    writeln!(out, "x{:04X}  x{:04X}  {}", address, self.machine.mem.read(address), label).unwrap();
+                }
+            },
+            Command::SetReg(reg, value) => {
+                let index = register_index(&reg).ok_or_else(|| format!("unknown register `{}`", reg))?;
+                let value = parse_value(&value)?;
+                self.machine.edit_reg(index, value).map_err(|e| e.to_string())?;
+                writeln!(out, "{} = x{:04X}", reg.to_uppercase(), value).unwrap();
+            },
+            Command::SetMem(at, value) => {
+                let address = self.resolve(&at)?;
+                let value = parse_value(&value)?;
+                self.machine.edit_mem(address, value);
+                writeln!(out, "x{:04X} = x{:04X}", address, value).unwrap();
+            },
+            Command::List(at) => {
+                let center = match at {
+                    Some(at) => self.resolve(&at)?,
+                    None => self.machine.reg_file.r_pc,
+                };
+                for i in 0..10_u16 {
+                    out += &self.line_at(center.wrapping_sub(4).wrapping_add(i));
+                }
+            },
+            Command::Input(text) => {
+                self.console.push_input(&text);
+                writeln!(out, "Queued {} character{} of input", text.len(), if text.len() == 1 { "" } else { "s" }).unwrap();
+            },
+            Command::Save(path) => {
+                self.machine.snapshot().write_file(&path).map_err(|e| format!("{}: {}", path, e))?;
+                writeln!(out, "Saved snapshot to {}", path).unwrap();
+            },
+            Command::Load(path) => {
+                let snapshot = Snapshot::read_file(&path).map_err(|e| format!("{}: {}", path, e))?;
+                self.machine.restore(&snapshot);
+                writeln!(out, "Restored snapshot from {} after {} instructions", path, snapshot.count).unwrap();
+                out += &self.line_at(self.machine.reg_file.r_pc);
+            },
+            Command::Watch(access, target) => {
+                let watch = self.watchpoint(access, &target)?;
+                writeln!(out, "Watchpoint {}: {}", self.machine.watchpoints.len(), watch).unwrap();
+                self.machine.watchpoints.push(watch);
+            },
+            Command::Watches => {
+                for (i, watch) in self.machine.watchpoints.iter().enumerate() {
+                    writeln!(out, "{}: {}", i, watch).unwrap();
+                }
+            },
+            Command::Unwatch(None) => {
+                self.machine.watchpoints.clear();
+                writeln!(out, "Deleted all watchpoints").unwrap();
+            },
+            Command::Unwatch(Some(n)) => {
+                if n >= self.machine.watchpoints.len() {
+                    return Err(format!("no watchpoint {}", n));
+                }
+                let watch = self.machine.watchpoints.remove(n);
+                writeln!(out, "Deleted watchpoint {}: {}", n, watch).unwrap();
+            },
+            Command::Help => out += HELP,
+            Command::Quit => self.quit = true,
+        }
+        Ok(out)
+    }
+
+    /**
+     * keep stepping until `done` (given the call depth change of each executed
+     * instruction) says so, `limit` instructions ran, a breakpoint is reached
+     * or the machine stops
+     */
+    fn resume<F: FnMut(i32) -> bool>(&mut self, limit: Option<usize>, mut done: F) -> String {
+        let mut steps = 0;
+        let message = loop {
+            let (pc, privilege) = (self.machine.reg_file.r_pc, self.machine.reg_file.privilege);
+            match self.machine.step() {
+                Ok(StepOutcome::Continue) => {},
+                Ok(StepOutcome::Halted) => break "Program halted\n".to_string(),
+                Ok(StepOutcome::Watchpoint(index)) => {
+                    let message = self.machine.watch_hit().map(|hit| hit.message.as_str()).unwrap_or("");
+                    break format!("Watchpoint {}: {}\n", index, message);
+                },
+                Ok(StepOutcome::WaitingForInput) => {
+                    break "Program is waiting for input, queue some with `input <text>`\n".to_string()
+                },
+                Err(e) => break format!("error: {}\n", e),
+            }
+            steps += 1;
+            // an interrupt taken before the fetch is one level deeper again
+            let delta = match self.machine.last_instruction() {
+                Some((address, word)) if address != pc => 1 + self.call_depth_delta(word, Privilege::Supervisor),
+                Some((_, word)) => self.call_depth_delta(word, privilege),
+                None => 0,
+            };
+            if done(delta) || limit == Some(steps) {
+                break String::new();
+            }
+            let pc = self.machine.reg_file.r_pc;
+            if self.breakpoints.contains(&pc) {
+                break format!("Breakpoint at x{:04X}\n", pc);
+            }
+        };
+        message + &self.line_at(self.machine.reg_file.r_pc)
+    }
+
+    /**
+     * +1 for an instruction `word` entering a subroutine, a service
+     * routine or an exception routine, -1 for RET and for RTI in supervisor
+     * mode, 0 otherwise
+     */
+    fn call_depth_delta(&self, word: u16, privilege: Privilege) -> i32 {
+        match decode(word) {
+            Ok(Instruction::Jsr { .. }) | Ok(Instruction::Jsrr { .. }) => 1,
+            Ok(Instruction::Trap { .. }) if self.machine.trap_mode == TrapMode::VectorTable => 1,
+            Ok(Instruction::Ret) => -1,
+            Ok(Instruction::Rti) if privilege == Privilege::Supervisor => -1,
+            // an illegal opcode and RTI in user mode raise an exception
+            Ok(Instruction::Rti) | Err(_) => 1,
+            Ok(_) => 0,
+        }
+    }
+
+    // one disassembled line, marking PC and breakpoints
+    fn line_at(&self, address: u16) -> String {
+        let marker = if address == self.machine.reg_file.r_pc { "=>" } else { "  " };
+        let breakpoint = if self.breakpoints.contains(&address) { "*" } else { " " };
+        let line = disassemble_word(address, self.machine.mem.read(address), Some(&self.symbols));
+        format!("{}{} {}\n", marker, breakpoint, line)
+    }
+
+    // a register condition, or an address, label or `first-last` range of memory
+    fn watchpoint(&self, access: WatchAccess, target: &str) -> Result<Watchpoint, String> {
+        let first_word = target.split_whitespace().next().unwrap_or("");
+        if register_index(first_word).is_some() {
+            if access != WatchAccess::Write {
+                return Err("registers can only be watched with `watch`".to_string());
+            }
+            return Watchpoint::parse_register(target);
+        }
+        let (first, last) = match target.split_once('-') {
+            Some((first, last)) => (self.resolve(first)?, self.resolve(last)?),
+            None => (self.resolve(target)?, self.resolve(target)?),
+        };
+        if first > last {
+            return Err(format!("empty range x{:04X}-x{:04X}", first, last));
+        }
+        Ok(Watchpoint::Memory { first, last, access })
+    }
+
+    // an address literal or a label
+    fn resolve(&self, text: &str) -> Result<u16, String> {
+        if let Some(value) = parse_number(text) {
+            return match value {
+                0..=0xFFFF => Ok(value as u16),
+                _ => Err(format!("address `{}` is out of range", text)),
+            };
+        }
+        self.symbols.get(text)
+            .or_else(|| self.symbols.get(&text.to_uppercase()))
+            .ok_or_else(|| format!("unknown address or label `{}`", text))
+    }
+}
+
+// a 16-bit value, negative decimals are stored in two's complement
+fn parse_value(text: &str) -> Result<u16, String> {
+    match parse_number(text) {
+        Some(value) if (-0x8000..=0xFFFF).contains(&value) => Ok(value as u16),
+        _ => Err(format!("expected a 16-bit value, found `{}`", text)),
+    }
+}
+
+#[cfg(test)]
+mod debugger_test {
+
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::register::PC_START;
+
+    const SOURCE: &str = "
+        .ORIG x3000
+        AND R1, R1, #0
+        JSR BUMP
+        JSR BUMP
+        OUT
+        HALT
+BUMP    ADD R1, R1, #1
+        RET
+        .END
+    ";
+
+    fn debugger() -> Debugger {
+        let program = assemble(SOURCE).unwrap();
+        let mut machine = Machine::new();
+        machine.load_image(&program.image).unwrap();
+        Debugger::new(machine, program.symbols)
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::parse("step 5"), Ok(Command::Step(5)));
+        assert_eq!(Command::parse("set reg R3 x10"), Ok(Command::SetReg("R3".to_string(), "x10".to_string())));
+        assert_eq!(Command::parse("input a b\\n"), Ok(Command::Input("a b\n".to_string())));
+        assert!(Command::parse("step 0").is_err());
+        assert!(Command::parse("frobnicate").is_err());
+    }
+
+    #[test]
+    fn break_and_continue() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.execute("break BUMP"), "Breakpoint at x3005\n");
+        let out = debugger.execute("continue");
+        assert!(out.starts_with("Breakpoint at x3005\n=>* x3005"), "{}", out);
+        debugger.execute("c");
+        assert_eq!(debugger.machine.reg_file.r_pc, 0x3005);
+        assert_eq!(debugger.machine.reg_file.read_reg(1).unwrap(), 1);
+        debugger.execute("delete");
+        assert!(debugger.execute("c").contains("Halt the program.\nProgram halted\n"));
+    }
+
+    #[test]
+    fn next_and_finish() {
+        let mut debugger = debugger();
+        debugger.execute("step");
+        debugger.execute("next");
+        assert_eq!(debugger.machine.reg_file.r_pc, PC_START + 2);
+        assert_eq!(debugger.machine.reg_file.read_reg(1).unwrap(), 1);
+        debugger.execute("step");
+        assert_eq!(debugger.machine.reg_file.r_pc, 0x3005);
+        debugger.execute("finish");
+        assert_eq!(debugger.machine.reg_file.r_pc, PC_START + 3);
+        assert_eq!(debugger.machine.reg_file.read_reg(1).unwrap(), 2);
+        // an empty line repeats the last command
+        debugger.execute("s");
+        debugger.execute("");
+        assert_eq!(debugger.machine.reg_file.r_pc, PC_START + 5);
+    }
+
+    #[test]
+    fn next_over_an_os_trap() {
+        let program = assemble(".ORIG x3000\nLD R0, CHAR\nOUT\nADD R1, R1, #1\nHALT\nCHAR .FILL x41\n.END").unwrap();
+        let mut machine = Machine::new();
+        machine.load_image(&program.image).unwrap();
+        machine.load_os().unwrap();
+        let mut debugger = Debugger::new(machine, program.symbols);
+        debugger.execute("step");
+        assert_eq!(debugger.execute("next"), "A=>  x3002  x1261               ADD R1, R1, #1\n");
+        assert_eq!(debugger.machine.reg_file.r_pc, PC_START + 2);
+        // and back out of the service routine
+        debugger.execute("rewind 1");
+        debugger.execute("step");
+        assert_ne!(debugger.machine.reg_file.r_pc, PC_START + 2);
+        debugger.execute("finish");
+        assert_eq!(debugger.machine.reg_file.r_pc, PC_START + 2);
+    }
+
+    #[test]
+    fn watch_commands() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.execute("watch R1 > #1"), "Watchpoint 0: R1 > x0001\n");
+        assert_eq!(debugger.execute("rwatch x4000-x40FF"), "Watchpoint 1: read x4000-x40FF\n");
+        assert_eq!(debugger.execute("watches"), "0: R1 > x0001\n1: read x4000-x40FF\n");
+        let out = debugger.execute("c");
+        assert!(out.starts_with("Watchpoint 0: R1 > x0001 (now x0002)\n=>  x3006"), "{}", out);
+        assert_eq!(debugger.execute("unwatch 1"), "Deleted watchpoint 1: read x4000-x40FF\n");
+        assert!(debugger.execute("rwatch R1").starts_with("error:"));
+        assert!(debugger.execute("watch x40-x30").starts_with("error: empty range"));
+    }
+
+    #[test]
+    fn reverse_execution() {
+        let mut debugger = debugger();
+        debugger.execute("break BUMP");
+        debugger.execute("c");
+        debugger.execute("c");
+        assert_eq!(debugger.machine.instruction_count(), 5);
+        assert!(debugger.execute("step-back 2").starts_with("=>  x3006"));
+        assert_eq!(debugger.machine.reg_file.read_reg(1).unwrap(), 1);
+        assert!(debugger.execute("rc").starts_with("Breakpoint at x3005\n"));
+        assert_eq!(debugger.machine.instruction_count(), 2);
+        assert!(debugger.execute("rewind 0").starts_with("After 0 instructions\n=>  x3000"));
+        assert_eq!(debugger.machine.reg_file.r_pc, PC_START);
+        assert_eq!(debugger.execute("sb"), format!("{}=>  x3000  x5260               AND R1, R1, #0\n", START_OF_HISTORY));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("lc3-debugger-{}.snapshot", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut debugger = debugger();
+        debugger.execute("step 3");
+        debugger.execute(&format!("save {}", path));
+        debugger.execute("c");
+        let out = debugger.execute(&format!("load {}", path));
+        assert!(out.contains("after 3 instructions\n=>  x3006"), "{}", out);
+        assert_eq!(debugger.machine.reg_file.read_reg(1).unwrap(), 1);
+        std::fs::remove_file(path).unwrap();
+        assert!(debugger.execute(&format!("load {}", path)).starts_with("error:"));
+    }
+
+    #[test]
+    fn inspect_and_modify() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.execute("set reg r0 x41"), "R0 = x0041\n");
+        assert_eq!(debugger.execute("set mem x4000 #-1"), "x4000 = xFFFF\n");
+        assert_eq!(debugger.execute("mem x4000 2"), "x4000  xFFFF  \nx4001  x0000  \n");
+        assert!(debugger.execute("regs").contains("R0 => 0x41"));
+        let listing = debugger.execute("list");
+        assert_eq!(listing.lines().count(), 10);
+        assert!(listing.lines().nth(4).unwrap().starts_with("=>  x3000"));
+        assert!(debugger.execute("mem NOWHERE").starts_with("error: unknown address or label"));
+        debugger.execute("break x3004");
+        assert!(debugger.execute("c").starts_with("ABreakpoint at x3004"));
+    }
+
+    #[test]
+    fn step_back_undoes_edits() {
+        let mut debugger = debugger();
+        debugger.execute("step");
+        debugger.execute("set reg R1 x7");
+        debugger.execute("set mem BUMP x1262");
+        debugger.execute("step 3");
+        assert_eq!(debugger.machine.reg_file.read_reg(1).unwrap(), 9);
+        debugger.execute("step-back 4");
+        assert_eq!(debugger.machine.mem.read(0x3005), 0x1261);
+        assert_eq!(debugger.machine.reg_file.read_reg(1).unwrap(), 7);
+        debugger.execute("step-back");
+        assert_eq!(debugger.machine.reg_file.read_reg(1).unwrap(), 0);
+        assert_eq!(debugger.machine.instruction_count(), 1);
+    }
+}
@@ -0,0 +1,183 @@
+/*
+This is the LC-3 Device Module, the memory-mapped registers living in the
+device page xFE00-xFFFF
+*/
+use crate::console::Console;
+use crate::memory::{AccessKind, Bus, Memory, MemoryAccess};
+
+// device register addresses
+pub const KBSR: u16 = 0xFE00; // keyboard status
+pub const KBDR: u16 = 0xFE02; // keyboard data
+pub const DSR: u16  = 0xFE04; // display status
+pub const DDR: u16  = 0xFE06; // display data
+pub const MCR: u16  = 0xFFFE; // machine control
+
+// first address of the device page
+pub const DEVICE_PAGE: u16 = 0xFE00;
+
+const READY_BIT: u16 = 1 << 15;
+const INTERRUPT_ENABLE_BIT: u16 = 1 << 14;
+const CLOCK_ENABLE_BIT: u16 = 1 << 15;
+
+// state of the keyboard, display and machine control registers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Devices {
+    pub kbsr_ie: bool, // keyboard interrupt enable (KBSR bit 14)
+    pub kbdr: u16,     // last character read from the keyboard
+    pub dsr_ie: bool,  // display interrupt enable (DSR bit 14)
+    pub mcr: u16,
+}
+
+impl Devices {
+
+    pub fn new() -> Devices {
+        Devices { kbsr_ie: false, kbdr: 0, dsr_ie: false, mcr: CLOCK_ENABLE_BIT }
+    }
+
+    // MCR bit 15 runs the clock, clearing it stops the machine
+    pub fn clock_enabled(&self) -> bool {
+        self.mcr & CLOCK_ENABLE_BIT != 0
+    }
+
+    pub fn read(&mut self, addr: u16, console: &mut dyn Console) -> u16 {
+        match addr {
+            KBSR => {
+                let ready = if console.poll() { READY_BIT } else { 0 };
+                ready | if self.kbsr_ie { INTERRUPT_ENABLE_BIT } else { 0 }
+            },
+            KBDR => {
+                // reading the data register consumes the character and clears KBSR[15]
+                if console.poll() {
+                    if let Ok(Some(c)) = console.read_char() {
+                        self.kbdr = c as u16;
+                    }
+                }
+                self.kbdr
+            },
+            // the display is always ready to accept a character
+            DSR => READY_BIT | if self.dsr_ie { INTERRUPT_ENABLE_BIT } else { 0 },
+            DDR => 0,
+            MCR => self.mcr,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u16, console: &mut dyn Console) {
+        match addr {
+            KBSR => self.kbsr_ie = value & INTERRUPT_ENABLE_BIT != 0,
+            DSR => self.dsr_ie = value & INTERRUPT_ENABLE_BIT != 0,
+            DDR => {
+                // a failing display cannot be reported through a store, so drop the character
+                let _ = console.write_char((value & 0xff) as u8).and_then(|_| console.flush());
+            },
+            MCR => self.mcr = value,
+            _ => {},
+        }
+    }
+}
+
+impl Default for Devices {
+    fn default() -> Devices {
+        Devices::new()
+    }
+}
+
+/**
+ * the address space seen by a running program: plain memory below the device
+ * page, device registers from xFE00 up; every access is appended to `log`
+ * when one is given
+ */
+pub struct MemoryBus<'a> {
+    pub mem: &'a mut Memory,
+    pub devices: &'a mut Devices,
+    pub console: &'a mut dyn Console,
+    pub log: Option<&'a mut Vec<MemoryAccess>>,
+}
+
+impl<'a> Bus for MemoryBus<'a> {
+    fn read(&mut self, addr: u16) -> u16 {
+        let value = if addr >= DEVICE_PAGE {
+            self.devices.read(addr, self.console)
+        } else {
+            self.mem.read(addr)
+        };
+        if let Some(log) = self.log.as_mut() {
+            log.push(MemoryAccess { kind: AccessKind::Read, address: addr, value, old: value });
+        }
+        value
+    }
+
+    fn write(&mut self, addr: u16, value: u16) {
+        // device registers have no stored value to replace
+        let old = if addr >= DEVICE_PAGE { 0 } else { self.mem.read(addr) };
+        if addr >= DEVICE_PAGE {
+            self.devices.write(addr, value, self.console);
+        } else {
+            self.mem.write(addr, value);
+        }
+        if let Some(log) = self.log.as_mut() {
+            log.push(MemoryAccess { kind: AccessKind::Write, address: addr, value, old });
+        }
+    }
+}
+
+#[cfg(test)]
+mod device_test {
+
+    use super::*;
+    use crate::console::BufferConsole;
+
+    #[test]
+    fn keyboard_polling() {
+        let mut mem = Memory::new();
+        let mut devices = Devices::new();
+        let mut console = BufferConsole::new("a");
+        let mut bus = MemoryBus { mem: &mut mem, devices: &mut devices, console: &mut console, log: None };
+        assert_eq!(bus.read(KBSR), 0x8000);
+        assert_eq!(bus.read(KBDR), 'a' as u16);
+        assert_eq!(bus.read(KBSR), 0x0000);
+        bus.write(KBSR, 0x4000);
+        assert_eq!(bus.read(KBSR), 0x4000);
+    }
+
+    #[test]
+    fn display_output() {
+        let mut mem = Memory::new();
+        let mut devices = Devices::new();
+        let console = BufferConsole::new("");
+        let mut handle = console.clone();
+        let mut bus = MemoryBus { mem: &mut mem, devices: &mut devices, console: &mut handle, log: None };
+        assert_eq!(bus.read(DSR) & 0x8000, 0x8000);
+        bus.write(DDR, 'Z' as u16);
+        assert_eq!(console.output(), "Z");
+    }
+
+    #[test]
+    fn access_log() {
+        let mut mem = Memory::new();
+        let mut devices = Devices::new();
+        let mut console = BufferConsole::new("");
+        let mut log = Vec::new();
+        mem.write(0x4000, 3);
+        let mut bus = MemoryBus { mem: &mut mem, devices: &mut devices, console: &mut console, log: Some(&mut log) };
+        bus.write(0x4000, 9);
+        bus.read(0x4000);
+        assert_eq!(log, vec![
+            MemoryAccess { kind: AccessKind::Write, address: 0x4000, value: 9, old: 3 },
+            MemoryAccess { kind: AccessKind::Read, address: 0x4000, value: 9, old: 9 },
+        ]);
+    }
+
+    #[test]
+    fn memory_below_device_page() {
+        let mut mem = Memory::new();
+        let mut devices = Devices::new();
+        let mut console = BufferConsole::new("");
+        let mut bus = MemoryBus { mem: &mut mem, devices: &mut devices, console: &mut console, log: None };
+        bus.write(0xFDFF, 7);
+        bus.write(MCR, 0);
+        assert_eq!(bus.read(0xFDFF), 7);
+        assert!(!devices.clock_enabled());
+        assert_eq!(mem.read(0xFDFF), 7);
+    }
+}
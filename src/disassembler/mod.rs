@@ -0,0 +1,169 @@
+/*
+This is the LC-3 Disassembler Module, turning memory or object images back
+into readable assembly
+*/
+use crate::assembler::SymbolTable;
+use crate::instruction::decode::*;
+use crate::instruction::trap::*;
+use crate::loader::ObjectImage;
+use crate::memory::Memory;
+
+use std::fmt;
+
+// one disassembled word
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub address: u16,
+    pub word: u16,
+    // label bound to this address, if any
+    pub label: Option<String>,
+    pub text: String,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "x{:04X}  x{:04X}  {:<12} {}",
+            self.address, self.word, self.label.as_deref().unwrap_or(""), self.text,
+        )
+    }
+}
+
+fn trap_name(vector: u8) -> Option<&'static str> {
+    match vector as u16 {
+        GETC => Some("GETC"),
+        OUT => Some("OUT"),
+        PUTS => Some("PUTS"),
+        IN => Some("IN"),
+        PUTSP => Some("PUTSP"),
+        HALT => Some("HALT"),
+        _ => None,
+    }
+}
+
+fn operand(op: Operand) -> String {
+    match op {
+        Operand::Reg(r) => format!("R{}", r),
+        Operand::Imm5(imm) => format!("#{}", imm),
+    }
+}
+
+// absolute target of a PC-relative offset, with its label as a comment
+fn target(address: u16, offset: i16, symbols: Option<&SymbolTable>) -> String {
+    let target = address.wrapping_add(1).wrapping_add(offset as u16);
+    match symbols.and_then(|s| s.name_of(target)) {
+        Some(name) => format!("x{:04X} ; {}", target, name),
+        None => format!("x{:04X}", target),
+    }
+}
+
+/**
+ * render one decoded instruction located at `address`
+ */
+pub fn format_instruction(instr: &Instruction, address: u16, symbols: Option<&SymbolTable>) -> String {
+    match *instr {
+        Instruction::Br { n: false, z: false, p: false, .. } => "NOP".to_string(),
+        Instruction::Br { n, z, p, offset9 } => {
+            let mut name = "BR".to_string();
+            if !(n && z && p) {
+                if n { name.push('n'); }
+                if z { name.push('z'); }
+                if p { name.push('p'); }
+            }
+            format!("{} {}", name, target(address, offset9, symbols))
+        },
+        Instruction::Add { dr, sr1, operand: op } => format!("ADD R{}, R{}, {}", dr, sr1, operand(op)),
+        Instruction::And { dr, sr1, operand: op } => format!("AND R{}, R{}, {}", dr, sr1, operand(op)),
+        Instruction::Ld { dr, offset9 } => format!("LD R{}, {}", dr, target(address, offset9, symbols)),
+        Instruction::Ldi { dr, offset9 } => format!("LDI R{}, {}", dr, target(address, offset9, symbols)),
+        Instruction::Lea { dr, offset9 } => format!("LEA R{}, {}", dr, target(address, offset9, symbols)),
+        Instruction::St { sr, offset9 } => format!("ST R{}, {}", sr, target(address, offset9, symbols)),
+        Instruction::Sti { sr, offset9 } => format!("STI R{}, {}", sr, target(address, offset9, symbols)),
+        Instruction::Jsr { offset11 } => format!("JSR {}", target(address, offset11, symbols)),
+        Instruction::Jsrr { base_r } => format!("JSRR R{}", base_r),
+        Instruction::Ldr { dr, base_r, offset6 } => format!("LDR R{}, R{}, #{}", dr, base_r, offset6),
+        Instruction::Str { sr, base_r, offset6 } => format!("STR R{}, R{}, #{}", sr, base_r, offset6),
+        Instruction::Not { dr, sr } => format!("NOT R{}, R{}", dr, sr),
+        Instruction::Jmp { base_r } => format!("JMP R{}", base_r),
+        Instruction::Ret => "RET".to_string(),
+        Instruction::Rti => "RTI".to_string(),
+        Instruction::Trap { vector } => match trap_name(vector) {
+            Some(name) => name.to_string(),
+            None => format!("TRAP x{:02X}", vector),
+        },
+    }
+}
+
+/**
+ * disassemble a single word, words that do not decode are shown as data
+ */
+pub fn disassemble_word(address: u16, word: u16, symbols: Option<&SymbolTable>) -> Line {
+    let text = match decode(word) {
+        Ok(instr) => format_instruction(&instr, address, symbols),
+        Err(_) => format!(".FILL x{:04X}", word),
+    };
+    let label = symbols.and_then(|s| s.name_of(address)).map(|name| name.to_string());
+    Line { address, word, label, text }
+}
+
+// disassemble `count` words of memory starting at `start`
+pub fn disassemble_memory(mem: &Memory, start: u16, count: usize, symbols: Option<&SymbolTable>) -> Vec<Line> {
+    (0..count)
+        .map(|i| start.wrapping_add(i as u16))
+        .map(|address| disassemble_word(address, mem.read(address), symbols))
+        .collect()
+}
+
+// disassemble every word of an object image
+pub fn disassemble_image(image: &ObjectImage, symbols: Option<&SymbolTable>) -> Vec<Line> {
+    image.words.iter().enumerate()
+        .map(|(i, word)| disassemble_word(image.origin.wrapping_add(i as u16), *word, symbols))
+        .collect()
+}
+
+#[cfg(test)]
+mod disassembler_test {
+
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn format_basic_instructions() {
+        assert_eq!(disassemble_word(0x3000, 0x1261, None).text, "ADD R1, R1, #1");
+        assert_eq!(disassemble_word(0x3000, 0x5A3F, None).text, "AND R5, R0, #-1");
+        assert_eq!(disassemble_word(0x3000, 0x6C7E, None).text, "LDR R6, R1, #-2");
+        assert_eq!(disassemble_word(0x3000, 0xF025, None).text, "HALT");
+        assert_eq!(disassemble_word(0x3000, 0xF0FF, None).text, "TRAP xFF");
+        assert_eq!(disassemble_word(0x3000, 0x0000, None).text, "NOP");
+        assert_eq!(disassemble_word(0x3000, 0xD123, None).text, ".FILL xD123");
+    }
+
+    #[test]
+    fn resolve_pc_relative_targets() {
+        let program = assemble(".ORIG x3000\nLOOP ADD R0, R0, #-1\nBRz LOOP\nBR LOOP\n.END").unwrap();
+        let lines = disassemble_image(&program.image, Some(&program.symbols));
+        assert_eq!(lines[0].label.as_deref(), Some("LOOP"));
+        assert_eq!(lines[1].text, "BRz x3000 ; LOOP");
+        assert_eq!(lines[2].text, "BR x3000 ; LOOP");
+        assert_eq!(lines[1].to_string(), "x3001  x05FE               BRz x3000 ; LOOP");
+    }
+
+    #[test]
+    fn long_labels_stay_apart_from_the_instruction() {
+        let program = assemble(".ORIG x3000
+COUNTDOWN_LOOP ADD R0, R0, #-1
+.END").unwrap();
+        let lines = disassemble_image(&program.image, Some(&program.symbols));
+        assert_eq!(lines[0].to_string(), "x3000  x103F  COUNTDOWN_LOOP ADD R0, R0, #-1");
+    }
+
+    #[test]
+    fn disassemble_from_memory() {
+        let mut mem = Memory::new();
+        mem.write(0x3000, 0xE002); // LEA R0, x3003
+        mem.write(0x3001, 0xC1C0); // RET
+        let lines = disassemble_memory(&mem, 0x3000, 2, None);
+        assert_eq!(lines[0].text, "LEA R0, x3003");
+        assert_eq!(lines[1].text, "RET");
+    }
+}
@@ -0,0 +1,49 @@
+/*
+Errors raised while executing LC-3 code, returned to the embedder instead of
+panicking or exiting the process
+*/
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum VmError {
+    // the machine already executed HALT
+    Halted,
+    // the fetched word has no valid meaning (e.g. the reserved opcode 1101)
+    IllegalOpcode { pc: u16, word: u16 },
+    // RTI executed in user mode
+    PrivilegeViolation { pc: u16 },
+    // TRAP with a vector that has no service routine
+    UnknownTrap { vector: u16 },
+    // a register index outside R0-R7 / PC / COND / PSR
+    InvalidRegister { reg: u16 },
+    IoError(io::Error),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::Halted => write!(f, "the machine is halted"),
+            VmError::IllegalOpcode { pc, word } => write!(f, "illegal opcode 0x{:04x} at 0x{:04x}", word, pc),
+            VmError::PrivilegeViolation { pc } => write!(f, "privilege mode violation at 0x{:04x}", pc),
+            VmError::UnknownTrap { vector } => write!(f, "unknown trap vector 0x{:02x}", vector),
+            VmError::InvalidRegister { reg } => write!(f, "invalid register {}", reg),
+            VmError::IoError(e) => write!(f, "console I/O failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for VmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VmError::IoError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VmError {
+    fn from(e: io::Error) -> VmError {
+        VmError::IoError(e)
+    }
+}
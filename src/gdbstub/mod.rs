@@ -0,0 +1,587 @@
+/*
+This is the LC-3 GDB Stub Module, serving a machine over the GDB Remote
+Serial Protocol so gdb front ends and scripts can drive the simulator
+*/
+use crate::error::VmError;
+use crate::machine::{Machine, StepOutcome};
+use crate::memory::AccessKind;
+use crate::register::{PC_REG, PSR_REG};
+use crate::watch::{WatchAccess, Watchpoint};
+
+use std::collections::BTreeSet;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+// R0-R7, PC and PSR, in the order of `g` packets and the target description
+const REGISTERS: usize = 10;
+
+// the largest reply, in the hex digits of a packet; longer reads are cut short
+const PACKET_SIZE: usize = 0x1000;
+
+// instructions run between checks for a ^C from the client
+const POLL_INTERVAL: usize = 1024;
+
+// SIGINT, SIGILL and SIGTRAP, as gdb numbers them
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+// the registers as gdb sees them, served through qXfer:features:read
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.lc3.core">
+    <reg name="r0" bitsize="16" type="int16" regnum="0"/>
+    <reg name="r1" bitsize="16" type="int16"/>
+    <reg name="r2" bitsize="16" type="int16"/>
+    <reg name="r3" bitsize="16" type="int16"/>
+    <reg name="r4" bitsize="16" type="int16"/>
+    <reg name="r5" bitsize="16" type="int16"/>
+    <reg name="r6" bitsize="16" type="data_ptr"/>
+    <reg name="r7" bitsize="16" type="code_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="psr" bitsize="16" type="int16"/>
+  </feature>
+</target>
+"#;
+
+/**
+ * a byte stream to a gdb client that can also be checked for a ^C without
+ * blocking while the machine runs
+ */
+pub trait Connection: Read + Write {
+    // the next byte the client sent, `None` if nothing arrived yet
+    fn poll_byte(&mut self) -> io::Result<Option<u8>>;
+}
+
+fn poll_nonblocking<F: FnMut(bool) -> io::Result<()>, R: Read>(stream: &mut R, mut set_nonblocking: F) -> io::Result<Option<u8>> {
+    set_nonblocking(true)?;
+    let mut byte = [0; 1];
+    let result = match stream.read(&mut byte) {
+        Ok(1) => Ok(Some(byte[0])),
+        Ok(_) => Ok(None),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e),
+    };
+    set_nonblocking(false)?;
+    result
+}
+
+impl Connection for TcpStream {
+    fn poll_byte(&mut self) -> io::Result<Option<u8>> {
+        let stream = self.try_clone()?;
+        poll_nonblocking(self, |on| stream.set_nonblocking(on))
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn poll_byte(&mut self) -> io::Result<Option<u8>> {
+        let stream = self.try_clone()?;
+        poll_nonblocking(self, |on| stream.set_nonblocking(on))
+    }
+}
+
+// the modulo 256 sum of a packet's data
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+// frame a reply as `$data#cs`, escaping `#`, `$`, `}` and `*`
+pub fn encode_packet(data: &str) -> Vec<u8> {
+    let mut body = Vec::with_capacity(data.len());
+    for byte in data.bytes() {
+        if matches!(byte, b'#' | b'$' | b'}' | b'*') {
+            body.push(b'}');
+            body.push(byte ^ 0x20);
+        } else {
+            body.push(byte);
+        }
+    }
+    let mut packet = vec![b'$'];
+    packet.extend_from_slice(&body);
+    packet.extend_from_slice(format!("#{:02x}", checksum(&body)).as_bytes());
+    packet
+}
+
+// what the client sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    Command(String),
+    // a ^C outside of a packet, asking to stop the running machine
+    Interrupt,
+}
+
+fn read_byte<C: Read>(conn: &mut C) -> io::Result<Option<u8>> {
+    let mut byte = [0; 1];
+    loop {
+        match conn.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+/**
+ * read the next packet, skipping acknowledgements; a packet with a bad
+ * checksum is answered with `-` so the client resends it, good ones with `+`
+ * unless acknowledgements are off; `None` when the client hung up
+ */
+pub fn read_packet<C: Read + Write>(conn: &mut C, ack: bool) -> io::Result<Option<Packet>> {
+    loop {
+        match read_byte(conn)? {
+            None => return Ok(None),
+            Some(0x03) => return Ok(Some(Packet::Interrupt)),
+            Some(b'$') => {},
+            Some(_) => continue,
+        }
+        let mut body = Vec::new();
+        let mut escaped = false;
+        let mut data = Vec::new();
+        loop {
+            let byte = match read_byte(conn)? {
+                Some(byte) => byte,
+                None => return Ok(None),
+            };
+            if byte == b'#' && !escaped {
+                break;
+            }
+            body.push(byte);
+            if escaped {
+                data.push(byte ^ 0x20);
+                escaped = false;
+            } else if byte == b'}' {
+                escaped = true;
+            } else {
+                data.push(byte);
+            }
+        }
+        let high = read_byte(conn)?.and_then(hex_digit);
+        let low = read_byte(conn)?.and_then(hex_digit);
+        let valid = matches!((high, low), (Some(high), Some(low)) if (high << 4 | low) == checksum(&body));
+        if ack {
+            conn.write_all(if valid { b"+" } else { b"-" })?;
+            conn.flush()?;
+        }
+        if valid || !ack {
+            return Ok(Some(Packet::Command(String::from_utf8_lossy(&data).into_owned())));
+        }
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+// a word as gdb expects registers and memory, low byte first
+fn word_hex(word: u16) -> String {
+    format!("{:02x}{:02x}", word & 0xff, word >> 8)
+}
+
+// bytes from a hex string
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// `addr,length` with both in hex
+fn parse_range(text: &str) -> Option<(u16, usize)> {
+    let (address, length) = text.split_once(',')?;
+    let address = parse_hex(address)?;
+    if address > 0xFFFF {
+        return None;
+    }
+    Some((address as u16, parse_hex(length)? as usize))
+}
+
+// what the stub does after a command
+enum Reply {
+    Packet(String),
+    // the client is done with the machine (`k` or `D` after its OK)
+    Close(Option<String>),
+}
+
+/**
+ * a machine served to one gdb client at a time; addresses are LC-3 word
+ * addresses, lengths count bytes (two per word) and words go low byte
+ * first, so `m3000,4` reads x3000 and x3001
+ */
+pub struct GdbStub {
+    pub machine: Machine,
+    breakpoints: BTreeSet<u16>,
+    ack: bool,
+}
+
+impl GdbStub {
+
+    pub fn new(machine: Machine) -> GdbStub {
+        GdbStub { machine, breakpoints: BTreeSet::new(), ack: true }
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    fn send<C: Write>(&self, conn: &mut C, data: &str) -> io::Result<()> {
+        conn.write_all(&encode_packet(data))?;
+        conn.flush()
+    }
+
+    // answer packets until the client detaches, kills the machine or hangs up
+    pub fn serve<C: Connection>(&mut self, conn: &mut C) -> io::Result<()> {
+        self.ack = true;
+        while let Some(packet) = read_packet(conn, self.ack)? {
+            let command = match packet {
+                Packet::Command(command) => command,
+                // nothing is running, so there is nothing to stop
+                Packet::Interrupt => continue,
+            };
+            match self.command(&command, conn)? {
+                Reply::Packet(reply) => self.send(conn, &reply)?,
+                Reply::Close(reply) => {
+                    if let Some(reply) = reply {
+                        self.send(conn, &reply)?;
+                    }
+                    return Ok(());
+                },
+            }
+        }
+        Ok(())
+    }
+
+    // gdb's register number => register file index
+    fn register(number: u32) -> Option<u16> {
+        match number {
+            0..=7 => Some(number as u16),
+            8 => Some(PC_REG),
+            9 => Some(PSR_REG),
+            _ => None,
+        }
+    }
+
+    fn read_register(&self, number: u32) -> Option<u16> {
+        self.machine.reg_file.read_reg(GdbStub::register(number)?).ok()
+    }
+
+    fn write_register(&mut self, number: u32, value: u16) -> Option<()> {
+        self.machine.reg_file.update_reg(GdbStub::register(number)?, value).ok()
+    }
+
+    fn command<C: Connection>(&mut self, command: &str, conn: &mut C) -> io::Result<Reply> {
+        const ERROR: &str = "E01";
+        let reply = |reply: Option<String>| Reply::Packet(reply.unwrap_or_else(|| ERROR.to_string()));
+        let (kind, args) = command.split_at(command.len().min(1));
+        Ok(match kind {
+            "?" => Reply::Packet(format!("S{:02x}", SIGTRAP)),
+            "g" => Reply::Packet((0..REGISTERS as u32).filter_map(|n| self.read_register(n)).map(word_hex).collect()),
+            "G" => reply(decode_hex(args).filter(|bytes| bytes.len() == REGISTERS * 2).map(|bytes| {
+                for (n, pair) in bytes.chunks(2).enumerate() {
+                    self.write_register(n as u32, u16::from_le_bytes([pair[0], pair[1]]));
+                }
+                "OK".to_string()
+            })),
+            "p" => reply(parse_hex(args).and_then(|n| self.read_register(n)).map(word_hex)),
+            "P" => reply((|| {
+                let (number, value) = args.split_once('=')?;
+                let bytes = decode_hex(value).filter(|bytes| bytes.len() == 2)?;
+                self.write_register(parse_hex(number)?, u16::from_le_bytes([bytes[0], bytes[1]]))?;
+                Some("OK".to_string())
+            })()),
+            "m" => reply(parse_range(args).map(|(address, length)| {
+                let bytes: Vec<u8> = (0..length.min(PACKET_SIZE / 2))
+                    .map(|i| self.machine.mem.read(address.wrapping_add((i / 2) as u16)).to_le_bytes()[i % 2])
+                    .collect();
+                encode_hex(&bytes)
+            })),
+            "M" => reply((|| {
+                let (range, data) = args.split_once(':')?;
+                let (address, length) = parse_range(range)?;
+                let bytes = decode_hex(data).filter(|bytes| bytes.len() == length)?;
+                for (i, byte) in bytes.iter().enumerate() {
+                    let address = address.wrapping_add((i / 2) as u16);
+                    let mut word = self.machine.mem.read(address).to_le_bytes();
+                    word[i % 2] = *byte;
+                    self.machine.mem.write(address, u16::from_le_bytes(word));
+                }
+                Some("OK".to_string())
+            })()),
+            "Z" | "z" => reply(self.breakpoint(kind == "Z", args)),
+            "c" | "s" => {
+                if !args.is_empty() {
+                    match parse_hex(args) {
+                        Some(address) if address <= 0xFFFF => self.machine.reg_file.r_pc = address as u16,
+                        _ => return Ok(Reply::Packet(ERROR.to_string())),
+                    }
+                }
+                Reply::Packet(self.resume(kind == "s", conn)?)
+            },
+            "v" if args == "Cont?" => Reply::Packet("vCont;c;s".to_string()),
+            "v" if args.starts_with("Cont;") => {
+                // one thread, so the first action decides
+                let step = args["Cont;".len()..].starts_with('s');
+                Reply::Packet(self.resume(step, conn)?)
+            },
+            "H" => Reply::Packet("OK".to_string()),
+            "k" => Reply::Close(None),
+            "D" => Reply::Close(Some("OK".to_string())),
+            "q" | "Q" => Reply::Packet(self.query(command)),
+            // anything else is not supported, which gdb understands from an empty reply
+            _ => Reply::Packet(String::new()),
+        })
+    }
+
+    fn query(&mut self, command: &str) -> String {
+        if command.starts_with("qSupported") {
+            return format!("PacketSize={:x};qXfer:features:read+;swbreak+;QStartNoAckMode+", PACKET_SIZE);
+        }
+        if let Some(range) = command.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_range(range) {
+                Some((offset, length)) => {
+                    let rest = TARGET_XML.get(offset as usize..).unwrap_or("");
+                    let chunk = &rest[..rest.len().min(length)];
+                    format!("{}{}", if chunk.len() < rest.len() { "m" } else { "l" }, chunk)
+                },
+                None => "E01".to_string(),
+            };
+        }
+        match command {
+            "QStartNoAckMode" => {
+                self.ack = false;
+                "OK".to_string()
+            },
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /**
+     * `Z<type>,<addr>,<kind>` / `z...`: types 0 and 1 are breakpoints, 2, 3
+     * and 4 write, read and access watchpoints on `kind` bytes
+     */
+    fn breakpoint(&mut self, insert: bool, args: &str) -> Option<String> {
+        let (kind, range) = args.split_once(',')?;
+        let (address, length) = parse_range(range)?;
+        let access = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.breakpoints.insert(address);
+                } else {
+                    self.breakpoints.remove(&address);
+                }
+                return Some("OK".to_string());
+            },
+            "2" => WatchAccess::Write,
+            "3" => WatchAccess::Read,
+            "4" => WatchAccess::Any,
+            _ => return Some(String::new()),
+        };
+        let last = address.wrapping_add((length.max(1).div_ceil(2) - 1) as u16);
+        let watch = Watchpoint::Memory { first: address, last: last.max(address), access };
+        if insert {
+            self.machine.watchpoints.push(watch);
+        } else if let Some(i) = self.machine.watchpoints.iter().position(|w| *w == watch) {
+            self.machine.watchpoints.remove(i);
+        }
+        Some("OK".to_string())
+    }
+
+    /**
+     * run one instruction or until a breakpoint, a watchpoint, HALT, an
+     * error or a ^C from the client, returning the stop reply; errors are
+     * printed on the gdb console first
+     */
+    fn resume<C: Connection>(&mut self, step: bool, conn: &mut C) -> io::Result<String> {
+        let mut steps = 0;
+        loop {
+            match self.machine.step() {
+                Ok(StepOutcome::Continue) => {},
+                Ok(StepOutcome::Halted) => return Ok("W00".to_string()),
+                Ok(StepOutcome::Watchpoint(index)) => return Ok(self.watch_stop(index)),
+                Ok(StepOutcome::WaitingForInput) => {
+                    if conn.poll_byte()? == Some(0x03) {
+                        return Ok(format!("S{:02x}", SIGINT));
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                    continue;
+                },
+                // the program already exited
+                Err(VmError::Halted) => return Ok("W00".to_string()),
+                Err(e) => {
+                    self.send(conn, &format!("O{}", encode_hex(format!("error: {}\n", e).as_bytes())))?;
+                    return Ok(format!("S{:02x}", SIGILL));
+                },
+            }
+            steps += 1;
+            if step {
+                return Ok(format!("S{:02x}", SIGTRAP));
+            }
+            if self.breakpoints.contains(&self.machine.reg_file.r_pc) {
+                return Ok(format!("T{:02x}swbreak:;", SIGTRAP));
+            }
+            if steps % POLL_INTERVAL == 0 && conn.poll_byte()? == Some(0x03) {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+    }
+
+    // `T05watch:addr;`, `rwatch` or `awatch` for the access that triggered watchpoint `index`
+    fn watch_stop(&self, index: usize) -> String {
+        if let Some(Watchpoint::Memory { first, last, access }) = self.machine.watchpoints.get(index) {
+            let name = match access {
+                WatchAccess::Write => "watch",
+                WatchAccess::Read => "rwatch",
+                WatchAccess::Any => "awatch",
+            };
+            let address = self.machine.last_accesses().iter()
+                .filter(|a| *access == WatchAccess::Any || (a.kind == AccessKind::Write) == (*access == WatchAccess::Write))
+                .map(|a| a.address)
+                .find(|address| address >= first && address <= last)
+                .unwrap_or(*first);
+            return format!("T{:02x}{}:{:x};", SIGTRAP, name, address);
+        }
+        format!("S{:02x}", SIGTRAP)
+    }
+}
+
+#[cfg(test)]
+mod gdbstub_test {
+
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::console::BufferConsole;
+
+    use std::collections::VecDeque;
+
+    // a client that sent `input` up front, recording everything the stub writes
+    struct Pipe {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    impl Pipe {
+        fn new(packets: &[&str]) -> Pipe {
+            let input = packets.iter().flat_map(|packet| match *packet {
+                "^C" => vec![0x03],
+                packet => encode_packet(packet),
+            }).collect();
+            Pipe { input, output: Vec::new() }
+        }
+
+        // the data of every packet the stub sent
+        fn replies(&self) -> Vec<String> {
+            let text = String::from_utf8_lossy(&self.output).into_owned();
+            text.split('$').skip(1).map(|packet| packet.rsplit_once('#').unwrap().0.to_string()).collect()
+        }
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Pipe {
+        fn poll_byte(&mut self) -> io::Result<Option<u8>> {
+            Ok(self.input.pop_front())
+        }
+    }
+
+    fn stub(source: &str) -> GdbStub {
+        let mut machine = Machine::with_console(Box::new(BufferConsole::new("")));
+        machine.load_image(&assemble(source).unwrap().image).unwrap();
+        GdbStub::new(machine)
+    }
+
+    const PROGRAM: &str = "
+        .ORIG x3000
+                AND R1, R1, #0
+        LOOP    ADD R1, R1, #1
+                ST R1, COUNT
+                ADD R2, R1, #-3
+                BRn LOOP
+                HALT
+        COUNT   .FILL x0000
+        .END
+    ";
+
+    #[test]
+    fn framing() {
+        assert_eq!(encode_packet("OK"), b"$OK#9a");
+        assert_eq!(encode_packet("a#b"), b"$a}\x03b#43");
+        let mut pipe = Pipe { input: b"+$m0,2#fb$bad#00$g#67".iter().copied().collect(), output: Vec::new() };
+        assert_eq!(read_packet(&mut pipe, true).unwrap(), Some(Packet::Command("m0,2".to_string())));
+        assert_eq!(read_packet(&mut pipe, true).unwrap(), Some(Packet::Command("g".to_string())));
+        assert_eq!(pipe.output, b"+-+");
+        assert_eq!(read_packet(&mut pipe, true).unwrap(), None);
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let mut stub = stub(PROGRAM);
+        let mut pipe = Pipe::new(&[
+            "qSupported:swbreak+", "QStartNoAckMode", "?", "p8", "P2=3412", "m3006,2", "M3006,4:cdab0100", "m3006,4", "g",
+            "qXfer:features:read:target.xml:0,10", "qXfer:features:read:target.xml:10,1000", "vMustReplyEmpty", "D",
+        ]);
+        stub.serve(&mut pipe).unwrap();
+        let replies = pipe.replies();
+        assert_eq!(replies[0], "PacketSize=1000;qXfer:features:read+;swbreak+;QStartNoAckMode+");
+        assert_eq!(&replies[1..8], ["OK", "S05", "0030", "OK", "0000", "OK", "cdab0100"]);
+        assert_eq!(replies[8], format!("000000003412{}0030{}", "0000".repeat(5), word_hex(stub.machine.reg_file.psr())));
+        assert_eq!(replies[9], "m<?xml version=\"1");
+        assert_eq!(replies[10], format!("l{}", &TARGET_XML[0x10..]));
+        assert_eq!(&replies[11..], ["", "OK"]);
+        assert_eq!(stub.machine.reg_file.read_reg(2).unwrap(), 0x1234);
+        assert_eq!(stub.machine.mem.read(0x3007), 0x0001);
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints() {
+        let mut stub = stub(PROGRAM);
+        let mut pipe = Pipe::new(&["Z0,3004,2", "c", "p1", "z0,3004,2", "s", "Z2,3006,2", "c", "p1", "z2,3006,2", "c", "c"]);
+        stub.serve(&mut pipe).unwrap();
+        assert_eq!(pipe.replies(), [
+            "OK", "T05swbreak:;", "0100", "OK", "S05", "OK", "T05watch:3006;", "0200", "OK", "W00", "W00",
+        ]);
+        assert!(stub.machine.watchpoints.is_empty());
+    }
+
+    #[test]
+    fn interrupt_and_errors() {
+        let mut stub = stub(".ORIG x3000\nSPIN BRnzp SPIN\n.END");
+        stub.machine.reg_file.r_cond = 2;
+        let mut pipe = Pipe::new(&["QStartNoAckMode", "c", "^C", "p8", "M3000,2:00d0", "c"]);
+        stub.serve(&mut pipe).unwrap();
+        let error = encode_hex(b"error: illegal opcode 0xd000 at 0x3000\n");
+        assert_eq!(pipe.replies(), ["OK", "S02", "0030", "OK", &format!("O{}", error), "S04"]);
+    }
+}
@@ -0,0 +1,103 @@
+/*
+This is the LC-3 History Module, an undo log with one record per executed
+instruction so the machine can step backwards
+*/
+use crate::device::Devices;
+use crate::interrupt::InterruptController;
+use crate::register::RegFile;
+use crate::timing::StepCycles;
+
+use std::collections::VecDeque;
+
+/**
+ * the state an instruction may change, as it was before the instruction ran;
+ * memory is recorded as the (address, old value) of each store, in order,
+ * the interrupt controller only when the step changed it, and time as the
+ * cycles the step was charged
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndoRecord {
+    pub reg_file: RegFile,
+    pub devices: Devices,
+    pub interrupts: Option<InterruptController>,
+    pub writes: Vec<(u16, u16)>,
+    pub count: u64,
+    pub running: bool,
+    pub cycles: Option<StepCycles>,
+}
+
+// the most recent `limit` undo records, older ones are dropped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct History {
+    records: VecDeque<UndoRecord>,
+    limit: usize,
+}
+
+impl History {
+
+    pub fn new(limit: usize) -> History {
+        History { records: VecDeque::new(), limit }
+    }
+
+    pub fn push(&mut self, record: UndoRecord) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.records.len() == self.limit {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    pub fn pop(&mut self) -> Option<UndoRecord> {
+        self.records.pop_back()
+    }
+
+    // the record that would be undone next
+    pub fn last(&self) -> Option<&UndoRecord> {
+        self.records.back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+}
+
+#[cfg(test)]
+mod history_test {
+
+    use super::*;
+
+    fn record(count: u64) -> UndoRecord {
+        UndoRecord {
+            reg_file: RegFile::new(),
+            devices: Devices::new(),
+            interrupts: None,
+            writes: Vec::new(),
+            count,
+            running: true,
+            cycles: None,
+        }
+    }
+
+    #[test]
+    fn keeps_the_latest_records() {
+        let mut history = History::new(2);
+        for count in 0..3 {
+            history.push(record(count));
+        }
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.pop().unwrap().count, 2);
+        assert_eq!(history.last().unwrap().count, 1);
+        assert_eq!(history.pop().unwrap().count, 1);
+        assert!(history.pop().is_none());
+    }
+}
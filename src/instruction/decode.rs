@@ -0,0 +1,210 @@
+// typed view of an LC-3 instruction word, shared by the executor, the
+// assembler, the disassembler and the debugger
+use super::*;
+
+use std::fmt;
+
+// second operand of ADD / AND
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Reg(u16),
+    Imm5(i16),
+}
+
+// one decoded instruction, offsets are already sign-extended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Br { n: bool, z: bool, p: bool, offset9: i16 },
+    Add { dr: u16, sr1: u16, operand: Operand },
+    Ld { dr: u16, offset9: i16 },
+    St { sr: u16, offset9: i16 },
+    Jsr { offset11: i16 },
+    Jsrr { base_r: u16 },
+    And { dr: u16, sr1: u16, operand: Operand },
+    Ldr { dr: u16, base_r: u16, offset6: i16 },
+    Str { sr: u16, base_r: u16, offset6: i16 },
+    Rti,
+    Not { dr: u16, sr: u16 },
+    Ldi { dr: u16, offset9: i16 },
+    Sti { sr: u16, offset9: i16 },
+    Jmp { base_r: u16 },
+    Ret,
+    Lea { dr: u16, offset9: i16 },
+    Trap { vector: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    // opcode 1101 is reserved and has no meaning
+    Reserved { word: u16 },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Reserved { word } => write!(f, "reserved opcode in word 0x{:04x}", word),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+// sign-extend the low `bits` bits of a word into an i16
+fn signed(word: u16, bits: u8) -> i16 {
+    sign_extend(word & ((1 << bits) - 1), bits) as i16
+}
+
+// truncate a signed field back down to `bits` bits
+fn unsigned(value: i16, bits: u8) -> u16 {
+    (value as u16) & ((1 << bits) - 1)
+}
+
+fn dr(word: u16) -> u16 {
+    (word >> DR_SHIFT) & REG_MASK
+}
+
+fn sr1(word: u16) -> u16 {
+    (word >> SR1_SHIFT) & REG_MASK
+}
+
+fn alu_operand(word: u16) -> Operand {
+    if (word >> IMM_BIT) & 0x1 == 1 {
+        Operand::Imm5(signed(word, 5))
+    } else {
+        Operand::Reg(word & REG_MASK)
+    }
+}
+
+/**
+ * decode a raw instruction word, unused bits are ignored as on the real machine
+ */
+pub fn decode(word: u16) -> Result<Instruction, DecodeError> {
+    let instr = match opcode(word) {
+        OP_BR => Instruction::Br {
+            n: (word >> 11) & 1 == 1,
+            z: (word >> 10) & 1 == 1,
+            p: (word >> 9) & 1 == 1,
+            offset9: signed(word, 9),
+        },
+        OP_ADD => Instruction::Add { dr: dr(word), sr1: sr1(word), operand: alu_operand(word) },
+        OP_LD => Instruction::Ld { dr: dr(word), offset9: signed(word, 9) },
+        OP_ST => Instruction::St { sr: dr(word), offset9: signed(word, 9) },
+        OP_JSR => {
+            if (word >> 11) & 1 == 1 {
+                Instruction::Jsr { offset11: signed(word, 11) }
+            } else {
+                Instruction::Jsrr { base_r: sr1(word) }
+            }
+        },
+        OP_AND => Instruction::And { dr: dr(word), sr1: sr1(word), operand: alu_operand(word) },
+        OP_LDR => Instruction::Ldr { dr: dr(word), base_r: sr1(word), offset6: signed(word, 6) },
+        OP_STR => Instruction::Str { sr: dr(word), base_r: sr1(word), offset6: signed(word, 6) },
+        OP_RTI => Instruction::Rti,
+        OP_NOT => Instruction::Not { dr: dr(word), sr: sr1(word) },
+        OP_LDI => Instruction::Ldi { dr: dr(word), offset9: signed(word, 9) },
+        OP_STI => Instruction::Sti { sr: dr(word), offset9: signed(word, 9) },
+        OP_JMP => {
+            let base_r = sr1(word);
+            if base_r == 7 {
+                Instruction::Ret
+            } else {
+                Instruction::Jmp { base_r }
+            }
+        },
+        OP_RES => return Err(DecodeError::Reserved { word }),
+        OP_LEA => Instruction::Lea { dr: dr(word), offset9: signed(word, 9) },
+        OP_TRAP => Instruction::Trap { vector: (word & 0xff) as u8 },
+        _ => unreachable!(),
+    };
+    Ok(instr)
+}
+
+fn encode_alu(op: u16, dr: u16, sr1: u16, operand: Operand) -> u16 {
+    let head = (op << 12) | ((dr & REG_MASK) << DR_SHIFT) | ((sr1 & REG_MASK) << SR1_SHIFT);
+    match operand {
+        Operand::Reg(sr2) => head | (sr2 & REG_MASK),
+        Operand::Imm5(imm) => head | (1 << IMM_BIT) | unsigned(imm, 5),
+    }
+}
+
+/**
+ * encode an instruction back into its word, fields wider than their slot are
+ * truncated so callers (e.g. the assembler) must range check beforehand
+ */
+pub fn encode(instr: &Instruction) -> u16 {
+    let reg = |op: u16, r: u16, offset9: i16| (op << 12) | ((r & REG_MASK) << DR_SHIFT) | unsigned(offset9, 9);
+    match *instr {
+        Instruction::Br { n, z, p, offset9 } => {
+            ((n as u16) << 11) | ((z as u16) << 10) | ((p as u16) << 9) | unsigned(offset9, 9)
+        },
+        Instruction::Add { dr, sr1, operand } => encode_alu(OP_ADD, dr, sr1, operand),
+        Instruction::Ld { dr, offset9 } => reg(OP_LD, dr, offset9),
+        Instruction::St { sr, offset9 } => reg(OP_ST, sr, offset9),
+        Instruction::Jsr { offset11 } => (OP_JSR << 12) | (1 << 11) | unsigned(offset11, 11),
+        Instruction::Jsrr { base_r } => (OP_JSR << 12) | ((base_r & REG_MASK) << SR1_SHIFT),
+        Instruction::And { dr, sr1, operand } => encode_alu(OP_AND, dr, sr1, operand),
+        Instruction::Ldr { dr, base_r, offset6 } => {
+            (OP_LDR << 12) | ((dr & REG_MASK) << DR_SHIFT) | ((base_r & REG_MASK) << SR1_SHIFT) | unsigned(offset6, 6)
+        },
+        Instruction::Str { sr, base_r, offset6 } => {
+            (OP_STR << 12) | ((sr & REG_MASK) << DR_SHIFT) | ((base_r & REG_MASK) << SR1_SHIFT) | unsigned(offset6, 6)
+        },
+        Instruction::Rti => OP_RTI << 12,
+        Instruction::Not { dr, sr } => {
+            (OP_NOT << 12) | ((dr & REG_MASK) << DR_SHIFT) | ((sr & REG_MASK) << SR1_SHIFT) | 0x3f
+        },
+        Instruction::Ldi { dr, offset9 } => reg(OP_LDI, dr, offset9),
+        Instruction::Sti { sr, offset9 } => reg(OP_STI, sr, offset9),
+        Instruction::Jmp { base_r } => (OP_JMP << 12) | ((base_r & REG_MASK) << SR1_SHIFT),
+        Instruction::Ret => (OP_JMP << 12) | (7 << SR1_SHIFT),
+        Instruction::Lea { dr, offset9 } => reg(OP_LEA, dr, offset9),
+        Instruction::Trap { vector } => (OP_TRAP << 12) | vector as u16,
+    }
+}
+
+#[cfg(test)]
+mod decode_test {
+
+    use super::*;
+
+    #[test]
+    fn decode_add_register() {
+        let instr = decode(0b0001011001000010).unwrap(); // ADD R3, R1, R2
+        assert_eq!(instr, Instruction::Add { dr: 3, sr1: 1, operand: Operand::Reg(2) });
+    }
+
+    #[test]
+    fn decode_add_negative_immediate() {
+        let instr = decode(0b0001000000111111).unwrap(); // ADD R0, R0, #-1
+        assert_eq!(instr, Instruction::Add { dr: 0, sr1: 0, operand: Operand::Imm5(-1) });
+    }
+
+    #[test]
+    fn decode_branch_flags() {
+        let instr = decode(0b0000101111111110).unwrap(); // BRnp #-2
+        assert_eq!(instr, Instruction::Br { n: true, z: false, p: true, offset9: -2 });
+    }
+
+    #[test]
+    fn decode_jmp_r7_is_ret() {
+        assert_eq!(decode(0xC1C0).unwrap(), Instruction::Ret);
+        assert_eq!(decode(0xC080).unwrap(), Instruction::Jmp { base_r: 2 });
+    }
+
+    #[test]
+    fn decode_reserved_opcode() {
+        assert_eq!(decode(0xD123), Err(DecodeError::Reserved { word: 0xD123 }));
+    }
+
+    #[test]
+    fn encode_is_inverse_of_decode() {
+        let words: [u16; 17] = [
+            0x0E05, 0x1262, 0x1042, 0x2401, 0x3BFF, 0x4FFF, 0x4080, 0x5A3F,
+            0x6C7E, 0x7241, 0x8000, 0x967F, 0xA201, 0xB1F0, 0xC040, 0xE9F6, 0xF025,
+        ];
+        for word in words.iter() {
+            let instr = decode(*word).unwrap();
+            assert_eq!(encode(&instr), *word, "round trip of 0x{:04x}", word);
+        }
+    }
+}
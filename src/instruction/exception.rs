@@ -0,0 +1,88 @@
+// specific file to handle exceptions and the supervisor stack
+use crate::error::VmError;
+use crate::memory::Bus;
+use crate::register::RegFile;
+
+// base of the interrupt vector table x0100-x01FF
+pub const INTERRUPT_VECTOR_TABLE: u16 = 0x0100;
+
+// exception vectors, offsets into the interrupt vector table
+pub const PRIVILEGE_VIOLATION: u16 = 0x00;
+pub const ILLEGAL_OPCODE: u16      = 0x01;
+
+// push a word onto the stack pointed to by R6
+pub fn push<B: Bus>(value: u16, reg_file: &mut RegFile, mem: &mut B) -> Result<(), VmError> {
+    let sp = reg_file.read_reg(6)?.wrapping_sub(1);
+    reg_file.update_reg(6, sp)?;
+    mem.write(sp, value);
+    Ok(())
+}
+
+// pop a word off the stack pointed to by R6
+pub fn pop<B: Bus>(reg_file: &mut RegFile, mem: &mut B) -> Result<u16, VmError> {
+    let sp = reg_file.read_reg(6)?;
+    reg_file.update_reg(6, sp.wrapping_add(1))?;
+    Ok(mem.read(sp))
+}
+
+// address of the service routine for a vector, x0000 when none is installed
+pub fn service_routine<B: Bus>(vector: u16, mem: &mut B) -> u16 {
+    mem.read(INTERRUPT_VECTOR_TABLE.wrapping_add(vector & 0xff))
+}
+
+// switch to the supervisor stack and push PSR then PC, for RTI to return through
+pub fn save_context<B: Bus>(reg_file: &mut RegFile, mem: &mut B) -> Result<(), VmError> {
+    let psr = reg_file.psr();
+    reg_file.switch_to_supervisor();
+    push(psr, reg_file, mem)?;
+    push(reg_file.r_pc, reg_file, mem)
+}
+
+/**
+ * the entry sequence shared by exceptions and interrupts: switch to the
+ * supervisor stack, push PSR and PC, then jump through the vector table;
+ * interrupts also raise the priority level to that of the device
+ */
+pub fn enter_service_routine<B: Bus>(vector: u16, priority: Option<u16>, reg_file: &mut RegFile, mem: &mut B) -> Result<(), VmError> {
+    save_context(reg_file, mem)?;
+    if let Some(priority) = priority {
+        reg_file.priority = priority;
+    }
+    reg_file.r_pc = service_routine(vector, mem);
+    Ok(())
+}
+
+#[cfg(test)]
+mod exception_test {
+
+    use super::*;
+    use crate::memory::Memory;
+    use crate::register::{Privilege, PC_START, SSP_START};
+
+    #[test]
+    fn enter_from_user_mode() {
+        let mut reg_file = RegFile::new();
+        let mut mem = Memory::new();
+        mem.write(INTERRUPT_VECTOR_TABLE + ILLEGAL_OPCODE, 0x1000);
+        reg_file.update_reg(6, 0xFD00).unwrap();
+        reg_file.r_cond = 2;
+        reg_file.r_pc = PC_START + 1;
+        enter_service_routine(ILLEGAL_OPCODE, None, &mut reg_file, &mut mem).unwrap();
+        assert_eq!(reg_file.privilege, Privilege::Supervisor);
+        assert_eq!(reg_file.r_pc, 0x1000);
+        assert_eq!(reg_file.read_reg(6).unwrap(), SSP_START - 2);
+        assert_eq!(reg_file.saved_usp, 0xFD00);
+        assert_eq!(mem.read(SSP_START - 1), 0x8002); // user mode, priority 0, Z
+        assert_eq!(mem.read(SSP_START - 2), PC_START + 1);
+    }
+
+    #[test]
+    fn interrupt_raises_priority() {
+        let mut reg_file = RegFile::new();
+        let mut mem = Memory::new();
+        mem.write(0x0180, 0x1200);
+        enter_service_routine(0x80, Some(4), &mut reg_file, &mut mem).unwrap();
+        assert_eq!(reg_file.priority, 4);
+        assert_eq!(reg_file.r_pc, 0x1200);
+    }
+}
@@ -1,168 +1,168 @@
+use crate::error::VmError;
 use crate::register::RegFile;
 use crate::register::PC_REG;
-use crate::memory::Memory;
-use super::*;
+use crate::memory::Bus;
+use super::decode::Operand;
+use super::exception::pop;
+
+// value of the second ADD / AND operand
+fn operand_value(operand: Operand, reg_file: &RegFile) -> Result<u16, VmError> {
+    match operand {
+        Operand::Reg(sr2) => reg_file.read_reg(sr2),
+        Operand::Imm5(imm5) => Ok(imm5 as u16),
+    }
+}
 
 /**
  * parsing the instruction for "ADD" both R1 + R2 and R1 + IMMEDIATE
  */
-pub fn instr_add(instr: u16, reg_file: &mut RegFile) {
-    let dr = (instr >> DR_SHIFT) & REG_MASK;
-    let sr1 = (instr >> SR1_SHIFT) & REG_MASK;
-    let imm_flag = (instr >> IMM_BIT) & 0x1;
-    if imm_flag == 1 {
-        let imm5 = sign_extend(instr & 0x1F, IMM_BIT as u8);
-        let val = imm5 as u32 + reg_file.read_reg(sr1) as u32;
-        reg_file.update_reg(dr, val as u16);
-    } else {
-        let sr2 = instr & REG_MASK;
-        let val = reg_file.read_reg(sr1) as u32 + reg_file.read_reg(sr2) as u32;
-        reg_file.update_reg(dr, val as u16);
-    }
+pub fn instr_add(dr: u16, sr1: u16, operand: Operand, reg_file: &mut RegFile) -> Result<(), VmError> {
+    let val = reg_file.read_reg(sr1)?.wrapping_add(operand_value(operand, reg_file)?);
+    reg_file.update_reg(dr, val)?;
     // update the conditional flag for destination register
-    reg_file.update_cond_flag(dr);
+    reg_file.update_cond_flag(dr)?;
+    Ok(())
 }
 
 
 /**
  * for AND both sr1 and sr2 and sr1 and immediate
  */
-pub fn instr_and(instr: u16, reg_file: &mut RegFile) {
-    let dr = (instr >> DR_SHIFT) & REG_MASK;
-    let sr1 = (instr >> SR1_SHIFT) & REG_MASK;
-    let imm_flag = (instr >> IMM_BIT) & 0x1;
-    if imm_flag == 1 {
-        let imm5 = sign_extend(instr & 0x1F, IMM_BIT as u8);
-        let val = reg_file.read_reg(sr1);
-        reg_file.update_reg(dr, val & imm5);
-    } else {
-        let sr2 = instr & REG_MASK;
-        let val = reg_file.read_reg(sr1) & reg_file.read_reg(sr2);
-        reg_file.update_reg(dr, val); 
-    }
-    reg_file.update_cond_flag(dr);
+pub fn instr_and(dr: u16, sr1: u16, operand: Operand, reg_file: &mut RegFile) -> Result<(), VmError> {
+    let val = reg_file.read_reg(sr1)? & operand_value(operand, reg_file)?;
+    reg_file.update_reg(dr, val)?;
+    reg_file.update_cond_flag(dr)?;
+    Ok(())
 }
 
 
 /**
  * for Branch instructions
  */
-pub fn instr_brx(instr: u16, reg_file: &mut RegFile) {
-    let pc_offset = sign_extend((instr) & 0x1ff, 9);
-    let cond_flag = (instr >> 9) & 0x7;
+pub fn instr_brx(n: bool, z: bool, p: bool, offset9: i16, reg_file: &mut RegFile) -> Result<(), VmError> {
+    let cond_flag = ((n as u16) << 2) | ((z as u16) << 1) | p as u16;
     if cond_flag & reg_file.r_cond != 0 {
-        let next_pc = reg_file.r_pc as u32 + pc_offset as u32; // PC = 
-        reg_file.r_pc = next_pc as u16;
+        reg_file.r_pc = reg_file.r_pc.wrapping_add_signed(offset9);
     }
+    Ok(())
 }
 
 
 /**
  * Unconditional jump to base register address
  */
-pub fn instr_jmp(instr: u16, reg_file: &mut RegFile) {
-    let base_reg = (instr >> 6) & 0x7;
-    reg_file.r_pc = reg_file.read_reg(base_reg);
+pub fn instr_jmp(base_r: u16, reg_file: &mut RegFile) -> Result<(), VmError> {
+    reg_file.r_pc = reg_file.read_reg(base_r)?;
+    Ok(())
 }
 
 
 /**
  * Unconditional jump to label or subroutine
  */
-pub fn instr_jsr(instr: u16, reg_file: &mut RegFile) {
-    let base_reg = (instr >> 6) & 0x7;
-    let pc_offset = sign_extend(instr & 0x7ff, 11);
-    let flag = (instr >> 11) & 1;
+pub fn instr_jsr(offset11: i16, reg_file: &mut RegFile) -> Result<(), VmError> {
     reg_file.r_r7 = reg_file.r_pc; // update R7 to PC + 1;
-    if flag != 0 {
-        let val = reg_file.r_pc as u32 + pc_offset as u32;
-        reg_file.r_pc = val as u16;
-    } else {
-        // JSRR case
-        reg_file.r_pc = reg_file.read_reg(base_reg);
-    }
+    reg_file.r_pc = reg_file.r_pc.wrapping_add_signed(offset11);
+    Ok(())
+}
+
+
+/**
+ * jump to the subroutine at a base register address
+ */
+pub fn instr_jsrr(base_r: u16, reg_file: &mut RegFile) -> Result<(), VmError> {
+    // BaseR is read before R7 changes so JSRR R7 works
+    let target = reg_file.read_reg(base_r)?;
+    reg_file.r_r7 = reg_file.r_pc;
+    reg_file.r_pc = target;
+    Ok(())
 }
 
 
-pub fn instr_ld(instr: u16, reg_file: &mut RegFile, mem: &mut Memory) {
-    let dr = (instr >> DR_SHIFT) & REG_MASK;
-    let pc_offset = sign_extend(instr & 0x1ff, 9);
-    let address = pc_offset as u32 + reg_file.r_pc as u32;
-    reg_file.update_reg(dr, mem.read(address as u16));
-    reg_file.update_cond_flag(dr);
+pub fn instr_ld<B: Bus>(dr: u16, offset9: i16, reg_file: &mut RegFile, mem: &mut B) -> Result<(), VmError> {
+    let address = reg_file.r_pc.wrapping_add_signed(offset9);
+    reg_file.update_reg(dr, mem.read(address))?;
+    reg_file.update_cond_flag(dr)?;
+    Ok(())
 }
 
 
 // indirect load (load twice)
-pub fn instr_ldi(instr: u16, reg_file: &mut RegFile, mem: &mut Memory) {
-    let dr = (instr >> DR_SHIFT) & REG_MASK;
-    let pc_offset = sign_extend(instr & 0x1ff, 9);
-    let address = mem.read(reg_file.r_pc + pc_offset);
-    reg_file.update_reg(dr, mem.read(address));
-    reg_file.update_cond_flag(dr);
+pub fn instr_ldi<B: Bus>(dr: u16, offset9: i16, reg_file: &mut RegFile, mem: &mut B) -> Result<(), VmError> {
+    let address = mem.read(reg_file.r_pc.wrapping_add_signed(offset9));
+    reg_file.update_reg(dr, mem.read(address))?;
+    reg_file.update_cond_flag(dr)?;
+    Ok(())
 }
 
 
-pub fn instr_ldr(instr: u16, reg_file: &mut RegFile, mem: &mut Memory) {
-    let dr = (instr >> DR_SHIFT) & REG_MASK;
-    let sr1 = (instr >> SR1_SHIFT) & REG_MASK;
-    let offset = sign_extend(instr & 0x3f, 6);
-    let address = reg_file.read_reg(sr1) as u32 + offset as u32;
-    reg_file.update_reg(dr, mem.read(address as u16));
-    reg_file.update_cond_flag(dr);
+pub fn instr_ldr<B: Bus>(dr: u16, base_r: u16, offset6: i16, reg_file: &mut RegFile, mem: &mut B) -> Result<(), VmError> {
+    let address = reg_file.read_reg(base_r)?.wrapping_add_signed(offset6);
+    reg_file.update_reg(dr, mem.read(address))?;
+    reg_file.update_cond_flag(dr)?;
+    Ok(())
 }
 
 
-pub fn instr_lea(instr: u16, reg_file: &mut RegFile) {
-    let dr = (instr >> DR_SHIFT) & REG_MASK;
-    let pc_offset = sign_extend(instr & 0x1ff, 9);
-    let val: u32 = reg_file.r_pc as u32 + pc_offset as u32;
-    reg_file.update_reg(dr, val as u16);
-    reg_file.update_cond_flag(dr);
+pub fn instr_lea(dr: u16, offset9: i16, reg_file: &mut RegFile) -> Result<(), VmError> {
+    let val = reg_file.r_pc.wrapping_add_signed(offset9);
+    reg_file.update_reg(dr, val)?;
+    reg_file.update_cond_flag(dr)?;
+    Ok(())
 }
 
 
-pub fn instr_not(instr: u16, reg_file: &mut RegFile) {
-    let dr = (instr >> DR_SHIFT) & REG_MASK;
-    let sr1 = (instr >> SR1_SHIFT) & REG_MASK;
-    let val = reg_file.read_reg(sr1);
-    reg_file.update_reg(dr, !val);
-    reg_file.update_cond_flag(dr);
+pub fn instr_not(dr: u16, sr: u16, reg_file: &mut RegFile) -> Result<(), VmError> {
+    let val = reg_file.read_reg(sr)?;
+    reg_file.update_reg(dr, !val)?;
+    reg_file.update_cond_flag(dr)?;
+    Ok(())
 }
 
 // return from R7 to PC
-pub fn instr_ret(reg_file: &mut RegFile) {
-    let prev_pc = reg_file.read_reg(7);
-    reg_file.update_reg(PC_REG, prev_pc);
+pub fn instr_ret(reg_file: &mut RegFile) -> Result<(), VmError> {
+    let prev_pc = reg_file.read_reg(7)?;
+    reg_file.update_reg(PC_REG, prev_pc)?;
+    Ok(())
+}
+
+
+/**
+ * return from an interrupt or exception: pop PC then PSR off the supervisor
+ * stack, going back to the user stack if the saved PSR was in user mode; the
+ * caller checks the privilege mode first
+ */
+pub fn instr_rti<B: Bus>(reg_file: &mut RegFile, mem: &mut B) -> Result<(), VmError> {
+    let pc = pop(reg_file, mem)?;
+    let psr = pop(reg_file, mem)?;
+    reg_file.set_psr(psr);
+    reg_file.r_pc = pc;
+    Ok(())
 }
 
 
-pub fn instr_st(instr: u16, reg_file: &mut RegFile, mem: &mut Memory) {
-    let dr = (instr >> DR_SHIFT) & REG_MASK;
-    let pc_offset = sign_extend(instr & 0x1ff, 9);
-    let address = reg_file.r_pc as u32 + pc_offset as u32;
-    mem .write(address as u16, reg_file.read_reg(dr));
+pub fn instr_st<B: Bus>(sr: u16, offset9: i16, reg_file: &mut RegFile, mem: &mut B) -> Result<(), VmError> {
+    let address = reg_file.r_pc.wrapping_add_signed(offset9);
+    mem.write(address, reg_file.read_reg(sr)?);
+    Ok(())
 }
 
 
-pub fn instr_sti(instr: u16, reg_file: &mut RegFile, mem: &mut Memory) {
-    let dr = (instr >> DR_SHIFT) & REG_MASK;
-    let pc_offset = sign_extend(instr & 0x1ff, 9);
-    let addr1 = reg_file.r_pc as u32 + pc_offset as u32;
-    let addr2 = mem.read(addr1 as u16);
-    mem.write(addr2, reg_file.read_reg(dr));
+pub fn instr_sti<B: Bus>(sr: u16, offset9: i16, reg_file: &mut RegFile, mem: &mut B) -> Result<(), VmError> {
+    let address = mem.read(reg_file.r_pc.wrapping_add_signed(offset9));
+    mem.write(address, reg_file.read_reg(sr)?);
+    Ok(())
 }
 
 
-pub fn instr_str(instr: u16, reg_file: &mut RegFile, mem: &mut Memory) {
-    let dr = (instr >> DR_SHIFT) & REG_MASK;
-    let sr1 = (instr >> SR1_SHIFT) & REG_MASK;
-    let offset = sign_extend(instr & 0x3f, 6);
-    let address = reg_file.read_reg(sr1) as u32 + offset as u32;
-    mem.write(address as u16, reg_file.read_reg(dr));
+pub fn instr_str<B: Bus>(sr: u16, base_r: u16, offset6: i16, reg_file: &mut RegFile, mem: &mut B) -> Result<(), VmError> {
+    let address = reg_file.read_reg(base_r)?.wrapping_add_signed(offset6);
+    mem.write(address, reg_file.read_reg(sr)?);
+    Ok(())
 }
 
 #[cfg(test)]
+#[allow(clippy::unnecessary_cast)]
 mod general_instruction_test {
     use super::*;
     use crate::memory::*;
@@ -172,11 +172,10 @@ mod general_instruction_test {
     #[test]
     fn test_instr_add() {
         let mut reg_file = RegFile::new();
-        reg_file.update_reg(1, 3);
-        reg_file.update_reg(2, 4);
-        let binary_instr: u16 = 0b0001011001000010; // R3 <- R1 + R2
-        instr_add(binary_instr, &mut reg_file);
-        let value = reg_file.read_reg(3);
+        reg_file.update_reg(1, 3).unwrap();
+        reg_file.update_reg(2, 4).unwrap();
+        instr_add(3, 1, Operand::Reg(2), &mut reg_file).unwrap(); // R3 <- R1 + R2
+        let value = reg_file.read_reg(3).unwrap();
         assert_eq!(value, 7 as u16);
     }
 
@@ -184,10 +183,9 @@ mod general_instruction_test {
     #[test]
     fn test_instr_add2() {
         let mut reg_file = RegFile::new();
-        reg_file.update_reg(4, 13);
-        let binary_instr: u16 = 0b0001010100100011; // R2 <- R4 + 3
-        instr_add(binary_instr, &mut reg_file);
-        let value = reg_file.read_reg(2);
+        reg_file.update_reg(4, 13).unwrap();
+        instr_add(2, 4, Operand::Imm5(3), &mut reg_file).unwrap(); // R2 <- R4 + 3
+        let value = reg_file.read_reg(2).unwrap();
         assert_eq!(value, 16 as u16);
     }
 
@@ -195,11 +193,10 @@ mod general_instruction_test {
     #[test]
     fn test_instr_and() {
         let mut reg_file = RegFile::new();
-        reg_file.update_reg(1, 0b0011 as u16);
-        reg_file.update_reg(2, 0b1110 as u16);
-        let binary_instr: u16 = 0b0101011001000010;
-        instr_and(binary_instr, &mut reg_file); // R3 <- R1 & R2
-        let value = reg_file.read_reg(3);
+        reg_file.update_reg(1, 0b0011 as u16).unwrap();
+        reg_file.update_reg(2, 0b1110 as u16).unwrap();
+        instr_and(3, 1, Operand::Reg(2), &mut reg_file).unwrap(); // R3 <- R1 & R2
+        let value = reg_file.read_reg(3).unwrap();
         assert_eq!(value, 0b0010 as u16);
     }
 
@@ -207,10 +204,9 @@ mod general_instruction_test {
     #[test]
     fn test_instr_and2() {
         let mut reg_file = RegFile::new();
-        reg_file.update_reg(1, 0b1110 as u16);
-        let binary_instr: u16 = 0b0101010001100110; // R2 <- R1 & 00110
-        instr_and(binary_instr, &mut reg_file);
-        let value = reg_file.read_reg(2);
+        reg_file.update_reg(1, 0b1110 as u16).unwrap();
+        instr_and(2, 1, Operand::Imm5(0b00110), &mut reg_file).unwrap(); // R2 <- R1 & 00110
+        let value = reg_file.read_reg(2).unwrap();
         assert_eq!(value, 0b0110 as u16);
     }
 
@@ -218,12 +214,10 @@ mod general_instruction_test {
     #[test]
     fn test_instr_brz() {
         let mut reg_file = RegFile::new();
-        let and: u16 = 0b0101001001100000;
-        let brz: u16 = 0b0000010010101011;
-        instr_and(and, &mut reg_file);
-        let pc_val = reg_file.read_reg(PC_REG);
-        instr_brx(brz, &mut reg_file);
-        let value = reg_file.read_reg(PC_REG);
+        instr_and(1, 1, Operand::Imm5(0), &mut reg_file).unwrap();
+        let pc_val = reg_file.read_reg(PC_REG).unwrap();
+        instr_brx(false, true, false, 0b010101011, &mut reg_file).unwrap();
+        let value = reg_file.read_reg(PC_REG).unwrap();
         assert_eq!(value - pc_val, 0b010101011 as u16);
     }
 
@@ -231,12 +225,10 @@ mod general_instruction_test {
     #[test]
     fn test_instr_brn() {
         let mut reg_file = RegFile::new();
-        let add: u16 = 0b0101001001111100;
-        let brn: u16 = 0b0000100001110110;
-        instr_add(add, &mut reg_file);
-        let pc_val = reg_file.read_reg(PC_REG);
-        instr_brx(brn , &mut reg_file);
-        let value = reg_file.read_reg(PC_REG);
+        instr_add(1, 1, Operand::Imm5(-4), &mut reg_file).unwrap();
+        let pc_val = reg_file.read_reg(PC_REG).unwrap();
+        instr_brx(true, false, false, 0b001110110, &mut reg_file).unwrap();
+        let value = reg_file.read_reg(PC_REG).unwrap();
         assert_eq!(value - pc_val, 0b001110110 as u16);
     }
 
@@ -244,12 +236,10 @@ mod general_instruction_test {
     #[test]
     fn test_instr_brp() {
         let mut reg_file = RegFile::new();
-        let add: u16 = 0b0101001001100001;
-        let brp: u16 = 0b0000001000111000; // PC <- PC + 000111000
-        instr_add(add, &mut reg_file);
-        let pc_val = reg_file.read_reg(PC_REG);
-        instr_brx(brp, &mut reg_file);
-        let value = reg_file.read_reg(PC_REG);
+        instr_add(1, 1, Operand::Imm5(1), &mut reg_file).unwrap();
+        let pc_val = reg_file.read_reg(PC_REG).unwrap();
+        instr_brx(false, false, true, 0b000111000, &mut reg_file).unwrap(); // PC <- PC + 000111000
+        let value = reg_file.read_reg(PC_REG).unwrap();
         assert_eq!(value - pc_val, 0b000111000);
     }
 
@@ -257,10 +247,9 @@ mod general_instruction_test {
     #[test]
     fn test_instr_jmp() {
         let mut reg_file = RegFile::new();
-        reg_file.update_reg(1, 0b0111 as u16);
-        let jmp: u16 = 0b1100000001000000;
-        instr_jmp(jmp, &mut reg_file);
-        let value = reg_file.read_reg(PC_REG);
+        reg_file.update_reg(1, 0b0111 as u16).unwrap();
+        instr_jmp(1, &mut reg_file).unwrap();
+        let value = reg_file.read_reg(PC_REG).unwrap();
         assert_eq!(value, 0b0111 as u16);
     }
 
@@ -268,35 +257,42 @@ mod general_instruction_test {
     #[test]
     fn test_instr_jsr() {
         let mut reg_file = RegFile::new();
-        let jsr: u16 = 0b0100100011011010;
-        let pc_val = reg_file.read_reg(PC_REG);
-        instr_jsr(jsr, &mut reg_file);
-        let r7 = reg_file.read_reg(7);
-        let value = reg_file.read_reg(PC_REG);
+        let pc_val = reg_file.read_reg(PC_REG).unwrap();
+        instr_jsr(0b00011011010, &mut reg_file).unwrap();
+        let r7 = reg_file.read_reg(7).unwrap();
+        let value = reg_file.read_reg(PC_REG).unwrap();
         assert!((r7 == pc_val) && (value - pc_val == 0b00011011010 as u16));
     }
 
     #[test]
     fn test_instr_jsrr() {
         let mut reg_file = RegFile::new();
-        reg_file.update_reg(2, 0b011101 as u16);
-        let jsrr: u16 = 0b0100000010000000;
-        let pc_val = reg_file.read_reg(PC_REG);
-        instr_jsr(jsrr, &mut reg_file);
-        let r7 = reg_file.read_reg(7);
-        let value = reg_file.read_reg(PC_REG);
+        reg_file.update_reg(2, 0b011101 as u16).unwrap();
+        let pc_val = reg_file.read_reg(PC_REG).unwrap();
+        instr_jsrr(2, &mut reg_file).unwrap();
+        let r7 = reg_file.read_reg(7).unwrap();
+        let value = reg_file.read_reg(PC_REG).unwrap();
         assert!((r7 == pc_val) && (value == 0b011101 as u16));
     }
 
+    // JSRR R7 jumps to the old R7, not to its own return address
+    #[test]
+    fn test_instr_jsrr_r7() {
+        let mut reg_file = RegFile::new();
+        reg_file.update_reg(7, 0x4000).unwrap();
+        instr_jsrr(7, &mut reg_file).unwrap();
+        assert_eq!(reg_file.read_reg(PC_REG).unwrap(), 0x4000);
+        assert_eq!(reg_file.read_reg(7).unwrap(), 0x3000);
+    }
+
     #[test]
     fn test_instr_ld() {
         let mut reg_file = RegFile::new();
         let mut mem = Memory::new();
         let location = (0x3000 + 0x25) as u16;
         mem.write(location, 0x333 as u16);
-        let ld = (0b0010001000000000 | 0x25) as u16;
-        instr_ld(ld, &mut reg_file, &mut mem);
-        let value = reg_file.read_reg(1);
+        instr_ld(1, 0x25, &mut reg_file, &mut mem).unwrap();
+        let value = reg_file.read_reg(1).unwrap();
         assert_eq!(value, 0x333 as u16);
     }
 
@@ -308,9 +304,8 @@ mod general_instruction_test {
         let addr = (0x3000 + 0x35) as u16;
         mem.write(addr, 0x3333 as u16);
         mem.write(0x3333, 0x1528);
-        let ldi = (0b1010001000000000 | 0x35) as u16;
-        instr_ldi(ldi, &mut reg_file, &mut mem);
-        let value = reg_file.read_reg(1);
+        instr_ldi(1, 0x35, &mut reg_file, &mut mem).unwrap();
+        let value = reg_file.read_reg(1).unwrap();
         assert_eq!(value, 0x1528 as u16);
     }
 
@@ -321,10 +316,9 @@ mod general_instruction_test {
         let mut mem = Memory::new();
         let addr = (0x2099 + 0x00F) as u16;
         mem.write(addr, 0x5555);
-        reg_file.update_reg(5, 0x2099);
-        let ldr = (0b0110010101000000 | 0x00F) as u16;
-        instr_ldr(ldr, &mut reg_file, &mut mem);
-        let value = reg_file.read_reg(2);
+        reg_file.update_reg(5, 0x2099).unwrap();
+        instr_ldr(2, 5, 0x00F, &mut reg_file, &mut mem).unwrap();
+        let value = reg_file.read_reg(2).unwrap();
         // println!("{}", value);
         assert_eq!(value, 0x5555);
     }
@@ -335,19 +329,17 @@ mod general_instruction_test {
         let mut reg_file = RegFile::new();
         // let mut mem = Memory::new();
         let addr = (0x3000 + 0x003B) as u16;
-        let lea = (0b1110110000000000 | 0x003B) as u16;
-        instr_lea(lea, &mut reg_file);
-        let value = reg_file.read_reg(6);
+        instr_lea(6, 0x003B, &mut reg_file).unwrap();
+        let value = reg_file.read_reg(6).unwrap();
         assert_eq!(value, addr);
     }
 
     #[test]
     fn test_instr_not() {
         let mut reg_file = RegFile::new();
-        let not = 0b1001001001111111 as u16;
-        reg_file.update_reg(1, 0xFFFF);
-        instr_not(not, &mut reg_file);
-        let value = reg_file.read_reg(1);
+        reg_file.update_reg(1, 0xFFFF).unwrap();
+        instr_not(1, 1, &mut reg_file).unwrap();
+        let value = reg_file.read_reg(1).unwrap();
         assert_eq!(value, 0x0000 as u16);
     }
 
@@ -355,21 +347,38 @@ mod general_instruction_test {
     #[test]
     fn test_instr_ret() {
         let mut reg_file = RegFile::new();
-        reg_file.update_reg(PC_REG, 0x3199);
-        reg_file.update_reg(7, 0x304A);
-        instr_ret(&mut reg_file);
-        let value = reg_file.read_reg(PC_REG);
+        reg_file.update_reg(PC_REG, 0x3199).unwrap();
+        reg_file.update_reg(7, 0x304A).unwrap();
+        instr_ret(&mut reg_file).unwrap();
+        let value = reg_file.read_reg(PC_REG).unwrap();
         assert_eq!(value, 0x304A);
     }
 
+    // test rti instruction
+    #[test]
+    fn test_instr_rti() {
+        let mut reg_file = RegFile::new();
+        let mut mem = Memory::new();
+        reg_file.update_reg(6, 0xFD00).unwrap();
+        reg_file.switch_to_supervisor(); // R6 = 0x3000
+        reg_file.update_reg(6, 0x2FFE).unwrap();
+        mem.write(0x2FFE, 0x3042); // PC
+        mem.write(0x2FFF, 0x8001); // PSR: user mode, P
+        instr_rti(&mut reg_file, &mut mem).unwrap();
+        assert_eq!(reg_file.r_pc, 0x3042);
+        assert_eq!(reg_file.privilege, Privilege::User);
+        assert_eq!(reg_file.r_cond, 1);
+        assert_eq!(reg_file.read_reg(6).unwrap(), 0xFD00);
+        assert_eq!(reg_file.saved_ssp, 0x3000);
+    }
+
     // test st instruction
     #[test]
     fn test_instr_st() {
         let mut reg_file = RegFile::new();
         let mut mem = Memory::new();
-        reg_file.update_reg(1, 0x10 as u16);
-        let st: u16 = 0b0011001000000000 | 0x0024;
-        instr_st(st, &mut reg_file, &mut mem);
+        reg_file.update_reg(1, 0x10 as u16).unwrap();
+        instr_st(1, 0x0024, &mut reg_file, &mut mem).unwrap();
         let value = mem.read(0x3000 + 0x0024);
         assert_eq!(value, 0x10 as u16);
     }
@@ -379,10 +388,9 @@ mod general_instruction_test {
     fn test_instr_sti() {
         let mut reg_file = RegFile::new();
         let mut mem = Memory::new();
-        reg_file.update_reg(5, 0x004D);
-        let sti: u16 = 0b1011101000000000 | 0x0025;
+        reg_file.update_reg(5, 0x004D).unwrap();
         mem.write(0x3000 + 0x0025 as u16, 0x324E as u16);
-        instr_sti(sti, &mut reg_file, &mut mem);
+        instr_sti(5, 0x0025, &mut reg_file, &mut mem).unwrap();
         let value = mem.read(0x324E as u16);
         // println!("{}", value);
         assert_eq!(value, 0x004D as u16);
@@ -393,10 +401,9 @@ mod general_instruction_test {
     fn test_instr_str() {
         let mut reg_file = RegFile::new();
         let mut mem = Memory::new();
-        reg_file.update_reg(1, 0x2020); // SR
-        reg_file.update_reg(2, 0x2055); // BaseR
-        let str: u16 = 0b0111001010000000 | 0x0013;
-        instr_str(str, &mut reg_file, &mut mem);
+        reg_file.update_reg(1, 0x2020).unwrap(); // SR
+        reg_file.update_reg(2, 0x2055).unwrap(); // BaseR
+        instr_str(1, 2, 0x0013, &mut reg_file, &mut mem).unwrap();
         let value = mem.read(0x2055 + 0x0013);
         assert_eq!(value, 0x2020);
     }
@@ -1,5 +1,25 @@
 pub mod instr;
 pub mod trap;
+pub mod exception;
+pub mod decode;
+
+// opcode constants (bits [15:12] of an instruction)
+pub const OP_BR: u16   = 0x0;
+pub const OP_ADD: u16  = 0x1;
+pub const OP_LD: u16   = 0x2;
+pub const OP_ST: u16   = 0x3;
+pub const OP_JSR: u16  = 0x4;
+pub const OP_AND: u16  = 0x5;
+pub const OP_LDR: u16  = 0x6;
+pub const OP_STR: u16  = 0x7;
+pub const OP_RTI: u16  = 0x8;
+pub const OP_NOT: u16  = 0x9;
+pub const OP_LDI: u16  = 0xA;
+pub const OP_STI: u16  = 0xB;
+pub const OP_JMP: u16  = 0xC;
+pub const OP_RES: u16  = 0xD;
+pub const OP_LEA: u16  = 0xE;
+pub const OP_TRAP: u16 = 0xF;
 
 // constant for instruction parsing
 const DR_SHIFT: usize = 9;
@@ -7,6 +27,11 @@ const SR1_SHIFT: usize = 6;
 const REG_MASK: u16 = 0x7;
 const IMM_BIT: usize = 5;
 
+// extract the opcode from an instruction
+pub fn opcode(instr: u16) -> u16 {
+    instr >> 12
+}
+
 pub fn sign_extend(mut x: u16, bit_count: u8) -> u16 {
     if (x >> (bit_count - 1)) & 1 != 0 {
         x |= 0xFFFF << bit_count;
@@ -28,7 +53,7 @@ mod sign_extension_test {
 
     #[test]
     fn negative_number() {
-        let x = 0b1100 as u16;
+        let x = 0b1100_u16;
         let y = sign_extend(x, 4);
         assert_eq!(y, 0xFFFC);
     }
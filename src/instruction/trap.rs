@@ -1,11 +1,10 @@
 // specific file to handle trap instructions
+use crate::console::Console;
+use crate::error::VmError;
+use crate::machine::StepOutcome;
 use crate::register::RegFile;
-use crate::memory::Memory;
-
-use std::io::Read;
-use std::io::Write;
-use std::process;
-
+use crate::memory::Bus;
+use super::exception::save_context;
 
 // TRAP code constants
 pub const GETC: u16  = 0x20;
@@ -15,79 +14,197 @@ pub const IN: u16    = 0x23;
 pub const PUTSP: u16 = 0x24;
 pub const HALT: u16  = 0x25;
 
+// read a character for GETC / IN, or rewind PC to retry the TRAP once input arrives
+fn read_input(reg_file: &mut RegFile, console: &mut dyn Console) -> Result<Option<u8>, VmError> {
+    let c = console.read_char()?;
+    if c.is_none() {
+        reg_file.r_pc = reg_file.r_pc.wrapping_sub(1);
+    }
+    Ok(c)
+}
+
 /**
  * interacting with I/O
  */
-pub fn instr_trap(instr: u16, reg_file: &mut RegFile, mem: &mut Memory) {
-    let trap_vector = instr & 0xff;
+pub fn instr_trap<B: Bus>(vector: u8, reg_file: &mut RegFile, mem: &mut B, console: &mut dyn Console) -> Result<StepOutcome, VmError> {
+    let trap_vector = vector as u16;
     match trap_vector {
         GETC => {
-            let mut buffer = [0; 1];
-            std::io::stdin().read_exact(&mut buffer).unwrap();
-            reg_file.update_reg(0, buffer[0] as u16);
+            match read_input(reg_file, console)? {
+                Some(c) => reg_file.update_reg(0, c as u16)?,
+                None => return Ok(StepOutcome::WaitingForInput),
+            }
             // no update for condition flag because this is a sys trap
         },
         OUT => {
             // output the char from register 0
-            let c = reg_file.read_reg(0) as u8;
-            print!("{}", c as char);
-            std::io::stdout().flush().expect("Flushed.");
+            let c = reg_file.read_reg(0)? as u8;
+            console.write_char(c)?;
+            console.flush()?;
         },
         PUTS => {
-            let mut index = reg_file.read_reg(0) as usize;
-            // error check: index out of range?
-            let mut cc = mem.cells[index];
+            let mut index = reg_file.read_reg(0)?;
+            let mut cc = mem.read(index);
             while cc != 0x0000 {
-                // one word stores two character, either of which could be null char
-                let c1 = ((cc & 0xff) as u8) as char;
-                if c1 == '\0' {
-                    break;
-                }
-                print!("{}", c1);
-                let c2 = ((cc >> 8) as u8) as char;
-                if c2 == '\0' {
-                    break;
-                }
-                print!("{}", c2);
-                index += 1;
-                cc = mem.cells[index];
+                // one character per word, stored in the low byte, as TRAP_PUTS in the OS image
+                console.write_char((cc & 0xff) as u8)?;
+                index = index.wrapping_add(1);
+                cc = mem.read(index);
             }
-            std::io::stdout().flush().expect("Flushed.");
+            console.flush()?;
         },
         IN => {
-            // input a character
-            print!("Enter a character: ");
-            let input = std::io::stdin()
-                .bytes()
-                .next()
-                .and_then(|res| res.ok())
-                .map(|byte| byte as u16)
-                .unwrap();
-            reg_file.update_reg(0, input);
+            // wait before prompting so a non-blocking console does not repeat the prompt
+            if !console.blocks() && !console.poll() {
+                reg_file.r_pc = reg_file.r_pc.wrapping_sub(1);
+                return Ok(StepOutcome::WaitingForInput);
+            }
+            console.write_str("Enter a character: ")?;
+            console.flush()?;
+            match read_input(reg_file, console)? {
+                Some(c) => {
+                    // echo the character, as TRAP_IN in the OS image does
+                    console.write_char(c)?;
+                    console.flush()?;
+                    reg_file.update_reg(0, c as u16)?;
+                },
+                None => return Ok(StepOutcome::WaitingForInput),
+            }
         },
         PUTSP => {
-            // output a byte string
-            let mut index = reg_file.read_reg(0) as usize;
-            let mut cc = mem.cells[index];
+            // output a byte string, two characters per word
+            let mut index = reg_file.read_reg(0)?;
+            let mut cc = mem.read(index);
             while cc != 0x0000 {
-                let c1 = ((cc & 0xff) as u8) as char;
-                print!("{}", c1);
-                let c2 = ((cc >> 8) as u8) as char;
-                if c2 != '\0' {
-                    print!("{}", c2);
+                console.write_char((cc & 0xff) as u8)?;
+                let c2 = (cc >> 8) as u8;
+                if c2 != 0 {
+                    console.write_char(c2)?;
                 }
-                index += 1;
-                cc = mem.cells[index];
+                index = index.wrapping_add(1);
+                cc = mem.read(index);
             }
-            std::io::stdout().flush().expect("Flushed.");
+            console.flush()?;
         },
         HALT => {
-            println!("Halt the program.");
-            std::io::stdout().flush().expect("Flushed.");
-            process::exit(1);
+            console.write_str("Halt the program.\n")?;
+            console.flush()?;
+            return Ok(StepOutcome::Halted);
         },
         _ => {
-            panic!("invalid trap vector!");
+            return Err(VmError::UnknownTrap { vector: trap_vector });
         },
     }
-}
\ No newline at end of file
+    Ok(StepOutcome::Continue)
+}
+
+/**
+ * TRAP through the trap vector table: save the return address in R7, enter
+ * supervisor mode with PSR and PC pushed on the supervisor stack, as for an
+ * interrupt, and jump to the service routine at mem[trapvect8]; the routine
+ * returns with RTI, or with RET through R7 staying in supervisor mode; an
+ * empty entry means no routine
+ */
+pub fn instr_trap_vector<B: Bus>(vector: u8, reg_file: &mut RegFile, mem: &mut B) -> Result<(), VmError> {
+    let trap_vector = vector as u16;
+    if mem.read(trap_vector) == 0 {
+        return Err(VmError::UnknownTrap { vector: trap_vector });
+    }
+    reg_file.update_reg(7, reg_file.r_pc)?;
+    save_context(reg_file, mem)?;
+    reg_file.r_pc = mem.read(trap_vector);
+    Ok(())
+}
+
+#[cfg(test)]
+mod trap_test {
+
+    use super::*;
+    use crate::console::BufferConsole;
+    use crate::memory::Memory;
+    use crate::register::{Privilege, PC_START, SSP_START};
+
+    #[test]
+    fn trap_getc() {
+        let mut reg_file = RegFile::new();
+        let mut mem = Memory::new();
+        let mut console = BufferConsole::new("q");
+        let outcome = instr_trap(0x20, &mut reg_file, &mut mem, &mut console).unwrap();
+        assert_eq!(outcome, StepOutcome::Continue);
+        assert_eq!(reg_file.read_reg(0).unwrap(), 'q' as u16);
+    }
+
+    #[test]
+    fn trap_getc_waits_for_input() {
+        let mut reg_file = RegFile::new();
+        let mut mem = Memory::new();
+        let mut console = BufferConsole::new("");
+        reg_file.r_pc = PC_START + 1; // as if the TRAP at PC_START was just fetched
+        let outcome = instr_trap(0x20, &mut reg_file, &mut mem, &mut console).unwrap();
+        assert_eq!(outcome, StepOutcome::WaitingForInput);
+        assert_eq!(reg_file.r_pc, PC_START);
+    }
+
+    #[test]
+    fn trap_puts_and_putsp() {
+        let mut reg_file = RegFile::new();
+        let mut mem = Memory::new();
+        let console = BufferConsole::new("");
+        for (i, c) in "hi".bytes().enumerate() {
+            mem.write(0x4000 + i as u16, c as u16);
+        }
+        mem.write(0x4010, ('b' as u16) << 8 | 'a' as u16);
+        mem.write(0x4011, 'c' as u16);
+        reg_file.update_reg(0, 0x4000).unwrap();
+        instr_trap(0x22, &mut reg_file, &mut mem, &mut console.clone()).unwrap();
+        reg_file.update_reg(0, 0x4010).unwrap();
+        instr_trap(0x24, &mut reg_file, &mut mem, &mut console.clone()).unwrap();
+        assert_eq!(console.output(), "hiabc");
+    }
+
+    #[test]
+    fn trap_in_prompts_once() {
+        let mut reg_file = RegFile::new();
+        let mut mem = Memory::new();
+        let console = BufferConsole::new("");
+        reg_file.r_pc = PC_START + 1;
+        let outcome = instr_trap(0x23, &mut reg_file, &mut mem, &mut console.clone()).unwrap();
+        assert_eq!(outcome, StepOutcome::WaitingForInput);
+        assert_eq!(console.output(), "");
+        console.push_input("z");
+        reg_file.r_pc = PC_START + 1;
+        instr_trap(0x23, &mut reg_file, &mut mem, &mut console.clone()).unwrap();
+        assert_eq!(console.output(), "Enter a character: z");
+        assert_eq!(reg_file.read_reg(0).unwrap(), 'z' as u16);
+    }
+
+    #[test]
+    fn trap_through_vector_table() {
+        let mut reg_file = RegFile::new();
+        let mut mem = Memory::new();
+        mem.write(0x0021, 0x0420);
+        reg_file.update_reg(6, 0xFD00).unwrap();
+        reg_file.update_reg(7, 0x1234).unwrap();
+        reg_file.r_cond = 2;
+        reg_file.r_pc = PC_START + 1;
+        instr_trap_vector(0x21, &mut reg_file, &mut mem).unwrap();
+        assert_eq!(reg_file.r_pc, 0x0420);
+        assert_eq!(reg_file.privilege, Privilege::Supervisor);
+        assert_eq!(reg_file.read_reg(6).unwrap(), SSP_START - 2);
+        assert_eq!(reg_file.saved_usp, 0xFD00);
+        assert_eq!(mem.read(SSP_START - 1), 0x8002); // user mode, Z
+        assert_eq!(mem.read(SSP_START - 2), PC_START + 1);
+        assert_eq!(reg_file.read_reg(7).unwrap(), PC_START + 1);
+        assert!(matches!(instr_trap_vector(0x30, &mut reg_file, &mut mem), Err(VmError::UnknownTrap { vector: 0x30 })));
+    }
+
+    #[test]
+    fn trap_halt() {
+        let mut reg_file = RegFile::new();
+        let mut mem = Memory::new();
+        let console = BufferConsole::new("");
+        let outcome = instr_trap(0x25, &mut reg_file, &mut mem, &mut console.clone()).unwrap();
+        assert_eq!(outcome, StepOutcome::Halted);
+        assert_eq!(console.output(), "Halt the program.\n");
+    }
+}
@@ -0,0 +1,131 @@
+/*
+This is the LC-3 Interrupt Module, collecting interrupt requests from devices
+until the CPU is ready to take them between instructions
+*/
+
+// keyboard interrupt, vectored through x0180 at priority level 4
+pub const KEYBOARD_VECTOR: u16 = 0x80;
+pub const KEYBOARD_PRIORITY: u16 = 4;
+
+// an interrupt request: the offset into the interrupt vector table and its priority level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupt {
+    pub vector: u16,
+    pub priority: u16,
+}
+
+// a timer raising an interrupt every `interval` instructions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timer {
+    pub interval: u64,
+    pub vector: u16,
+    pub priority: u16,
+    pub remaining: u64, // instructions until it next fires
+}
+
+impl Timer {
+
+    pub fn new(interval: u64, vector: u16, priority: u16) -> Timer {
+        Timer { interval, vector, priority, remaining: interval }
+    }
+
+    // count one instruction, true when the timer fires
+    fn tick(&mut self) -> bool {
+        if self.interval == 0 {
+            return false;
+        }
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            self.remaining = self.interval;
+            return true;
+        }
+        false
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InterruptController {
+    pending: Vec<Interrupt>,
+    pub timer: Option<Timer>,
+}
+
+impl InterruptController {
+
+    pub fn new() -> InterruptController {
+        InterruptController::default()
+    }
+
+    // request an interrupt; a request for a vector that is already pending is merged
+    pub fn raise(&mut self, vector: u16, priority: u16) {
+        if self.pending.iter().all(|irq| irq.vector != vector) {
+            self.pending.push(Interrupt { vector, priority: priority & 0x7 });
+        }
+    }
+
+    // withdraw a request, for level-triggered devices whose condition went away
+    pub fn clear(&mut self, vector: u16) {
+        self.pending.retain(|irq| irq.vector != vector);
+    }
+
+    pub fn pending(&self) -> &[Interrupt] {
+        &self.pending
+    }
+
+    // advance the timer by one instruction
+    pub fn tick(&mut self) {
+        if let Some(timer) = self.timer.as_mut() {
+            if timer.tick() {
+                let (vector, priority) = (timer.vector, timer.priority);
+                self.raise(vector, priority);
+            }
+        }
+    }
+
+    /**
+     * remove and return the highest priority request above the current
+     * priority level, lower ones stay pending until the level drops
+     */
+    pub fn take(&mut self, level: u16) -> Option<Interrupt> {
+        let index = self.pending.iter().enumerate()
+            .filter(|(_, irq)| irq.priority > level)
+            .max_by_key(|(_, irq)| irq.priority)
+            .map(|(index, _)| index)?;
+        Some(self.pending.remove(index))
+    }
+}
+
+#[cfg(test)]
+mod interrupt_test {
+
+    use super::*;
+
+    #[test]
+    fn highest_priority_first() {
+        let mut controller = InterruptController::new();
+        controller.raise(0x81, 2);
+        controller.raise(KEYBOARD_VECTOR, KEYBOARD_PRIORITY);
+        controller.raise(KEYBOARD_VECTOR, KEYBOARD_PRIORITY);
+        assert_eq!(controller.pending().len(), 2);
+        controller.raise(0x82, 1);
+        controller.clear(0x82);
+        assert_eq!(controller.take(4), None);
+        assert_eq!(controller.take(1), Some(Interrupt { vector: KEYBOARD_VECTOR, priority: 4 }));
+        assert_eq!(controller.take(1), Some(Interrupt { vector: 0x81, priority: 2 }));
+        assert_eq!(controller.take(0), None);
+    }
+
+    #[test]
+    fn timer_fires_every_interval() {
+        let mut controller = InterruptController::new();
+        controller.timer = Some(Timer::new(3, 0x81, 6));
+        controller.tick();
+        controller.tick();
+        assert!(controller.pending().is_empty());
+        controller.tick();
+        assert_eq!(controller.take(0), Some(Interrupt { vector: 0x81, priority: 6 }));
+        for _ in 0..3 {
+            controller.tick();
+        }
+        assert_eq!(controller.pending().len(), 1);
+    }
+}
@@ -1,7 +1,26 @@
-mod utils;
+pub mod utils;
+pub mod error;
+pub mod console;
+pub mod device;
+pub mod interrupt;
 pub mod memory;
 pub mod register;
 pub mod instruction;
+pub mod machine;
+pub mod loader;
+pub mod os;
+pub mod assembler;
+pub mod disassembler;
+pub mod debugger;
+pub mod watch;
+pub mod history;
+pub mod snapshot;
+pub mod tracer;
+pub mod coverage;
+pub mod profiler;
+pub mod timing;
+pub mod datapath;
+pub mod gdbstub;
 
 use wasm_bindgen::prelude::*;
 
@@ -21,6 +40,111 @@ pub fn greet() {
     alert("Hello, lc3-vm-sim!");
 }
 
+// assemble a source buffer and return its diagnostics as a JSON array of
+// editor markers, empty when the program assembled cleanly
+#[wasm_bindgen]
+pub fn assemble_markers(source: &str) -> String {
+    let diagnostics = match assembler::assemble(source) {
+        Ok(program) => program.warnings,
+        Err(diagnostics) => diagnostics,
+    };
+    let markers: Vec<String> = diagnostics.iter().map(|d| d.to_json(source)).collect();
+    format!("[{}]", markers.join(","))
+}
+
+// a machine for the web front end, console output is handed to a JS callback
+// one character at a time and keyboard input is pushed in by the page
+#[wasm_bindgen]
+pub struct WebMachine {
+    machine: machine::Machine,
+    input: console::InputQueue,
+}
+
+#[wasm_bindgen]
+impl WebMachine {
+
+    #[wasm_bindgen(constructor)]
+    pub fn new(on_output: js_sys::Function) -> WebMachine {
+        utils::set_panic_hook();
+        let console = console::CallbackConsole::new(move |c| {
+            let _ = on_output.call1(&JsValue::NULL, &JsValue::from_str(&(c as char).to_string()));
+        });
+        let input = console.input();
+        WebMachine { machine: machine::Machine::with_console(Box::new(console)), input }
+    }
+
+    // assemble and load a program, returning the error markers on failure
+    pub fn load_source(&mut self, source: &str) -> Result<(), JsValue> {
+        let program = assembler::assemble(source).map_err(|_| JsValue::from_str(&assemble_markers(source)))?;
+        self.machine.load_image(&program.image).map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    // run TRAPs through the bundled OS image instead of the built-in routines
+    pub fn load_os(&mut self) -> Result<(), JsValue> {
+        self.machine.load_os().map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    // the machine state in the snapshot file format, for saving a session
+    pub fn snapshot(&self) -> Vec<u8> {
+        self.machine.snapshot().to_bytes()
+    }
+
+    pub fn restore(&mut self, bytes: &[u8]) -> Result<(), JsValue> {
+        let snapshot = snapshot::Snapshot::parse(bytes).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        self.machine.restore(&snapshot);
+        Ok(())
+    }
+
+    // count cycles from now on, with memory accesses taking `memory_latency` cycles
+    pub fn enable_timing(&mut self, memory_latency: u32) {
+        self.machine.enable_timing(timing::TimingModel::new(memory_latency as u64));
+    }
+
+    // cycles elapsed since timing was enabled, as a float since JS numbers are doubles
+    pub fn cycles(&self) -> f64 {
+        self.machine.cycles() as f64
+    }
+
+    // run instructions through the control state machine, so `last_cycles` can be animated
+    pub fn use_datapath(&mut self, enabled: bool) {
+        self.machine.engine = if enabled { machine::Engine::Datapath } else { machine::Engine::Handlers };
+    }
+
+    // the states the last instruction went through as a JSON array, see `datapath::Cycle::to_json`
+    pub fn last_cycles(&self) -> String {
+        let cycles: Vec<String> = self.machine.last_cycles().iter().map(|cycle| cycle.to_json()).collect();
+        format!("[{}]", cycles.join(","))
+    }
+
+    pub fn push_input(&mut self, input: &str) {
+        self.input.push(input);
+    }
+
+    // run at most `n` instructions, returning "continue", "halted", "waiting" or "watchpoint"
+    pub fn run_for(&mut self, n: usize) -> Result<String, JsValue> {
+        match self.machine.run_for(n) {
+            Ok(machine::StepOutcome::Continue) => Ok("continue".to_string()),
+            Ok(machine::StepOutcome::Halted) => Ok("halted".to_string()),
+            Ok(machine::StepOutcome::WaitingForInput) => Ok("waiting".to_string()),
+            Ok(machine::StepOutcome::Watchpoint(_)) => Ok("watchpoint".to_string()),
+            Err(e) => Err(JsValue::from_str(&e.to_string())),
+        }
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.machine.reg_file.r_pc
+    }
+
+    pub fn register(&self, reg: u16) -> Result<u16, JsValue> {
+        self.machine.reg_file.read_reg(reg).map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    // a window of memory for the memory view, returned as a Uint16Array
+    pub fn memory(&self, start: u16, len: usize) -> Vec<u16> {
+        self.machine.mem.read_range(start, len).to_vec()
+    }
+}
+
 // #[wasm_bindgen]
 // pub fn select_file() {
 
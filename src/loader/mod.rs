@@ -0,0 +1,168 @@
+/*
+This is the LC-3 Loader Module, reading big-endian object files into memory
+*/
+use crate::memory::{Memory, MEMORY_SIZE};
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    // the file does not even contain an origin word
+    Empty,
+    // the file has an odd number of bytes
+    Truncated { len: usize },
+    // the program does not fit between its origin and the end of memory
+    PastEndOfMemory { origin: u16, len: usize },
+    // the program writes to a cell another loaded program already occupies
+    Overlap { address: u16 },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "unable to read object file: {}", e),
+            LoadError::Empty => write!(f, "object file is empty"),
+            LoadError::Truncated { len } => write!(f, "object file has an odd number of bytes ({})", len),
+            LoadError::PastEndOfMemory { origin, len } => {
+                write!(f, "program of {} words at 0x{:04x} runs past the end of memory", len, origin)
+            },
+            LoadError::Overlap { address } => write!(f, "program overlaps a loaded program at 0x{:04x}", address),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> LoadError {
+        LoadError::Io(e)
+    }
+}
+
+// an object file: an origin followed by the words placed consecutively from it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectImage {
+    pub origin: u16,
+    pub words: Vec<u16>,
+}
+
+impl ObjectImage {
+
+    pub fn new(origin: u16, words: Vec<u16>) -> ObjectImage {
+        ObjectImage { origin, words }
+    }
+
+    // parse the big-endian object format
+    pub fn parse(bytes: &[u8]) -> Result<ObjectImage, LoadError> {
+        if !bytes.len().is_multiple_of(2) {
+            return Err(LoadError::Truncated { len: bytes.len() });
+        }
+        let mut words = bytes.chunks(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]]));
+        let origin = words.next().ok_or(LoadError::Empty)?;
+        Ok(ObjectImage::new(origin, words.collect()))
+    }
+
+    pub fn read_file<P: AsRef<Path>>(path: P) -> Result<ObjectImage, LoadError> {
+        ObjectImage::parse(&fs::read(path)?)
+    }
+
+    // serialize back to the big-endian object format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(2 * (self.words.len() + 1));
+        bytes.extend_from_slice(&self.origin.to_be_bytes());
+        for word in self.words.iter() {
+            bytes.extend_from_slice(&word.to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn write_file<P: AsRef<Path>>(&self, path: P) -> Result<(), LoadError> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    // the addresses this image occupies
+    pub fn span(&self) -> Range<usize> {
+        let start = self.origin as usize;
+        start..start + self.words.len()
+    }
+}
+
+/**
+ * copy an image into memory, refusing to clobber any of the already loaded
+ * `regions`; on success the image's span is appended to `regions`
+ */
+pub fn load_image(image: &ObjectImage, mem: &mut Memory, regions: &mut Vec<Range<usize>>) -> Result<(), LoadError> {
+    let span = image.span();
+    if span.end > MEMORY_SIZE {
+        return Err(LoadError::PastEndOfMemory { origin: image.origin, len: image.words.len() });
+    }
+    for region in regions.iter() {
+        if span.start < region.end && region.start < span.end {
+            let address = span.start.max(region.start);
+            return Err(LoadError::Overlap { address: address as u16 });
+        }
+    }
+    mem.load_slice(image.origin, &image.words);
+    if !span.is_empty() {
+        regions.push(span);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod loader_test {
+
+    use super::*;
+
+    #[test]
+    fn parse_big_endian() {
+        let image = ObjectImage::parse(&[0x30, 0x00, 0x12, 0x61, 0xF0, 0x25]).unwrap();
+        assert_eq!(image.origin, 0x3000);
+        assert_eq!(image.words, vec![0x1261, 0xF025]);
+        assert_eq!(image.to_bytes(), vec![0x30, 0x00, 0x12, 0x61, 0xF0, 0x25]);
+    }
+
+    #[test]
+    fn parse_rejects_bad_files() {
+        assert!(matches!(ObjectImage::parse(&[]), Err(LoadError::Empty)));
+        assert!(matches!(ObjectImage::parse(&[0x30, 0x00, 0x12]), Err(LoadError::Truncated { len: 3 })));
+    }
+
+    #[test]
+    fn load_into_memory() {
+        let mut mem = Memory::new();
+        let mut regions = Vec::new();
+        let image = ObjectImage::new(0x3000, vec![0x1111, 0x2222]);
+        load_image(&image, &mut mem, &mut regions).unwrap();
+        assert_eq!(mem.read(0x3000), 0x1111);
+        assert_eq!(mem.read(0x3001), 0x2222);
+        assert_eq!(regions, vec![0x3000..0x3002]);
+    }
+
+    #[test]
+    fn load_reports_overlap() {
+        let mut mem = Memory::new();
+        let mut regions = Vec::new();
+        load_image(&ObjectImage::new(0x3000, vec![1, 2, 3]), &mut mem, &mut regions).unwrap();
+        let result = load_image(&ObjectImage::new(0x2FFF, vec![4, 5]), &mut mem, &mut regions);
+        assert!(matches!(result, Err(LoadError::Overlap { address: 0x3000 })));
+        // the failed load leaves memory untouched
+        assert_eq!(mem.read(0x2FFF), 0);
+        load_image(&ObjectImage::new(0x3003, vec![6]), &mut mem, &mut regions).unwrap();
+    }
+
+    #[test]
+    fn load_reports_past_end_of_memory() {
+        let mut mem = Memory::new();
+        let mut regions = Vec::new();
+        let origin = (MEMORY_SIZE - 1) as u16;
+        let result = load_image(&ObjectImage::new(origin, vec![1, 2]), &mut mem, &mut regions);
+        assert!(matches!(result, Err(LoadError::PastEndOfMemory { len: 2, .. })));
+    }
+}
@@ -0,0 +1,974 @@
+/*
+This is the LC-3 Machine Module, tying the register file and the memory
+together with a fetch-decode-execute loop
+*/
+use crate::console::{Console, StdConsole};
+use crate::datapath::{Cycle, Datapath};
+use crate::device::{Devices, MemoryBus, DEVICE_PAGE};
+use crate::error::VmError;
+use crate::interrupt::*;
+use crate::register::{Privilege, RegFile};
+use crate::history::{History, UndoRecord};
+use crate::memory::{AccessKind, Bus, Memory, MemoryAccess};
+use crate::instruction::decode::*;
+use crate::instruction::exception::*;
+use crate::instruction::instr::*;
+use crate::instruction::trap::*;
+use crate::loader::*;
+use crate::os::os_image;
+use crate::snapshot::Snapshot;
+use crate::timing::{StepCycles, Timing, TimingModel};
+use crate::watch::{WatchHit, Watchpoint};
+
+use std::ops::Range;
+use std::path::Path;
+
+// what happened after executing instructions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    // the machine can keep going
+    Continue,
+    // the program executed HALT
+    Halted,
+    // GETC / IN found no input, the TRAP is retried on the next step
+    WaitingForInput,
+    // the instruction triggered the watchpoint with this index, see `Machine::watch_hit`
+    Watchpoint(usize),
+}
+
+// how TRAP instructions are carried out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapMode {
+    // service routines implemented natively in Rust
+    Native,
+    // jump through the trap vector table at x0000-x00FF to LC-3 service routines
+    VectorTable,
+}
+
+// how instructions are carried out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    // one handler per instruction, `instr_*`
+    Handlers,
+    // the control state machine one state at a time, see `Machine::last_cycles`
+    Datapath,
+}
+
+pub struct Machine {
+    pub reg_file: RegFile,
+    pub mem: Memory,
+    pub devices: Devices,
+    pub interrupts: InterruptController,
+    pub trap_mode: TrapMode,
+    pub engine: Engine,
+    pub watchpoints: Vec<Watchpoint>,
+    console: Box<dyn Console>,
+    running: bool,
+    count: u64, // number of instructions executed
+    regions: Vec<Range<usize>>, // memory occupied by loaded object files
+    accesses: Vec<MemoryAccess>, // loads and stores made by the last step
+    watch_hit: Option<WatchHit>,
+    history: Option<History>, // undo log, when recording
+    last_fetch: Option<(u16, u16)>, // (address, word) of the last instruction fetched
+    timing: Option<Timing>, // cycle counter, when modelling time
+    datapath: Datapath, // MAR, MDR, IR, BEN and the states of the last step
+}
+
+impl Machine {
+
+    // initialize a machine with an empty memory and PC at 0x3000, using stdin / stdout
+    pub fn new() -> Machine {
+        Machine::with_console(Box::new(StdConsole::new()))
+    }
+
+    pub fn with_console(console: Box<dyn Console>) -> Machine {
+        Machine {
+            reg_file: RegFile::new(),
+            mem: Memory::new(),
+            devices: Devices::new(),
+            interrupts: InterruptController::new(),
+            trap_mode: TrapMode::Native,
+            engine: Engine::Handlers,
+            watchpoints: Vec::new(),
+            console,
+            running: true,
+            count: 0,
+            regions: Vec::new(),
+            accesses: Vec::new(),
+            watch_hit: None,
+            history: None,
+            last_fetch: None,
+            timing: None,
+            datapath: Datapath::new(),
+        }
+    }
+
+    /**
+     * load an object image and point PC at its origin, so when loading an OS
+     * image plus a user program the program should be loaded last
+     */
+    pub fn load_image(&mut self, image: &ObjectImage) -> Result<(), LoadError> {
+        load_image(image, &mut self.mem, &mut self.regions)?;
+        self.reg_file.r_pc = image.origin;
+        // the undo log cannot go back past a load
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
+        Ok(())
+    }
+
+    // load a big-endian .obj file from disk
+    pub fn load_obj_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), LoadError> {
+        let image = ObjectImage::read_file(path)?;
+        self.load_image(&image)
+    }
+
+    /**
+     * load the bundled OS image and run TRAPs through its trap vector table;
+     * PC is left alone so the OS can be loaded before or after the program
+     */
+    pub fn load_os(&mut self) -> Result<(), LoadError> {
+        let pc = self.reg_file.r_pc;
+        self.load_image(&os_image())?;
+        self.reg_file.r_pc = pc;
+        self.trap_mode = TrapMode::VectorTable;
+        Ok(())
+    }
+
+    pub fn set_console(&mut self, console: Box<dyn Console>) {
+        self.console = console;
+    }
+
+    pub fn console_mut(&mut self) -> &mut dyn Console {
+        self.console.as_mut()
+    }
+
+    // whether the machine is still running (has not hit HALT)
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    // number of instructions executed so far
+    pub fn instruction_count(&self) -> u64 {
+        self.count
+    }
+
+    // data loads and stores (instruction fetches excluded) made by the last step
+    pub fn last_accesses(&self) -> &[MemoryAccess] {
+        &self.accesses
+    }
+
+    // (address, word) of the instruction the last step fetched, after any interrupt was taken
+    pub fn last_instruction(&self) -> Option<(u16, u16)> {
+        self.last_fetch
+    }
+
+    // the watchpoint that stopped the last step, if any
+    pub fn watch_hit(&self) -> Option<&WatchHit> {
+        self.watch_hit.as_ref()
+    }
+
+    // capture the complete machine state, including keyboard input not read yet
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            reg_file: self.reg_file.clone(),
+            mem: self.mem.clone(),
+            devices: self.devices.clone(),
+            interrupts: self.interrupts.clone(),
+            trap_mode: self.trap_mode,
+            running: self.running,
+            count: self.count,
+            regions: self.regions.clone(),
+            input: self.console.pending_input(),
+        }
+    }
+
+    /**
+     * go back to a captured state; the console keeps its output but its
+     * pending input is replaced, and the undo log and cycle counter start over
+     */
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.reg_file = snapshot.reg_file.clone();
+        self.mem = snapshot.mem.clone();
+        self.devices = snapshot.devices.clone();
+        self.interrupts = snapshot.interrupts.clone();
+        self.trap_mode = snapshot.trap_mode;
+        self.running = snapshot.running;
+        self.count = snapshot.count;
+        self.regions = snapshot.regions.clone();
+        self.console.replace_input(&snapshot.input);
+        self.accesses.clear();
+        self.watch_hit = None;
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
+        if let Some(timing) = self.timing.as_mut() {
+            *timing = Timing::new(timing.model);
+        }
+    }
+
+    // count cycles from now on, as the textbook's state machine would take them
+    pub fn enable_timing(&mut self, model: TimingModel) {
+        self.timing = Some(Timing::new(model));
+    }
+
+    pub fn disable_timing(&mut self) {
+        self.timing = None;
+    }
+
+    pub fn timing(&self) -> Option<&Timing> {
+        self.timing.as_ref()
+    }
+
+    // cycles elapsed since timing was enabled, 0 when it is not
+    pub fn cycles(&self) -> u64 {
+        self.timing.as_ref().map_or(0, Timing::cycles)
+    }
+
+    // the microarchitectural registers, as left by the datapath engine
+    pub fn datapath(&self) -> &Datapath {
+        &self.datapath
+    }
+
+    // the states the last step went through, empty unless the datapath engine ran it
+    pub fn last_cycles(&self) -> &[Cycle] {
+        self.datapath.cycles()
+    }
+
+    /**
+     * record an undo log of at most `limit` instructions from now on, keyboard
+     * input already consumed by the program is not given back when stepping back
+     */
+    pub fn enable_history(&mut self, limit: usize) {
+        self.history = Some(History::new(limit));
+    }
+
+    pub fn disable_history(&mut self) {
+        self.history = None;
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    // undo the last recorded step, false when there is nothing left to undo
+    pub fn step_back(&mut self) -> bool {
+        let record = match self.history.as_mut().and_then(|history| history.pop()) {
+            Some(record) => record,
+            None => return false,
+        };
+        for (address, old) in record.writes.iter().rev() {
+            self.mem.write(*address, *old);
+        }
+        self.reg_file = record.reg_file;
+        self.devices = record.devices;
+        if let Some(interrupts) = record.interrupts {
+            self.interrupts = interrupts;
+        }
+        self.count = record.count;
+        self.running = record.running;
+        if let (Some(timing), Some(cycles)) = (self.timing.as_mut(), record.cycles) {
+            timing.uncharge(cycles);
+        }
+        self.accesses.clear();
+        self.watch_hit = None;
+        true
+    }
+
+    // change a register by hand, stepping back undoes it like an instruction
+    pub fn edit_reg(&mut self, reg: u16, value: u16) -> Result<(), VmError> {
+        let undo = self.undo_record();
+        self.reg_file.update_reg(reg, value)?;
+        if let (Some(history), Some(undo)) = (self.history.as_mut(), undo) {
+            history.push(undo);
+        }
+        Ok(())
+    }
+
+    // change a memory cell by hand, without going through the devices
+    pub fn edit_mem(&mut self, address: u16, value: u16) {
+        let undo = self.undo_record();
+        if let (Some(history), Some(mut undo)) = (self.history.as_mut(), undo) {
+            undo.writes.push((address, self.mem.read(address)));
+            history.push(undo);
+        }
+        self.mem.write(address, value);
+    }
+
+    // step back until at most `count` instructions have executed, false if the log runs out first
+    pub fn rewind_to(&mut self, count: u64) -> bool {
+        while self.count > count {
+            if !self.step_back() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /**
+     * take a pending interrupt if any, then fetch the word at PC, increment PC
+     * and execute the instruction
+     */
+    pub fn step(&mut self) -> Result<StepOutcome, VmError> {
+        if !self.running {
+            return Err(VmError::Halted);
+        }
+        self.accesses.clear();
+        self.watch_hit = None;
+        self.last_fetch = None;
+        self.datapath.clear();
+        let before = if self.watchpoints.is_empty() { None } else { Some(self.reg_file.clone()) };
+        let undo = self.undo_record();
+        let interrupted = self.service_interrupts()?;
+        let pc = self.reg_file.r_pc;
+        let instr = match self.engine {
+            Engine::Handlers => {
+                self.reg_file.r_pc = pc.wrapping_add(1);
+                self.bus().read(pc)
+            },
+            Engine::Datapath => {
+                let mem = &mut MemoryBus { mem: &mut self.mem, devices: &mut self.devices, console: self.console.as_mut(), log: None };
+                self.datapath.fetch(&mut self.reg_file, mem)
+            },
+        };
+        self.last_fetch = Some((pc, instr));
+        let (cond, privilege) = (self.reg_file.r_cond, self.reg_file.privilege);
+        let fetched = self.reg_file.clone();
+        let mut outcome = match self.execute(instr) {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                // leave the machine at the faulting instruction, its memory writes can be undone
+                self.reg_file = fetched;
+                self.reg_file.r_pc = pc;
+                self.push_undo(undo, None);
+                return Err(err);
+            },
+        };
+        // a store clearing MCR[15] stops the clock
+        if outcome == StepOutcome::Continue && !self.devices.clock_enabled() {
+            outcome = StepOutcome::Halted;
+        }
+        match outcome {
+            StepOutcome::Continue => {
+                self.count += 1;
+                self.interrupts.tick();
+                if let Some(before) = before {
+                    if let Some(hit) = self.check_watchpoints(&before) {
+                        outcome = StepOutcome::Watchpoint(hit.index);
+                        self.watch_hit = Some(hit);
+                    }
+                }
+            },
+            StepOutcome::Halted => {
+                self.count += 1;
+                self.running = false;
+            },
+            // the TRAP will be executed again (instructions never report watchpoints themselves)
+            StepOutcome::WaitingForInput | StepOutcome::Watchpoint(_) => {},
+        }
+        let trap_mode = self.trap_mode;
+        let cycles = self.timing.as_mut().map(|timing| {
+            let completed = if outcome == StepOutcome::WaitingForInput { None } else { Some((instr, cond, privilege)) };
+            timing.charge(trap_mode, interrupted, completed)
+        });
+        // waiting for input changes nothing unless an interrupt was taken first
+        if outcome != StepOutcome::WaitingForInput || undo.as_ref().is_some_and(|undo| undo.reg_file != self.reg_file) {
+            self.push_undo(undo, cycles);
+        }
+        Ok(outcome)
+    }
+
+    // the state as it is now, to be filled in and pushed once a change is made
+    fn undo_record(&self) -> Option<UndoRecord> {
+        self.history.as_ref().map(|_| UndoRecord {
+            reg_file: self.reg_file.clone(),
+            devices: self.devices.clone(),
+            interrupts: Some(self.interrupts.clone()),
+            writes: Vec::new(),
+            count: self.count,
+            running: self.running,
+            cycles: None,
+        })
+    }
+
+    // record the memory writes of the current step in its undo record and keep it
+    fn push_undo(&mut self, undo: Option<UndoRecord>, cycles: Option<StepCycles>) {
+        if let (Some(history), Some(mut undo)) = (self.history.as_mut(), undo) {
+            undo.writes = self.accesses.iter()
+                .filter(|access| access.kind == AccessKind::Write && access.address < DEVICE_PAGE)
+                .map(|access| (access.address, access.old))
+                .collect();
+            if undo.interrupts.as_ref() == Some(&self.interrupts) {
+                undo.interrupts = None;
+            }
+            undo.cycles = cycles;
+            history.push(undo);
+        }
+    }
+
+    // the first watchpoint triggered by the step that started with registers `before`
+    fn check_watchpoints(&self, before: &RegFile) -> Option<WatchHit> {
+        self.watchpoints.iter().enumerate().find_map(|(index, watch)| {
+            watch.check(before, &self.reg_file, &self.accesses).map(|message| WatchHit { index, message })
+        })
+    }
+
+    /**
+     * the keyboard requests an interrupt while a character is ready and
+     * KBSR[14] is set; the highest priority request above the current level
+     * is taken by pushing PSR / PC and jumping through the vector table;
+     * returns whether one was taken
+     */
+    fn service_interrupts(&mut self) -> Result<bool, VmError> {
+        if self.devices.kbsr_ie && self.console.poll() {
+            self.interrupts.raise(KEYBOARD_VECTOR, KEYBOARD_PRIORITY);
+        } else {
+            self.interrupts.clear(KEYBOARD_VECTOR);
+        }
+        if let Some(irq) = self.interrupts.take(self.reg_file.priority) {
+            let mem = &mut MemoryBus {
+                mem: &mut self.mem, devices: &mut self.devices, console: self.console.as_mut(), log: Some(&mut self.accesses),
+            };
+            match self.engine {
+                Engine::Handlers => enter_service_routine(irq.vector, Some(irq.priority), &mut self.reg_file, mem)?,
+                Engine::Datapath => self.datapath.interrupt(irq, &mut self.reg_file, mem),
+            }
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    // the address space as seen by the running program, devices included
+    pub fn bus(&mut self) -> MemoryBus<'_> {
+        MemoryBus { mem: &mut self.mem, devices: &mut self.devices, console: self.console.as_mut(), log: None }
+    }
+
+    // run until the machine halts or waits for input
+    pub fn run(&mut self) -> Result<StepOutcome, VmError> {
+        loop {
+            let outcome = self.step()?;
+            if outcome != StepOutcome::Continue {
+                return Ok(outcome);
+            }
+        }
+    }
+
+    // run at most n instructions, stopping early if the machine halts or waits for input
+    pub fn run_for(&mut self, n: usize) -> Result<StepOutcome, VmError> {
+        for _ in 0..n {
+            let outcome = self.step()?;
+            if outcome != StepOutcome::Continue {
+                return Ok(outcome);
+            }
+        }
+        Ok(StepOutcome::Continue)
+    }
+
+    /**
+     * raise an exception through the interrupt vector table; with no service
+     * routine installed (no OS loaded) there is nothing to vector to, so the
+     * error is reported to the embedder instead
+     */
+    fn raise_exception<B: Bus>(vector: u16, error: VmError, reg_file: &mut RegFile, mem: &mut B) -> Result<(), VmError> {
+        if service_routine(vector, mem) == 0 {
+            return Err(error);
+        }
+        enter_service_routine(vector, None, reg_file, mem)
+    }
+
+    // decode a single (already fetched) instruction and dispatch it to its handler
+    fn execute(&mut self, instr: u16) -> Result<StepOutcome, VmError> {
+        let reg_file = &mut self.reg_file;
+        let mem = &mut MemoryBus {
+            mem: &mut self.mem, devices: &mut self.devices, console: self.console.as_mut(), log: Some(&mut self.accesses),
+        };
+        if self.engine == Engine::Datapath {
+            return self.datapath.execute(self.trap_mode, reg_file, mem);
+        }
+        let pc = reg_file.r_pc.wrapping_sub(1);
+        let decoded = match decode(instr) {
+            Ok(decoded) => decoded,
+            Err(_) => {
+                Machine::raise_exception(ILLEGAL_OPCODE, VmError::IllegalOpcode { pc, word: instr }, reg_file, mem)?;
+                return Ok(StepOutcome::Continue);
+            },
+        };
+        match decoded {
+            Instruction::Br { n, z, p, offset9 } => instr_brx(n, z, p, offset9, reg_file)?,
+            Instruction::Add { dr, sr1, operand } => instr_add(dr, sr1, operand, reg_file)?,
+            Instruction::Ld { dr, offset9 } => instr_ld(dr, offset9, reg_file, mem)?,
+            Instruction::St { sr, offset9 } => instr_st(sr, offset9, reg_file, mem)?,
+            Instruction::Jsr { offset11 } => instr_jsr(offset11, reg_file)?,
+            Instruction::Jsrr { base_r } => instr_jsrr(base_r, reg_file)?,
+            Instruction::And { dr, sr1, operand } => instr_and(dr, sr1, operand, reg_file)?,
+            Instruction::Ldr { dr, base_r, offset6 } => instr_ldr(dr, base_r, offset6, reg_file, mem)?,
+            Instruction::Str { sr, base_r, offset6 } => instr_str(sr, base_r, offset6, reg_file, mem)?,
+            Instruction::Rti if reg_file.privilege == Privilege::User => {
+                Machine::raise_exception(PRIVILEGE_VIOLATION, VmError::PrivilegeViolation { pc }, reg_file, mem)?
+            },
+            Instruction::Rti => instr_rti(reg_file, mem)?,
+            Instruction::Not { dr, sr } => instr_not(dr, sr, reg_file)?,
+            Instruction::Ldi { dr, offset9 } => instr_ldi(dr, offset9, reg_file, mem)?,
+            Instruction::Sti { sr, offset9 } => instr_sti(sr, offset9, reg_file, mem)?,
+            Instruction::Jmp { base_r } => instr_jmp(base_r, reg_file)?,
+            Instruction::Ret => instr_ret(reg_file)?,
+            Instruction::Lea { dr, offset9 } => instr_lea(dr, offset9, reg_file)?,
+            Instruction::Trap { vector } => match self.trap_mode {
+                TrapMode::Native => return instr_trap(vector, reg_file, mem.mem, mem.console),
+                TrapMode::VectorTable => instr_trap_vector(vector, reg_file, mem)?,
+            },
+        }
+        Ok(StepOutcome::Continue)
+    }
+}
+
+impl Default for Machine {
+    fn default() -> Machine {
+        Machine::new()
+    }
+}
+
+#[cfg(test)]
+mod machine_test {
+
+    use super::*;
+    use crate::console::BufferConsole;
+    use crate::instruction::{OP_LD, OP_LDI};
+    use crate::register::*;
+    use crate::watch::WatchAccess;
+
+    // load a sequence of words starting at PC_START
+    fn load(machine: &mut Machine, program: &[u16]) {
+        for (i, word) in program.iter().enumerate() {
+            machine.mem.write(PC_START + i as u16, *word);
+        }
+    }
+
+    #[test]
+    fn step_increments_pc() {
+        let mut machine = Machine::new();
+        load(&mut machine, &[0b0001001001100001]); // ADD R1, R1, #1
+        assert_eq!(machine.step().unwrap(), StepOutcome::Continue);
+        assert_eq!(machine.reg_file.r_pc, PC_START + 1);
+        assert_eq!(machine.reg_file.read_reg(1).unwrap(), 1);
+        assert_eq!(machine.instruction_count(), 1);
+    }
+
+    #[test]
+    fn run_until_halt() {
+        let mut machine = Machine::new();
+        load(&mut machine, &[
+            0b0101000000100000, // AND R0, R0, #0
+            0b0001000000100101, // ADD R0, R0, #5
+            0b0001001001100011, // ADD R1, R1, #3
+            0b0001000000111111, // LOOP ADD R0, R0, #-1
+            0b0000001111111110, // BRp LOOP
+            0xF025,             // HALT
+        ]);
+        assert_eq!(machine.run().unwrap(), StepOutcome::Halted);
+        assert!(!machine.is_running());
+        assert_eq!(machine.reg_file.read_reg(0).unwrap(), 0);
+        assert_eq!(machine.reg_file.read_reg(1).unwrap(), 3);
+        assert_eq!(machine.reg_file.r_pc, PC_START + 6);
+        // stepping a halted machine is an error rather than a no-op
+        assert!(matches!(machine.step(), Err(VmError::Halted)));
+    }
+
+    #[test]
+    fn run_for_stops_after_n() {
+        let mut machine = Machine::new();
+        load(&mut machine, &[0b0000111111111111]); // BRnzp #-1 (spin forever)
+        machine.reg_file.update_reg(COND_REG, CondFlag::ZRO as u16).unwrap();
+        assert_eq!(machine.run_for(10).unwrap(), StepOutcome::Continue);
+        assert_eq!(machine.instruction_count(), 10);
+        assert!(machine.is_running());
+        assert_eq!(machine.reg_file.r_pc, PC_START);
+    }
+
+    #[test]
+    fn load_image_sets_pc() {
+        let mut machine = Machine::new();
+        let os = ObjectImage::new(0x0200, vec![0xF025]);
+        let program = ObjectImage::new(0x4000, vec![0x1261, 0xF025]);
+        machine.load_image(&os).unwrap();
+        machine.load_image(&program).unwrap();
+        assert_eq!(machine.reg_file.r_pc, 0x4000);
+        assert!(machine.load_image(&ObjectImage::new(0x4001, vec![0])).is_err());
+        machine.run().unwrap();
+        assert_eq!(machine.reg_file.read_reg(1).unwrap(), 1);
+    }
+
+    #[test]
+    fn subroutine_call_and_return() {
+        let mut machine = Machine::new();
+        load(&mut machine, &[
+            0b0100100000000010, // JSR #2
+            0b0001010010100001, // ADD R2, R2, #1
+            0xF025,             // HALT
+            0b0001011011100111, // ADD R3, R3, #7
+            0b1100000111000000, // RET
+        ]);
+        machine.run().unwrap();
+        assert_eq!(machine.reg_file.read_reg(2).unwrap(), 1);
+        assert_eq!(machine.reg_file.read_reg(3).unwrap(), 7);
+    }
+
+    #[test]
+    fn echo_through_console() {
+        let console = BufferConsole::new("");
+        let mut machine = Machine::with_console(Box::new(console.clone()));
+        load(&mut machine, &[
+            0xF020, // GETC
+            0xF021, // OUT
+            0xF025, // HALT
+        ]);
+        assert_eq!(machine.run().unwrap(), StepOutcome::WaitingForInput);
+        assert_eq!(machine.reg_file.r_pc, PC_START);
+        assert_eq!(machine.instruction_count(), 0);
+        console.push_input("k");
+        assert_eq!(machine.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(console.output(), "kHalt the program.\n");
+    }
+
+    #[test]
+    fn polling_keyboard_and_display() {
+        let console = BufferConsole::new("x");
+        let mut machine = Machine::with_console(Box::new(console.clone()));
+        load(&mut machine, &[
+            0xA207, // POLL LDI R1, KBSRP
+            0x07FE, //      BRzp POLL
+            0xA006, //      LDI R0, KBDRP
+            0xA206, // WAIT LDI R1, DSRP
+            0x07FE, //      BRzp WAIT
+            0xB005, //      STI R0, DDRP
+            0x5260, //      AND R1, R1, #0
+            0xB204, //      STI R1, MCRP
+            0xFE00, // KBSRP
+            0xFE02, // KBDRP
+            0xFE04, // DSRP
+            0xFE06, // DDRP
+            0xFFFE, // MCRP
+        ]);
+        assert_eq!(machine.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(console.output(), "x");
+        assert!(!machine.devices.clock_enabled());
+    }
+
+    #[test]
+    fn illegal_opcode_is_an_error() {
+        let mut machine = Machine::new();
+        load(&mut machine, &[0xD000]);
+        assert!(matches!(machine.step(), Err(VmError::IllegalOpcode { pc: PC_START, word: 0xD000 })));
+    }
+
+    #[test]
+    fn illegal_opcode_exception() {
+        let mut machine = Machine::new();
+        load(&mut machine, &[0xD000]);
+        machine.mem.write(0x0101, 0x1000);
+        machine.mem.load_slice(0x1000, &[
+            0b0001001001100001, // ADD R1, R1, #1
+            0x8000,             // RTI
+        ]);
+        machine.reg_file.update_reg(6, 0xF000).unwrap();
+        machine.step().unwrap();
+        assert_eq!(machine.reg_file.r_pc, 0x1000);
+        assert_eq!(machine.reg_file.privilege, Privilege::Supervisor);
+        machine.run_for(2).unwrap();
+        // back in user mode after the faulting instruction, on the user stack
+        assert_eq!(machine.reg_file.r_pc, PC_START + 1);
+        assert_eq!(machine.reg_file.privilege, Privilege::User);
+        assert_eq!(machine.reg_file.read_reg(1).unwrap(), 1);
+        assert_eq!(machine.reg_file.read_reg(6).unwrap(), 0xF000);
+    }
+
+    #[test]
+    fn rti_in_user_mode() {
+        let mut machine = Machine::new();
+        load(&mut machine, &[0x8000]);
+        assert!(matches!(machine.step(), Err(VmError::PrivilegeViolation { pc: PC_START })));
+        assert_eq!(machine.reg_file.r_pc, PC_START);
+        machine.mem.write(0x0100, 0x1000);
+        machine.step().unwrap();
+        assert_eq!(machine.reg_file.r_pc, 0x1000);
+        assert_eq!(machine.reg_file.privilege, Privilege::Supervisor);
+    }
+
+    #[test]
+    fn keyboard_interrupt() {
+        let console = BufferConsole::new("");
+        let mut machine = Machine::with_console(Box::new(console.clone()));
+        load(&mut machine, &[
+            0x2003, // LD R0, IE
+            0xB001, // STI R0, KBSRP
+            0x0FFF, // SPIN BRnzp SPIN
+            0xFE00, // KBSRP
+            0x4000, // IE
+        ]);
+        // keyboard service routine: read the character into R2 and return
+        machine.mem.write(0x0180, 0x1000);
+        machine.mem.load_slice(0x1000, &[
+            0xA401, // LDI R2, KBDRP
+            0x8000, // RTI
+            0xFE02, // KBDRP
+        ]);
+        machine.reg_file.update_reg(6, 0xF000).unwrap();
+        machine.run_for(10).unwrap();
+        assert!(machine.devices.kbsr_ie);
+        assert_eq!(machine.reg_file.r_pc, PC_START + 2);
+        console.push_input("a");
+        machine.step().unwrap();
+        assert_eq!(machine.reg_file.r_pc, 0x1001);
+        assert_eq!(machine.reg_file.priority, KEYBOARD_PRIORITY);
+        assert_eq!(machine.reg_file.read_reg(2).unwrap(), 'a' as u16);
+        machine.step().unwrap();
+        assert_eq!(machine.reg_file.r_pc, PC_START + 2);
+        assert_eq!(machine.reg_file.priority, 0);
+        assert_eq!(machine.reg_file.read_reg(6).unwrap(), 0xF000);
+        // the character was consumed, so no further interrupt
+        machine.step().unwrap();
+        assert_eq!(machine.reg_file.r_pc, PC_START + 2);
+    }
+
+    #[test]
+    fn timer_interrupt() {
+        let mut machine = Machine::new();
+        load(&mut machine, &[0x0FFF]); // SPIN BRnzp SPIN
+        machine.mem.write(0x0181, 0x1000);
+        machine.mem.load_slice(0x1000, &[
+            0b0001001001100001, // ADD R1, R1, #1
+            0x8000,             // RTI
+        ]);
+        machine.reg_file.update_reg(COND_REG, CondFlag::ZRO as u16).unwrap();
+        machine.interrupts.timer = Some(Timer::new(5, 0x81, 6));
+        // fires after instructions 5, 10, 15 and 20, each handler taking two
+        machine.run_for(22).unwrap();
+        assert_eq!(machine.reg_file.read_reg(1).unwrap(), 4);
+        assert_eq!(machine.reg_file.privilege, Privilege::User);
+        assert_eq!(machine.reg_file.r_pc, PC_START);
+    }
+
+    #[test]
+    fn traps_through_bundled_os() {
+        let console = BufferConsole::new("k");
+        let mut machine = Machine::with_console(Box::new(console.clone()));
+        let program = crate::assembler::assemble("
+            .ORIG x3000
+            GETC
+            OUT
+            LEA R0, MSG
+            PUTS
+            LEA R0, PACKED
+            PUTSP
+            IN
+            HALT
+            MSG .STRINGZ \"ok\"
+            PACKED .FILL x6261
+            .FILL x0063
+            .FILL x0000
+            .END
+        ").unwrap();
+        machine.load_image(&program.image).unwrap();
+        machine.load_os().unwrap();
+        assert_eq!(machine.reg_file.r_pc, PC_START);
+        console.push_input("z");
+        assert_eq!(machine.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(console.output(), "kokabcEnter a character: zHalt the program.\n");
+        assert_eq!(machine.reg_file.read_reg(0).unwrap(), 0);
+    }
+
+    #[test]
+    fn bundled_os_reports_illegal_opcode() {
+        let console = BufferConsole::new("");
+        let mut machine = Machine::with_console(Box::new(console.clone()));
+        machine.load_os().unwrap();
+        load(&mut machine, &[0xD000]);
+        assert_eq!(machine.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(console.output(), "\nIllegal opcode.\n");
+    }
+
+    #[test]
+    fn trap_routine_returns_with_ret() {
+        let mut machine = Machine::new();
+        machine.trap_mode = TrapMode::VectorTable;
+        load(&mut machine, &[0xF040]); // TRAP x40
+        machine.mem.write(0x0040, 0x1000);
+        machine.mem.write(0x1000, 0xC1C0); // RET
+        machine.step().unwrap();
+        assert_eq!(machine.reg_file.read_reg(7).unwrap(), PC_START + 1);
+        machine.step().unwrap();
+        assert_eq!(machine.reg_file.r_pc, PC_START + 1);
+    }
+
+    #[test]
+    fn bundled_os_reports_privilege_violation() {
+        let console = BufferConsole::new("");
+        let mut machine = Machine::with_console(Box::new(console.clone()));
+        machine.load_os().unwrap();
+        load(&mut machine, &[0x8000]); // RTI in user mode
+        assert_eq!(machine.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(console.output(), "\nPrivilege mode violation.\n");
+    }
+
+    #[test]
+    fn bundled_os_drops_keyboard_interrupts() {
+        let console = BufferConsole::new("a");
+        let mut machine = Machine::with_console(Box::new(console.clone()));
+        machine.load_os().unwrap();
+        load(&mut machine, &[
+            0x2004,             // LD R0, IE
+            0xB002,             // STI R0, KBSRP
+            0b0001001001100001, // ADD R1, R1, #1
+            0xF025,             // HALT
+            0xFE00,             // KBSRP
+            0x4000,             // IE
+        ]);
+        assert_eq!(machine.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(console.output(), "Halt the program.\n");
+        assert_eq!(machine.reg_file.read_reg(1).unwrap(), 1);
+        // the service routine read the character
+        assert_eq!(machine.devices.kbdr, 'a' as u16);
+    }
+
+    #[test]
+    fn watchpoints_stop_run() {
+        let mut machine = Machine::new();
+        load(&mut machine, &[
+            0b0001110110111111, // LOOP ADD R6, R6, #-1
+            0b0111000110000000, //      STR R0, R6, #0
+            0b0000111111111101, //      BRnzp LOOP
+        ]);
+        machine.reg_file.update_reg(6, 0x3000).unwrap();
+        machine.watchpoints.push(Watchpoint::parse_register("R6 < x2FFE").unwrap());
+        machine.watchpoints.push(Watchpoint::Memory { first: 0x2FF0, last: 0x2FF0, access: WatchAccess::Write });
+        assert_eq!(machine.run().unwrap(), StepOutcome::Watchpoint(0));
+        assert_eq!(machine.reg_file.read_reg(6).unwrap(), 0x2FFD);
+        assert_eq!(machine.watch_hit().unwrap().message, "R6 < x2FFE (now x2FFD)");
+        assert_eq!(machine.run().unwrap(), StepOutcome::Watchpoint(1));
+        assert_eq!(machine.reg_file.r_pc, PC_START + 2);
+        assert_eq!(machine.watch_hit().unwrap().message, "write x2FF0: x0000 -> x0000");
+        assert_eq!(machine.last_accesses().len(), 1);
+    }
+
+    #[test]
+    fn step_back_restores_state() {
+        let mut machine = Machine::new();
+        machine.enable_history(100);
+        load(&mut machine, &[
+            0b0001000000100101, // ADD R0, R0, #5
+            0b0011000000000010, // ST R0, #2
+            0b0001000000111111, // ADD R0, R0, #-1
+            0xF025,             // HALT
+        ]);
+        let start = machine.mem.clone();
+        assert_eq!(machine.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(machine.mem.read(0x3004), 5);
+        // nothing touched the interrupt controller, so no record keeps a copy
+        assert!(machine.history().unwrap().last().unwrap().interrupts.is_none());
+        assert!(machine.step_back());
+        assert!(machine.is_running());
+        assert_eq!(machine.reg_file.r_pc, PC_START + 3);
+        assert!(machine.rewind_to(1));
+        assert_eq!(machine.reg_file.r_pc, PC_START + 1);
+        assert_eq!(machine.mem.read(0x3004), 0);
+        assert!(machine.step_back());
+        assert!(!machine.step_back());
+        assert_eq!(machine.instruction_count(), 0);
+        assert_eq!(machine.reg_file, RegFile::new());
+        assert!(machine.mem == start);
+        // and forward again
+        assert_eq!(machine.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(machine.reg_file.read_reg(0).unwrap(), 4);
+    }
+
+    #[test]
+    fn step_back_restores_the_timer() {
+        let mut machine = Machine::new();
+        machine.enable_history(100);
+        load(&mut machine, &[0x0FFF]); // SPIN BRnzp SPIN
+        machine.mem.write(0x0181, 0x1000);
+        machine.mem.load_slice(0x1000, &[
+            0b0001001001100001, // ADD R1, R1, #1
+            0x8000,             // RTI
+        ]);
+        machine.reg_file.update_reg(COND_REG, CondFlag::ZRO as u16).unwrap();
+        machine.interrupts.timer = Some(Timer::new(5, 0x81, 6));
+        let start = machine.interrupts.clone();
+        machine.run_for(22).unwrap();
+        assert!(machine.rewind_to(0));
+        assert_eq!(machine.interrupts, start);
+        machine.run_for(22).unwrap();
+        assert_eq!(machine.reg_file.read_reg(1).unwrap(), 4);
+    }
+
+    #[test]
+    fn failed_step_stays_at_the_instruction() {
+        let mut machine = Machine::new();
+        machine.enable_history(100);
+        load(&mut machine, &[
+            0b0001000000100101, // ADD R0, R0, #5
+            0x8000,             // RTI (user mode, no handler)
+        ]);
+        machine.step().unwrap();
+        assert!(machine.step().is_err());
+        assert!(machine.is_running());
+        assert_eq!(machine.reg_file.r_pc, PC_START + 1);
+        assert_eq!(machine.history().unwrap().len(), 2);
+        assert!(machine.step_back());
+        assert!(machine.step_back());
+        assert_eq!(machine.reg_file, RegFile::new());
+    }
+
+    #[test]
+    fn counts_cycles() {
+        let mut machine = Machine::with_console(Box::new(BufferConsole::new("")));
+        machine.enable_history(100);
+        machine.enable_timing(TimingModel::new(5));
+        load(&mut machine, &[
+            0b0010000000000011, // LD R0, #3
+            0b1010001000000010, // LDI R1, #2
+            0b0000010000000000, // BRz #0 (taken, LDI loaded 0)
+            0xF025,             // HALT
+            0x3005,             // .FILL x3005
+        ]);
+        assert_eq!(machine.run().unwrap(), StepOutcome::Halted);
+        let timing = machine.timing().unwrap();
+        assert_eq!(timing.opcode(OP_LD).cycles, 15);
+        assert_eq!(timing.opcode(OP_LDI).cycles, 21);
+        assert_eq!(machine.cycles(), 15 + 21 + 10 + 9);
+        assert!(machine.step_back());
+        assert_eq!(machine.cycles(), 15 + 21 + 10);
+        machine.restore(&machine.snapshot());
+        assert_eq!(machine.cycles(), 0);
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let console = BufferConsole::new("ab");
+        let mut machine = Machine::with_console(Box::new(console.clone()));
+        load(&mut machine, &[
+            0xF020, // GETC
+            0xF021, // OUT
+            0xF020, // GETC
+            0xF021, // OUT
+            0xF025, // HALT
+        ]);
+        machine.run_for(2).unwrap();
+        let snapshot = Snapshot::parse(&machine.snapshot().to_bytes()).unwrap();
+        assert_eq!(snapshot.input, b"b".to_vec());
+        assert_eq!(machine.run().unwrap(), StepOutcome::Halted);
+        machine.restore(&snapshot);
+        assert!(machine.is_running());
+        assert_eq!(machine.instruction_count(), 2);
+        assert_eq!(machine.reg_file.r_pc, PC_START + 2);
+        assert_eq!(machine.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(console.output(), "abHalt the program.\nbHalt the program.\n");
+    }
+
+    #[test]
+    fn unknown_trap_is_an_error() {
+        let mut machine = Machine::new();
+        load(&mut machine, &[0xF0FF]);
+        assert!(matches!(machine.step(), Err(VmError::UnknownTrap { vector: 0xFF })));
+    }
+}
@@ -1,43 +1,453 @@
-pub mod memory;
-pub mod register;
-pub mod instruction;
+use lc3_vm_sim::assembler;
+use lc3_vm_sim::coverage::Coverage;
+use lc3_vm_sim::debugger::Debugger;
+use lc3_vm_sim::disassembler;
+use lc3_vm_sim::gdbstub::GdbStub;
+use lc3_vm_sim::loader::ObjectImage;
+use lc3_vm_sim::machine::{Engine, Machine, StepOutcome, TrapMode};
+use lc3_vm_sim::os;
+use lc3_vm_sim::profiler::Profiler;
+use lc3_vm_sim::snapshot::Snapshot;
+use lc3_vm_sim::timing::{TimingModel, DEFAULT_MEMORY_LATENCY};
+use lc3_vm_sim::tracer::{TraceFormat, Tracer};
+use lc3_vm_sim::tracer::diff::{diff_traces, read_trace};
+use lc3_vm_sim::register;
 
 use std::env;
-use std::fs;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, IsTerminal, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::process;
 
 fn main() {
-    // let args: Vec<String> = env::args().collect();
-    // // println!("{:?}", args);
-    // if args.len() <= 1 {
-    //     println!("[ERROR] Invalid number of arguments!");
-    // } else if  args.len() == 2 {
-    //     println!("Run the simulator with a file provided...");
-    //     normal_simulator(&args[1]);
-    //     return;
-    // } else if args.len() == 3 && args[1] == "debugger" {
-    //     println!("Run the simulator with debugging mode...");
-    //     return;
-    // } else if args.len() == 3 && args[1] == "visualizer" {
-    //     println!("Run the simulaotr with WASM bindings for visualizer...");
-    //     return;
-    // } else {
-    //     println!("[ERROR] Invalid number of arguments!");
-    // }
+    let args: Vec<String> = env::args().collect();
+    if args.len() == 3 && args[1] == "assemble" {
+        assemble_file(&args[2]);
+        return;
+    }
+    if (args.len() == 3 || args.len() == 4) && args[1] == "disassemble" {
+        disassemble_file(&args[2], args.get(3));
+        return;
+    }
+    if args.len() == 3 && args[1] == "resume" {
+        resume_snapshot(&args[2]);
+        return;
+    }
+    if args.len() >= 4 && args[1] == "diff" {
+        diff_trace_files(&args[2..]);
+        return;
+    }
+    if args.len() >= 3 && args[1] == "gdb" {
+        gdb_server(&args[2..]);
+        return;
+    }
+    if args.len() >= 3 && (args[1] == "debug" || args[1] == "--debugger") {
+        debug_simulator(&args[2..]);
+        return;
+    }
+    if args.len() >= 2 {
+        println!("Run the simulator with a file provided...");
+        normal_simulator(&args[1..]);
+        return;
+    }
     // let mem = memory::Memory::new();
     // mem.show_content();
     let mut reg_file = register::RegFile::new();
-    reg_file.update_reg(0, 12);
+    reg_file.update_reg(0, 12).expect("R0 is a valid register");
     print!("{}", reg_file);
 }
 
-fn normal_simulator(trace_path: &String) {
-    let contents = fs::read_to_string(trace_path).expect("[ERROR] Unable to open the file!");
-    println!("Code:\n{}", contents);
+/**
+ * assemble an .asm source, printing its diagnostics and exiting if it does
+ * not assemble; returns the source text along with the program
+ */
+fn assemble_source(path: &str) -> (String, assembler::Program) {
+    let contents = fs::read_to_string(path).expect("[ERROR] Unable to open the file!");
+    let color = io::stderr().is_terminal();
+    match assembler::assemble(&contents) {
+        Ok(program) => {
+            for warning in program.warnings.iter() {
+                eprint!("{}", warning.render(path, &contents, color));
+            }
+            (contents, program)
+        },
+        Err(diagnostics) => {
+            for diagnostic in diagnostics.iter() {
+                eprint!("{}", diagnostic.render(path, &contents, color));
+            }
+            let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+            eprintln!("[ERROR] {} failed to assemble with {} error{}", path, errors, if errors == 1 { "" } else { "s" });
+            process::exit(1);
+        },
+    }
+}
+
+// write <file>.obj and <file>.sym next to the source
+fn assemble_file(path: &str) {
+    let (_, program) = assemble_source(path);
+    let obj = Path::new(path).with_extension("obj");
+    let sym = Path::new(path).with_extension("sym");
+    if let Err(e) = program.image.write_file(&obj) {
+        println!("[ERROR] {}: {}", obj.display(), e);
+        process::exit(1);
+    }
+    fs::write(&sym, program.symbols.to_string()).expect("[ERROR] Unable to write the symbol table!");
+    println!("Assembled {} => {}, {}", path, obj.display(), sym.display());
+}
+
+// print the disassembly of an .obj (or .asm) file, labelled from a .sym file if given
+fn disassemble_file(path: &str, sym_path: Option<&String>) {
+    let (image, mut symbols) = if path.ends_with(".asm") {
+        let (_, program) = assemble_source(path);
+        (program.image, Some(program.symbols))
+    } else {
+        match ObjectImage::read_file(path) {
+            Ok(image) => (image, None),
+            Err(e) => {
+                println!("[ERROR] {}: {}", path, e);
+                process::exit(1);
+            },
+        }
+    };
+    if let Some(sym_path) = sym_path {
+        let text = fs::read_to_string(sym_path).expect("[ERROR] Unable to open the symbol table!");
+        symbols = Some(assembler::SymbolTable::parse(&text));
+    }
+    for line in disassembler::disassemble_image(&image, symbols.as_ref()) {
+        println!("{}", line);
+    }
+}
+
+// a file loaded into the machine, kept so reports can refer back to it
+struct LoadedFile {
+    path: String,
+    image: ObjectImage,
+    // the source text and assembled program of an .asm file
+    source: Option<(String, assembler::Program)>,
+}
+
+/**
+ * load one or more .asm / .obj files (e.g. an OS image then a user program)
+ * into a new machine, along with their symbols; `--os` loads the bundled OS
+ * and `--trap-table` runs TRAPs through the trap vector table of a user
+ * supplied one
+ */
+fn load_machine(args: &[String]) -> (Machine, assembler::SymbolTable, Vec<LoadedFile>) {
+    let mut machine = Machine::new();
+    let mut symbols = assembler::SymbolTable::new();
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--os" => {
+                if let Err(e) = machine.load_os() {
+                    println!("[ERROR] bundled OS: {}", e);
+                    process::exit(1);
+                }
+                for (name, address) in os::os_program().symbols.iter() {
+                    symbols.insert(name, address);
+                }
+            },
+            "--trap-table" => machine.trap_mode = TrapMode::VectorTable,
+            _ => paths.push(arg),
+        }
+    }
+    let mut files = Vec::new();
+    for path in paths {
+        let (image, source) = if path.ends_with(".asm") {
+            let (text, program) = assemble_source(path);
+            for (name, address) in program.symbols.iter() {
+                symbols.insert(name, address);
+            }
+            (program.image.clone(), Some((text, program)))
+        } else {
+            // pick up the symbol table written next to the object file, if any
+            if let Ok(text) = fs::read_to_string(Path::new(path).with_extension("sym")) {
+                for (name, address) in assembler::SymbolTable::parse(&text).iter() {
+                    symbols.insert(name, address);
+                }
+            }
+            match ObjectImage::read_file(path) {
+                Ok(image) => (image, None),
+                Err(e) => {
+                    println!("[ERROR] {}: {}", path, e);
+                    process::exit(1);
+                },
+            }
+        };
+        if let Err(e) = machine.load_image(&image) {
+            println!("[ERROR] {}: {}", path, e);
+            process::exit(1);
+        }
+        files.push(LoadedFile { path: path.clone(), image, source });
+    }
+    (machine, symbols, files)
+}
 
+// remove `<flag> <value>` from the arguments, returning the value
+fn take_option(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    match args.iter().position(|arg| arg == flag) {
+        Some(i) if i + 1 < args.len() => {
+            let value = args.remove(i + 1);
+            args.remove(i);
+            Some(value)
+        },
+        Some(_) => {
+            println!("[ERROR] {} needs a value", flag);
+            process::exit(1);
+        },
+        None => None,
+    }
 }
 
-// let file = &args[1];
-// let contents = fs::read_to_string(file)
-//     .expect("unable to open the file!");
+/**
+ * run the loaded files; `--trace <file>` writes an execution trace, as JSON
+ * Lines when the file name ends in .jsonl and as text otherwise, and
+ * `--coverage <name>` writes an lcov tracefile to <name>.info and annotated
+ * listings to <name>.cov, and `--profile <name>` writes a flat profile to
+ * <name>.prof, the call graph to <name>.dot and folded stacks to
+ * <name>.folded; `--timing` prints cycles and CPI per opcode at the end, with
+ * memory accesses taking `--memory-latency <n>` cycles; `--datapath <file>`
+ * runs on the datapath engine and writes every state it goes through to the
+ * file as JSON Lines
+ */
+fn normal_simulator(args: &[String]) {
+    let mut args = args.to_vec();
+    let trace_path = take_option(&mut args, "--trace");
+    let coverage_name = take_option(&mut args, "--coverage");
+    let profile_name = take_option(&mut args, "--profile");
+    let datapath_path = take_option(&mut args, "--datapath");
+    let memory_latency = take_option(&mut args, "--memory-latency").map(|n| n.parse().unwrap_or_else(|_| {
+        println!("[ERROR] --memory-latency needs a number of cycles");
+        process::exit(1);
+    }));
+    let timing = match args.iter().position(|arg| arg == "--timing") {
+        Some(i) => {
+            args.remove(i);
+            true
+        },
+        None => memory_latency.is_some(),
+    };
+    let (mut machine, symbols, files) = load_machine(&args);
+    if timing {
+        machine.enable_timing(TimingModel::new(memory_latency.unwrap_or(DEFAULT_MEMORY_LATENCY)));
+    }
+    let mut tracer = trace_path.map(|path| {
+        let file = File::create(&path).unwrap_or_else(|e| {
+            println!("[ERROR] {}: {}", path, e);
+            process::exit(1);
+        });
+        let format = if path.ends_with(".jsonl") { TraceFormat::Json } else { TraceFormat::Text };
+        Tracer::new(BufWriter::new(file), format).with_symbols(symbols.clone())
+    });
+    let mut cycles = datapath_path.map(|path| {
+        machine.engine = Engine::Datapath;
+        BufWriter::new(File::create(&path).unwrap_or_else(|e| {
+            println!("[ERROR] {}: {}", path, e);
+            process::exit(1);
+        }))
+    });
+    let mut coverage = Coverage::new();
+    let mut profiler = Profiler::new().with_symbols(symbols.clone());
+    let result = loop {
+        let before = machine.reg_file.clone();
+        let outcome = match tracer.as_mut() {
+            Some(tracer) => tracer.step(&mut machine),
+            None => machine.step(),
+        };
+        if let Some(cycles) = cycles.as_mut() {
+            for cycle in machine.last_cycles() {
+                writeln!(cycles, "{}", cycle.to_json()).expect("[ERROR] Unable to write the cycles!");
+            }
+        }
+        match outcome {
+            Ok(StepOutcome::WaitingForInput) => break Ok(()),
+            Ok(outcome) => {
+                coverage.record(&machine, &before);
+                profiler.record(&machine);
+                if outcome != StepOutcome::Continue {
+                    break Ok(());
+                }
+            },
+            Err(e) => break Err(e),
+        }
+    };
+    if let Some(tracer) = tracer {
+        tracer.into_inner().flush().expect("[ERROR] Unable to write the trace!");
+    }
+    if let Some(cycles) = cycles.as_mut() {
+        cycles.flush().expect("[ERROR] Unable to write the cycles!");
+    }
+    if let Some(name) = coverage_name {
+        write_coverage(&name, &coverage, &files, &symbols);
+    }
+    if let Some(timing) = machine.timing() {
+        print!("{}", timing.report());
+    }
+    if let Some(name) = profile_name {
+        write_reports(&[
+            (format!("{}.prof", name), profiler.flat_profile()),
+            (format!("{}.dot", name), profiler.call_graph_dot()),
+            (format!("{}.folded", name), profiler.folded_stacks()),
+        ]);
+    }
+    if let Err(e) = result {
+        println!("[ERROR] {}", e);
+        process::exit(1);
+    }
+}
+
+// write the lcov tracefile for the .asm files and a listing for every file
+fn write_coverage(name: &str, coverage: &Coverage, files: &[LoadedFile], symbols: &assembler::SymbolTable) {
+    let mut lcov = String::new();
+    let mut listing = String::new();
+    for file in files {
+        listing += &format!("==> {} <==\n", file.path);
+        match &file.source {
+            Some((text, program)) => {
+                lcov += &coverage.lcov(&file.path, program);
+                listing += &coverage.annotate_source(text, program);
+            },
+            None => listing += &coverage.annotate_image(&file.image, Some(symbols)),
+        }
+    }
+    write_reports(&[(format!("{}.info", name), lcov), (format!("{}.cov", name), listing)]);
+}
 
-// println!("code:\n{} ", contents);
\ No newline at end of file
+fn write_reports(reports: &[(String, String)]) {
+    for (path, contents) in reports {
+        if let Err(e) = fs::write(path, contents) {
+            println!("[ERROR] {}: {}", path, e);
+            process::exit(1);
+        }
+    }
+}
+
+// continue running a machine saved with the debugger's `save` command
+fn resume_snapshot(path: &str) {
+    let snapshot = match Snapshot::read_file(path) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            println!("[ERROR] {}: {}", path, e);
+            process::exit(1);
+        },
+    };
+    let mut machine = Machine::new();
+    machine.restore(&snapshot);
+    if let Err(e) = machine.run() {
+        println!("[ERROR] {}", e);
+        process::exit(1);
+    }
+}
+
+/**
+ * compare two traces written with `--trace`: `diff <expected> <actual>
+ * [--context <n>]`; exits with 1 and reports the first divergence if they
+ * differ
+ */
+fn diff_trace_files(args: &[String]) {
+    let context = match args.get(2).map(String::as_str) {
+        None => 5,
+        Some("--context") => match args.get(3).and_then(|n| n.parse().ok()) {
+            Some(n) if args.len() == 4 => n,
+            _ => {
+                println!("[ERROR] --context needs a number of steps");
+                process::exit(2);
+            },
+        },
+        Some(arg) => {
+            println!("[ERROR] unexpected argument `{}`", arg);
+            process::exit(2);
+        },
+    };
+    let read = |path: &String| {
+        let text = fs::read_to_string(path).unwrap_or_else(|e| {
+            println!("[ERROR] {}: {}", path, e);
+            process::exit(2);
+        });
+        read_trace(&text).unwrap_or_else(|e| {
+            println!("[ERROR] {}: {}", path, e);
+            process::exit(2);
+        })
+    };
+    let (expected, actual) = (read(&args[0]), read(&args[1]));
+    match diff_traces(&expected, &actual, context) {
+        Some(divergence) => {
+            print!("{}", divergence);
+            process::exit(1);
+        },
+        None => println!("traces match ({} steps)", expected.len()),
+    }
+}
+
+// read debugger commands from stdin until `quit` or end of input
+fn debug_simulator(args: &[String]) {
+    let (machine, symbols, _) = load_machine(args);
+    let mut debugger = Debugger::new(machine, symbols);
+    println!("LC-3 debugger, type `help` for a list of commands");
+    print!("{}", debugger.execute("list"));
+    let stdin = io::stdin();
+    let mut line = String::new();
+    while !debugger.quit_requested() {
+        print!("(lc3) ");
+        io::stdout().flush().expect("[ERROR] Unable to write to stdout!");
+        line.clear();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => print!("{}", debugger.execute(&line)),
+        }
+    }
+}
+
+// remove a socket left behind by an earlier session, anything else at the path is left alone
+#[cfg(unix)]
+fn remove_stale_socket(path: &str) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse, format!("address in use: {} is not a socket", path))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/**
+ * serve the loaded files to a gdb client over the remote serial protocol:
+ * `gdb [--port <n>] <files>` listens on 127.0.0.1 (port 1234 by default) and
+ * `--socket <path>` on a Unix socket instead, for one session
+ */
+fn gdb_server(args: &[String]) {
+    let mut args = args.to_vec();
+    let port = take_option(&mut args, "--port").map(|port| port.parse::<u16>().unwrap_or_else(|_| {
+        println!("[ERROR] --port needs a port number");
+        process::exit(1);
+    }));
+    let socket = take_option(&mut args, "--socket");
+    let (machine, _, _) = load_machine(&args);
+    let mut stub = GdbStub::new(machine);
+    let result = match socket {
+        #[cfg(unix)]
+        Some(path) => {
+            remove_stale_socket(&path).and_then(|_| std::os::unix::net::UnixListener::bind(&path)).and_then(|listener| {
+                println!("Waiting for gdb on {} (target remote {})", path, path);
+                let (mut conn, _) = listener.accept()?;
+                stub.serve(&mut conn)
+            })
+        },
+        #[cfg(not(unix))]
+        Some(_) => {
+            println!("[ERROR] Unix sockets are not supported on this platform");
+            process::exit(1);
+        },
+        None => TcpListener::bind(("127.0.0.1", port.unwrap_or(1234))).and_then(|listener| {
+            let address = listener.local_addr()?;
+            println!("Waiting for gdb on {} (target remote {})", address, address);
+            let (mut conn, _) = listener.accept()?;
+            stub.serve(&mut conn)
+        }),
+    };
+    if let Err(e) = result {
+        println!("[ERROR] gdb connection: {}", e);
+        process::exit(1);
+    }
+}
@@ -4,13 +4,35 @@ This is the LC-3 Memory Module
 use std::fmt;
 use prettytable::*;
 
-// define the maximum size for memeory
-pub const MEMORY_SIZE: usize = std::u16::MAX as usize;
+// define the size of memory: one word for every 16-bit address x0000-xFFFF
+pub const MEMORY_SIZE: usize = 1 << 16;
 
-// define memory and implement it
-#[derive(Copy)]
+// anything instructions can load from and store to
+pub trait Bus {
+    fn read(&mut self, addr: u16) -> u16;
+    fn write(&mut self, addr: u16, value: u16);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+// one load or store made by a running program, `old` is the value a store replaced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub kind: AccessKind,
+    pub address: u16,
+    pub value: u16,
+    pub old: u16,
+}
+
+// define memory and implement it, the cells live on the heap so moving a
+// Memory around only moves a pointer
+#[derive(Clone, PartialEq, Eq)]
 pub struct Memory {
-    pub cells: [u16; MEMORY_SIZE],
+    pub cells: Box<[u16]>,
 }
 
 impl Memory {
@@ -18,7 +40,7 @@ impl Memory {
     // initialize a memory block
     pub fn new() -> Memory {
         Memory {
-            cells: [0; MEMORY_SIZE],
+            cells: vec![0; MEMORY_SIZE].into_boxed_slice(),
         }
     }
 
@@ -32,11 +54,41 @@ impl Memory {
         self.cells[addr as usize] = value;
     }
 
+    // copy words into memory starting at an address, wrapping past xFFFF like the CPU does
+    pub fn load_slice(&mut self, start: u16, words: &[u16]) {
+        let start = start as usize;
+        let first = words.len().min(MEMORY_SIZE - start);
+        self.cells[start..start + first].copy_from_slice(&words[..first]);
+        for chunk in words[first..].chunks(MEMORY_SIZE) {
+            self.cells[..chunk.len()].copy_from_slice(chunk);
+        }
+    }
+
+    // up to `len` words starting at an address, cut short at the end of memory
+    pub fn read_range(&self, start: u16, len: usize) -> &[u16] {
+        let start = start as usize;
+        &self.cells[start..start.saturating_add(len).min(MEMORY_SIZE)]
+    }
+
+    // (address, value) of every cell that is not zero
+    pub fn iter_nonzero(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.cells.iter().enumerate()
+            .filter(|(_, value)| **value != 0)
+            .map(|(addr, value)| (addr as u16, *value))
+    }
+
+    // set `len` words starting at an address to a value, cut short at the end of memory
+    pub fn fill(&mut self, start: u16, len: usize, value: u16) {
+        let start = start as usize;
+        let end = start.saturating_add(len).min(MEMORY_SIZE);
+        self.cells[start..end].fill(value);
+    }
+
     // show the memory content in the terminal using a pretty table
     pub fn show_content(&self) {
         let mut table = prettytable::Table::new();
         table.add_row(row!["address", "data"]);
-        let mut addr = 0 as usize;
+        let mut addr = 0_usize;
         while addr < MEMORY_SIZE {
             table.add_row(prettytable::Row::new(vec![
                 Cell::new(format!("0x{:x}", addr).as_str()),
@@ -48,10 +100,25 @@ impl Memory {
     }
 }
 
-// implement clone trait for memory 
-impl Clone for Memory {
-    fn clone(&self) -> Memory {
-        *self
+impl Bus for Memory {
+    fn read(&mut self, addr: u16) -> u16 {
+        Memory::read(self, addr)
+    }
+
+    fn write(&mut self, addr: u16, value: u16) {
+        Memory::write(self, addr, value)
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Memory {
+        Memory::new()
+    }
+}
+
+impl fmt::Debug for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Memory {{ {} non-zero cells }}", self.iter_nonzero().count())
     }
 }
 
@@ -60,9 +127,9 @@ impl fmt::Display for Memory {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "Displaying memory content...\n=====================\n")?;
         for i in 0..self.cells.len() {
-            write!(f, "address 0x{:x?} => 0x{:x?}\n", i as u16, self.cells[i] as u16)?;
+            writeln!(f, "address 0x{:x?} => 0x{:x?}", i as u16, self.cells[i])?;
         }
-        write!(f, "=====================\n")?;
+        writeln!(f, "=====================")?;
         Ok(())
     }
 }
@@ -82,4 +149,47 @@ mod memory_test {
         assert_eq!(value, 10);
     }
 
+    #[test]
+    fn last_address() {
+        let mut mem = Memory::new();
+        mem.write(0xFFFF, 0xBEEF);
+        assert_eq!(mem.read(0xFFFF), 0xBEEF);
+        assert_eq!(mem.cells.len(), 65536);
+    }
+
+    #[test]
+    fn clone_is_independent() {
+        let mut mem = Memory::new();
+        mem.write(0x3000, 1);
+        let copy = mem.clone();
+        mem.write(0x3000, 2);
+        assert_eq!(copy.read(0x3000), 1);
+    }
+
+    #[test]
+    fn bulk_access() {
+        let mut mem = Memory::new();
+        mem.load_slice(0x3000, &[1, 2, 3]);
+        assert_eq!(mem.read_range(0x3000, 4), &[1, 2, 3, 0]);
+        assert_eq!(mem.read_range(0xFFFE, 10).len(), 2);
+        mem.load_slice(0xFFFF, &[9, 8]);
+        assert_eq!(mem.read(0xFFFF), 9);
+        assert_eq!(mem.read(0x0000), 8);
+        mem.fill(0x4000, 2, 7);
+        let nonzero: Vec<(u16, u16)> = mem.iter_nonzero().collect();
+        assert_eq!(nonzero, vec![
+            (0x0000, 8), (0x3000, 1), (0x3001, 2), (0x3002, 3), (0x4000, 7), (0x4001, 7), (0xFFFF, 9),
+        ]);
+    }
+
+    #[test]
+    fn bulk_access_at_the_top_of_memory() {
+        let mut mem = Memory::new();
+        mem.fill(0xFFF0, 0x100, 5);
+        assert_eq!(mem.read_range(0xFFF0, 0x100), &[5; 16]);
+        assert_eq!(mem.read(0x0000), 0);
+        mem.fill(0xFFFF, usize::MAX, 6);
+        assert_eq!(mem.read_range(0xFFFF, usize::MAX), &[6]);
+    }
+
 }
\ No newline at end of file
@@ -0,0 +1,187 @@
+; LC-3 operating system image: the trap vector table, the interrupt vector
+; table and the service routines behind them, talking to the devices through
+; the memory-mapped registers in xFE00-xFFFF
+;
+; assembled by the simulator's own assembler and loaded with `--os`
+
+        .ORIG x0000
+
+; trap vector table x0000-x00FF, unused entries are left as x0000
+        .BLKW x20
+        .FILL TRAP_GETC     ; x20
+        .FILL TRAP_OUT      ; x21
+        .FILL TRAP_PUTS     ; x22
+        .FILL TRAP_IN       ; x23
+        .FILL TRAP_PUTSP    ; x24
+        .FILL TRAP_HALT     ; x25
+        .BLKW xDA
+
+; interrupt vector table x0100-x01FF
+        .FILL EXC_PRIVILEGE ; x100 privilege mode violation
+        .FILL EXC_ILLEGAL   ; x101 illegal opcode
+        .BLKW x7E
+        .FILL KBD_ISR       ; x180 keyboard
+        .BLKW x7F
+
+; service routines from x0200
+
+; GETC: read one character into R0 without echoing it
+TRAP_GETC
+        LDI R0, OS_KBSR
+        BRzp TRAP_GETC
+        LDI R0, OS_KBDR
+        RTI
+
+; OUT: write the character in R0
+TRAP_OUT
+        ST R7, OUT_R7
+        JSR WRITE_CHAR
+        LD R7, OUT_R7
+        RTI
+
+; PUTS: write the string at R0, one character per word
+TRAP_PUTS
+        ST R0, PUTS_R0
+        ST R1, PUTS_R1
+        ST R7, PUTS_R7
+        ADD R1, R0, #0
+PUTS_LOOP
+        LDR R0, R1, #0
+        BRz PUTS_DONE
+        JSR WRITE_CHAR
+        ADD R1, R1, #1
+        BRnzp PUTS_LOOP
+PUTS_DONE
+        LD R0, PUTS_R0
+        LD R1, PUTS_R1
+        LD R7, PUTS_R7
+        RTI
+
+; IN: prompt, read one character into R0 and echo it
+TRAP_IN
+        ST R1, IN_R1
+        ST R7, IN_R7
+        LEA R1, IN_PROMPT
+IN_PROMPT_LOOP
+        LDR R0, R1, #0
+        BRz IN_READ
+        JSR WRITE_CHAR
+        ADD R1, R1, #1
+        BRnzp IN_PROMPT_LOOP
+IN_READ
+        LDI R0, OS_KBSR
+        BRzp IN_READ
+        LDI R0, OS_KBDR
+        JSR WRITE_CHAR
+        LD R1, IN_R1
+        LD R7, IN_R7
+        RTI
+
+; PUTSP: write the string at R0, two characters per word, low byte first
+TRAP_PUTSP
+        ST R0, PUTSP_R0
+        ST R1, PUTSP_R1
+        ST R2, PUTSP_R2
+        ST R3, PUTSP_R3
+        ST R7, PUTSP_R7
+        ADD R1, R0, #0
+PUTSP_LOOP
+        LDR R2, R1, #0
+        BRz PUTSP_DONE
+        LD R3, LOW_BYTE
+        AND R0, R2, R3
+        JSR WRITE_CHAR
+        ; shift the high byte down by repeated doubling into R0
+        AND R0, R0, #0
+        ADD R3, R0, #8
+PUTSP_SHIFT
+        ADD R0, R0, R0
+        ADD R2, R2, #0
+        BRzp PUTSP_ZERO_BIT
+        ADD R0, R0, #1
+PUTSP_ZERO_BIT
+        ADD R2, R2, R2
+        ADD R3, R3, #-1
+        BRp PUTSP_SHIFT
+        ADD R0, R0, #0
+        BRz PUTSP_NEXT
+        JSR WRITE_CHAR
+PUTSP_NEXT
+        ADD R1, R1, #1
+        BRnzp PUTSP_LOOP
+PUTSP_DONE
+        LD R0, PUTSP_R0
+        LD R1, PUTSP_R1
+        LD R2, PUTSP_R2
+        LD R3, PUTSP_R3
+        LD R7, PUTSP_R7
+        RTI
+
+; HALT: say so and stop the clock by clearing MCR[15]
+TRAP_HALT
+        LEA R0, HALT_MESSAGE
+        PUTS
+        AND R0, R0, #0
+        STI R0, OS_MCR
+        BRnzp TRAP_HALT
+
+; privilege mode violation: report and stop the machine
+EXC_PRIVILEGE
+        LEA R0, PRIVILEGE_MESSAGE
+        PUTS
+        AND R0, R0, #0
+        STI R0, OS_MCR
+        BRnzp EXC_PRIVILEGE
+
+; illegal opcode: report and stop the machine
+EXC_ILLEGAL
+        LEA R0, ILLEGAL_MESSAGE
+        PUTS
+        AND R0, R0, #0
+        STI R0, OS_MCR
+        BRnzp EXC_ILLEGAL
+
+; keyboard interrupt: read and drop the character so the request is cleared
+KBD_ISR
+        ST R0, KBD_R0
+        LDI R0, OS_KBDR
+        LD R0, KBD_R0
+        RTI
+
+; write R0 to the display once it is ready, R0 is preserved
+WRITE_CHAR
+        ST R1, WRITE_R1
+WRITE_WAIT
+        LDI R1, OS_DSR
+        BRzp WRITE_WAIT
+        STI R0, OS_DDR
+        LD R1, WRITE_R1
+        RET
+
+OS_KBSR .FILL xFE00
+OS_KBDR .FILL xFE02
+OS_DSR  .FILL xFE04
+OS_DDR  .FILL xFE06
+OS_MCR  .FILL xFFFE
+LOW_BYTE .FILL x00FF
+
+OUT_R7   .BLKW 1
+PUTS_R0  .BLKW 1
+PUTS_R1  .BLKW 1
+PUTS_R7  .BLKW 1
+IN_R1    .BLKW 1
+IN_R7    .BLKW 1
+PUTSP_R0 .BLKW 1
+PUTSP_R1 .BLKW 1
+PUTSP_R2 .BLKW 1
+PUTSP_R3 .BLKW 1
+PUTSP_R7 .BLKW 1
+WRITE_R1 .BLKW 1
+KBD_R0   .BLKW 1
+
+IN_PROMPT         .STRINGZ "Enter a character: "
+HALT_MESSAGE      .STRINGZ "Halt the program.\n"
+PRIVILEGE_MESSAGE .STRINGZ "\nPrivilege mode violation.\n"
+ILLEGAL_MESSAGE   .STRINGZ "\nIllegal opcode.\n"
+
+        .END
@@ -0,0 +1,41 @@
+/*
+This is the LC-3 OS Module, the bundled operating system image providing the
+trap and exception service routines as LC-3 code
+*/
+use crate::assembler::{assemble, Program};
+use crate::loader::ObjectImage;
+
+// source of the bundled operating system, see lc3os.asm
+pub const OS_SOURCE: &str = include_str!("lc3os.asm");
+
+// the bundled operating system assembled with its symbols
+pub fn os_program() -> Program {
+    assemble(OS_SOURCE).expect("the bundled OS assembles")
+}
+
+// the bundled operating system as an object image starting at x0000
+pub fn os_image() -> ObjectImage {
+    os_program().image
+}
+
+#[cfg(test)]
+mod os_test {
+
+    use super::*;
+    use crate::instruction::trap::*;
+
+    #[test]
+    fn assembles_cleanly() {
+        let program = os_program();
+        assert!(program.warnings.is_empty());
+        assert_eq!(program.image.origin, 0x0000);
+        assert_eq!(program.symbols.get("TRAP_GETC"), Some(0x0200));
+        for (vector, label) in [(GETC, "TRAP_GETC"), (OUT, "TRAP_OUT"), (PUTS, "TRAP_PUTS"),
+                                (IN, "TRAP_IN"), (PUTSP, "TRAP_PUTSP"), (HALT, "TRAP_HALT")] {
+            assert_eq!(Some(program.image.words[vector as usize]), program.symbols.get(label));
+        }
+        assert_eq!(program.image.words[0x00], 0);
+        assert_eq!(Some(program.image.words[0x180]), program.symbols.get("KBD_ISR"));
+        assert_eq!(program.image.words[0x1FF], 0);
+    }
+}
@@ -0,0 +1,339 @@
+/*
+This is the LC-3 Profiler Module, following subroutine calls and returns to
+attribute executed instructions to subroutines, exported as a flat profile,
+a call graph and folded stacks for flamegraphs
+*/
+use crate::assembler::SymbolTable;
+use crate::error::VmError;
+use crate::instruction::decode::*;
+use crate::machine::{Machine, StepOutcome, TrapMode};
+use crate::register::Privilege;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// what the profile says about one subroutine, named by its entry address
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FunctionProfile {
+    pub address: u16,
+    pub calls: u64,
+    // instructions executed in the subroutine itself
+    pub exclusive: u64,
+    // instructions executed while it was on the call stack, callees included
+    pub inclusive: u64,
+    // most activations on the call stack at once, above 1 for recursion
+    pub max_depth: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    symbols: Option<SymbolTable>,
+    // entry addresses of the active subroutines, outermost first
+    stack: Vec<u16>,
+    // where each call on the stack returns to, the outermost has none
+    returns: Vec<u16>,
+    // activations of each subroutine on the stack
+    active: BTreeMap<u16, usize>,
+    functions: BTreeMap<u16, FunctionProfile>,
+    // (caller, callee) => calls
+    edges: BTreeMap<(u16, u16), u64>,
+    // call stack => instructions executed with exactly that stack
+    folded: BTreeMap<Vec<u16>, u64>,
+    total: u64,
+    // PC and privilege after the last instruction, a fetch elsewhere means an interrupt was taken
+    next: Option<(u16, Privilege)>,
+}
+
+impl Profiler {
+
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    // name subroutines by their labels
+    pub fn with_symbols(mut self, symbols: SymbolTable) -> Profiler {
+        self.symbols = Some(symbols);
+        self
+    }
+
+    fn enter(&mut self, function: u16) {
+        let active = self.active.entry(function).or_insert(0);
+        *active += 1;
+        let profile = self.functions.entry(function).or_insert(FunctionProfile { address: function, ..Default::default() });
+        profile.calls += 1;
+        profile.max_depth = profile.max_depth.max(*active);
+        self.stack.push(function);
+    }
+
+    fn call(&mut self, caller: u16, callee: u16, ret: u16) {
+        *self.edges.entry((caller, callee)).or_insert(0) += 1;
+        self.enter(callee);
+        self.returns.push(ret);
+    }
+
+    // unwind to the call returning to `target`, or leave the innermost one
+    fn return_to(&mut self, target: u16) {
+        match self.returns.iter().rposition(|ret| *ret == target) {
+            Some(i) => {
+                while self.returns.len() > i {
+                    self.leave();
+                }
+            },
+            None if self.stack.len() > 1 => self.leave(),
+            None => {},
+        }
+    }
+
+    fn leave(&mut self) {
+        if let Some(function) = self.stack.pop() {
+            self.returns.pop();
+            if let Some(active) = self.active.get_mut(&function) {
+                *active -= 1;
+                if *active == 0 {
+                    self.active.remove(&function);
+                }
+            }
+        }
+    }
+
+    /**
+     * attribute the instruction the machine just executed, then follow it
+     * into a subroutine (JSR, JSRR, TRAP through the vector table) or an
+     * exception routine, or back out of one (RET, RTI); a return unwinds to
+     * the call it returns to, and an interrupt taken before the fetch counts
+     * as a call from the interrupted code
+     */
+    pub fn record(&mut self, machine: &Machine) {
+        let (pc, word) = match machine.last_instruction() {
+            Some(fetched) => fetched,
+            None => return,
+        };
+        let privilege = match self.next {
+            Some((expected, privilege)) if expected == pc => privilege,
+            Some((expected, _)) => {
+                let caller = *self.stack.last().expect("the stack holds the entry point");
+                self.call(caller, pc, expected);
+                Privilege::Supervisor
+            },
+            None => {
+                // the program's entry point
+                self.enter(pc);
+                machine.reg_file.privilege
+            },
+        };
+        self.total += 1;
+        let top = *self.stack.last().expect("the stack holds the entry point");
+        if let Some(profile) = self.functions.get_mut(&top) {
+            profile.exclusive += 1;
+        }
+        for function in self.active.keys() {
+            if let Some(profile) = self.functions.get_mut(function) {
+                profile.inclusive += 1;
+            }
+        }
+        match self.folded.get_mut(&self.stack[..]) {
+            Some(count) => *count += 1,
+            None => {
+                self.folded.insert(self.stack.clone(), 1);
+            },
+        }
+
+        let target = machine.reg_file.r_pc;
+        let is_call = match decode(word) {
+            Ok(Instruction::Jsr { .. }) | Ok(Instruction::Jsrr { .. }) => true,
+            Ok(Instruction::Trap { .. }) => machine.trap_mode == TrapMode::VectorTable,
+            Ok(Instruction::Ret) => {
+                self.return_to(target);
+                false
+            },
+            Ok(Instruction::Rti) if privilege == Privilege::Supervisor => {
+                self.return_to(target);
+                false
+            },
+            // an illegal opcode and RTI in user mode enter an exception routine
+            Ok(Instruction::Rti) | Err(_) => true,
+            Ok(_) => false,
+        };
+        if is_call {
+            self.call(top, target, pc.wrapping_add(1));
+        }
+        self.next = Some((target, machine.reg_file.privilege));
+    }
+
+    // step the machine once, profiling the instruction it executed
+    pub fn step(&mut self, machine: &mut Machine) -> Result<StepOutcome, VmError> {
+        let outcome = machine.step()?;
+        if outcome != StepOutcome::WaitingForInput {
+            self.record(machine);
+        }
+        Ok(outcome)
+    }
+
+    // like `Machine::run`, profiling every instruction
+    pub fn run(&mut self, machine: &mut Machine) -> Result<StepOutcome, VmError> {
+        loop {
+            let outcome = self.step(machine)?;
+            if outcome != StepOutcome::Continue {
+                return Ok(outcome);
+            }
+        }
+    }
+
+    // instructions profiled so far
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    // every subroutine seen, most exclusive instructions first
+    pub fn functions(&self) -> Vec<FunctionProfile> {
+        let mut functions: Vec<FunctionProfile> = self.functions.values().copied().collect();
+        functions.sort_by(|a, b| b.exclusive.cmp(&a.exclusive).then(a.address.cmp(&b.address)));
+        functions
+    }
+
+    // the label at a subroutine's entry, or its address
+    pub fn name(&self, address: u16) -> String {
+        match self.symbols.as_ref().and_then(|symbols| symbols.name_of(address)) {
+            Some(name) => name.to_string(),
+            None => format!("x{:04X}", address),
+        }
+    }
+
+    // a gprof-style table, one line per subroutine
+    pub fn flat_profile(&self) -> String {
+        let mut out = format!("{:>7} {:>10} {:>10} {:>8} {:>6}  {}\n", "self%", "self", "total", "calls", "depth", "subroutine");
+        for f in self.functions() {
+            let share = if self.total == 0 { 0.0 } else { f.exclusive as f64 * 100.0 / self.total as f64 };
+            writeln!(
+                out, "{:>6.2}% {:>10} {:>10} {:>8} {:>6}  {}",
+                share, f.exclusive, f.inclusive, f.calls, f.max_depth, self.name(f.address),
+            ).unwrap();
+        }
+        out
+    }
+
+    // the call graph in Graphviz DOT, edges labelled with their call counts
+    pub fn call_graph_dot(&self) -> String {
+        let mut out = String::from("digraph calls {\n    node [shape=box];\n");
+        for f in self.functions.values() {
+            writeln!(
+                out, "    n{:04X} [label=\"{}\\ncalls {}, self {}, total {}\"];",
+                f.address, self.name(f.address), f.calls, f.exclusive, f.inclusive,
+            ).unwrap();
+        }
+        for ((caller, callee), calls) in self.edges.iter() {
+            writeln!(out, "    n{:04X} -> n{:04X} [label=\"{}\"];", caller, callee, calls).unwrap();
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    // `outer;inner count` lines, as read by flamegraph.pl and inferno
+    pub fn folded_stacks(&self) -> String {
+        let mut out = String::new();
+        for (stack, count) in self.folded.iter() {
+            let names: Vec<String> = stack.iter().map(|address| self.name(*address)).collect();
+            writeln!(out, "{} {}", names.join(";"), count).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod profiler_test {
+
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::console::BufferConsole;
+
+    // MAIN calls COUNT (recursing twice) and then LEAF
+    fn profile() -> Profiler {
+        let program = assemble("
+            .ORIG x3000
+            MAIN    LD R6, STACK
+                    AND R1, R1, #0
+                    ADD R1, R1, #2
+                    JSR COUNT
+                    JSR LEAF
+                    HALT
+            STACK   .FILL x4000
+            COUNT   ADD R6, R6, #-1
+                    STR R7, R6, #0
+                    ADD R1, R1, #-1
+                    BRn DONE
+                    JSR COUNT
+            DONE    LDR R7, R6, #0
+                    ADD R6, R6, #1
+                    RET
+            LEAF    RET
+            .END
+        ").unwrap();
+        let mut machine = Machine::with_console(Box::new(BufferConsole::new("")));
+        machine.load_image(&program.image).unwrap();
+        let mut profiler = Profiler::new().with_symbols(program.symbols);
+        assert_eq!(profiler.run(&mut machine).unwrap(), StepOutcome::Halted);
+        profiler
+    }
+
+    #[test]
+    fn attributes_instructions() {
+        let profiler = profile();
+        let functions = profiler.functions();
+        assert_eq!(profiler.total(), 30);
+        assert_eq!(functions[0], FunctionProfile { address: 0x3007, calls: 3, exclusive: 23, inclusive: 23, max_depth: 3 });
+        assert_eq!(functions[1], FunctionProfile { address: 0x3000, calls: 1, exclusive: 6, inclusive: 30, max_depth: 1 });
+        assert_eq!(functions[2], FunctionProfile { address: 0x300F, calls: 1, exclusive: 1, inclusive: 1, max_depth: 1 });
+        assert_eq!(profiler.name(0x3007), "COUNT");
+    }
+
+    #[test]
+    fn exports() {
+        let profiler = profile();
+        let flat = profiler.flat_profile();
+        assert_eq!(flat.lines().nth(1).unwrap(), " 76.67%         23         23        3      3  COUNT");
+        let dot = profiler.call_graph_dot();
+        assert!(dot.contains("    n3000 -> n3007 [label=\"1\"];\n    n3000 -> n300F [label=\"1\"];\n    n3007 -> n3007 [label=\"2\"];"));
+        assert!(dot.contains("n3007 [label=\"COUNT\\ncalls 3, self 23, total 23\"];"));
+        assert_eq!(profiler.folded_stacks(), "\
+MAIN 6
+MAIN;COUNT 8
+MAIN;COUNT;COUNT 8
+MAIN;COUNT;COUNT;COUNT 7
+MAIN;LEAF 1
+");
+    }
+
+    #[test]
+    fn returns_from_os_service_routines() {
+        let program = assemble(".ORIG x3000\nMAIN LD R0, CHAR\nOUT\nOUT\nHALT\nCHAR .FILL x41\n.END").unwrap();
+        let mut machine = Machine::with_console(Box::new(BufferConsole::new("")));
+        machine.load_image(&program.image).unwrap();
+        machine.load_os().unwrap();
+        let mut profiler = Profiler::new().with_symbols(program.symbols);
+        assert_eq!(profiler.run(&mut machine).unwrap(), StepOutcome::Halted);
+        let os = crate::os::os_program().symbols;
+        let out = profiler.functions().into_iter().find(|f| Some(f.address) == os.get("TRAP_OUT")).unwrap();
+        assert_eq!((out.calls, out.max_depth), (2, 1));
+        let main = profiler.functions().into_iter().find(|f| f.address == 0x3000).unwrap();
+        assert_eq!(main.inclusive, profiler.total());
+        // both OUTs return to MAIN, so it runs LD and the TRAPs with nothing else on the stack
+        assert!(profiler.folded_stacks().starts_with("MAIN 4\n"), "{}", profiler.folded_stacks());
+        let write_char = format!("x{:04X}", os.get("WRITE_CHAR").unwrap());
+        assert!(profiler.folded_stacks().lines().all(|line| line.matches(&write_char).count() <= 1));
+    }
+
+    #[test]
+    fn interrupts_are_calls() {
+        let program = assemble(".ORIG x3000\nMAIN ADD R1, R1, #8\nLOOP ADD R1, R1, #-1\nBRp LOOP\nHALT\n.END").unwrap();
+        let mut machine = Machine::with_console(Box::new(BufferConsole::new("")));
+        machine.load_image(&program.image).unwrap();
+        machine.mem.write(0x0181, 0x1000);
+        machine.mem.write(0x1000, 0x8000); // RTI
+        machine.interrupts.timer = Some(crate::interrupt::Timer::new(5, 0x81, 6));
+        let mut profiler = Profiler::new().with_symbols(program.symbols);
+        assert_eq!(profiler.run(&mut machine).unwrap(), StepOutcome::Halted);
+        let isr = profiler.functions().into_iter().find(|f| f.address == 0x1000).unwrap();
+        assert_eq!((isr.calls, isr.exclusive, isr.max_depth), (4, 4, 1));
+        assert!(profiler.folded_stacks().contains("MAIN;x1000 4\n"));
+    }
+}
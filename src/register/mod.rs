@@ -1,8 +1,20 @@
+use crate::error::VmError;
+
 use std::fmt;
 
 pub const PC_START: u16 = 0x3000;
 pub const PC_REG: u16 = 8;
 pub const COND_REG: u16 = 9;
+pub const PSR_REG: u16 = 10;
+
+// initial supervisor stack pointer, the supervisor stack grows down from here
+pub const SSP_START: u16 = 0x3000;
+
+// PSR layout: [15] privilege, [10:8] priority, [2:0] NZP
+const PSR_PRIVILEGE_BIT: u16 = 1 << 15;
+const PSR_PRIORITY_SHIFT: u16 = 8;
+const PSR_PRIORITY_MASK: u16 = 0x7;
+const PSR_COND_MASK: u16 = 0x7;
 
 pub enum CondFlag {
     POS = 1 << 0, // Postive
@@ -10,8 +22,14 @@ pub enum CondFlag {
     NEG = 1 << 2, // Negative
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Privilege {
+    Supervisor = 0,
+    User = 1,
+}
+
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RegFile {
     pub r_r0: u16, // expose r0 to outside
     r_r1: u16,
@@ -22,7 +40,11 @@ pub struct RegFile {
     r_r6: u16,
     pub r_r7: u16, // expose R7 for subroutine purpose
     pub r_pc: u16, // expose pc for running instruction
-    pub r_cond: u16, // conditional register for compare
+    pub r_cond: u16, // conditional register for compare, the NZP bits of the PSR
+    pub privilege: Privilege, // PSR[15]
+    pub priority: u16, // PSR[10:8]
+    pub saved_usp: u16, // user R6 while running in supervisor mode
+    pub saved_ssp: u16, // supervisor R6 while running in user mode
 }
 
 
@@ -40,11 +62,50 @@ impl RegFile {
             r_r7: 0,
             r_pc: PC_START,
             r_cond: 0, // conditional register 
+            privilege: Privilege::User,
+            priority: 0,
+            saved_usp: 0,
+            saved_ssp: SSP_START,
+        }
+    }
+
+    // pack privilege, priority and condition codes as the hardware PSR
+    pub fn psr(&self) -> u16 {
+        let privilege = if self.privilege == Privilege::User { PSR_PRIVILEGE_BIT } else { 0 };
+        privilege | ((self.priority & PSR_PRIORITY_MASK) << PSR_PRIORITY_SHIFT) | (self.r_cond & PSR_COND_MASK)
+    }
+
+    // load the PSR, a change of privilege swaps R6 with the saved stack pointer
+    pub fn set_psr(&mut self, psr: u16) {
+        if psr & PSR_PRIVILEGE_BIT != 0 {
+            self.switch_to_user();
+        } else {
+            self.switch_to_supervisor();
+        }
+        self.priority = (psr >> PSR_PRIORITY_SHIFT) & PSR_PRIORITY_MASK;
+        self.r_cond = psr & PSR_COND_MASK;
+    }
+
+    // enter supervisor mode, swapping R6 over to the supervisor stack if coming from user mode
+    pub fn switch_to_supervisor(&mut self) {
+        if self.privilege == Privilege::User {
+            self.saved_usp = self.r_r6;
+            self.r_r6 = self.saved_ssp;
+            self.privilege = Privilege::Supervisor;
+        }
+    }
+
+    // drop to user mode, swapping R6 back to the user stack
+    pub fn switch_to_user(&mut self) {
+        if self.privilege == Privilege::Supervisor {
+            self.saved_ssp = self.r_r6;
+            self.r_r6 = self.saved_usp;
+            self.privilege = Privilege::User;
         }
     }
 
     // the following functions are only for u16 arithmatics
-    pub fn update_reg(&mut self, reg: u16, val: u16) {
+    pub fn update_reg(&mut self, reg: u16, val: u16) -> Result<(), VmError> {
         // map the update for the registers
         match reg {
             0 => self.r_r0 = val,
@@ -57,14 +118,16 @@ impl RegFile {
             7 => self.r_r7 = val,
             PC_REG => self.r_pc = val,
             COND_REG => self.r_cond = val,
-            _ => panic!("Invalid Register in RegFile!"),
+            PSR_REG => self.set_psr(val),
+            _ => return Err(VmError::InvalidRegister { reg }),
         }
+        Ok(())
     }
 
-    pub fn read_reg(&mut self, reg: u16) -> u16 {
+    pub fn read_reg(&self, reg: u16) -> Result<u16, VmError> {
         // map registers
-        match reg {
-            0 => self.r_r0, 
+        let val = match reg {
+            0 => self.r_r0,
             1 => self.r_r1,
             2 => self.r_r2,
             3 => self.r_r3,
@@ -74,19 +137,28 @@ impl RegFile {
             7 => self.r_r7,
             PC_REG => self.r_pc,
             COND_REG => self.r_cond,
-            _ => panic!("Invalid Register in RegFile!"),
-        }
+            PSR_REG => self.psr(),
+            _ => return Err(VmError::InvalidRegister { reg }),
+        };
+        Ok(val)
     }
 
-    pub fn update_cond_flag(&mut self, reg: u16) {
-        let val = self.read_reg(reg);
+    pub fn update_cond_flag(&mut self, reg: u16) -> Result<(), VmError> {
+        let val = self.read_reg(reg)?;
         if val == 0 {
-            self.update_reg(COND_REG, CondFlag::ZRO as u16);
+            self.r_cond = CondFlag::ZRO as u16;
         } else if (val >> 15) != 0 {
-            self.update_reg(COND_REG, CondFlag::NEG as u16);
+            self.r_cond = CondFlag::NEG as u16;
         } else {
-            self.update_reg(COND_REG, CondFlag::POS as u16);
+            self.r_cond = CondFlag::POS as u16;
         }
+        Ok(())
+    }
+}
+
+impl Default for RegFile {
+    fn default() -> RegFile {
+        RegFile::new()
     }
 }
 
@@ -103,6 +175,9 @@ impl fmt::Display for RegFile {
         writeln!(f, "R7 => 0x{:x?}", self.r_r7)?;
         writeln!(f, "PC => 0x{:x?}", self.r_pc)?;
         writeln!(f, "COND REG => 0x{:x?}", self.r_cond)?;
+        writeln!(f, "PSR => 0x{:x?} ({:?}, priority {})", self.psr(), self.privilege, self.priority)?;
+        writeln!(f, "SAVED USP => 0x{:x?}", self.saved_usp)?;
+        writeln!(f, "SAVED SSP => 0x{:x?}", self.saved_ssp)?;
         writeln!(f, "=========================")?;
         Ok(())
     }
@@ -116,27 +191,79 @@ mod register_test {
     #[test]
     fn flag_zero() {
         let mut reg_file = RegFile::new();
-        reg_file.update_reg(1, 0);
-        reg_file.update_cond_flag(1);
-        let value = reg_file.read_reg(COND_REG);
+        reg_file.update_reg(1, 0).unwrap();
+        reg_file.update_cond_flag(1).unwrap();
+        let value = reg_file.read_reg(COND_REG).unwrap();
         assert_eq!(CondFlag::ZRO as u16, value);
     }
 
     #[test]
     fn flag_positive() {
         let mut reg_file = RegFile::new();
-        reg_file.update_reg(3, 12);
-        reg_file.update_cond_flag(3);
-        let value = reg_file.read_reg(COND_REG);
+        reg_file.update_reg(3, 12).unwrap();
+        reg_file.update_cond_flag(3).unwrap();
+        let value = reg_file.read_reg(COND_REG).unwrap();
         assert_eq!(CondFlag::POS as u16, value);
     }
 
     #[test]
     fn flag_negative() {
         let mut reg_file = RegFile::new();
-        reg_file.update_reg(5, 0xE00F);
-        reg_file.update_cond_flag(5);
-        let value = reg_file.read_reg(COND_REG);
+        reg_file.update_reg(5, 0xE00F).unwrap();
+        reg_file.update_cond_flag(5).unwrap();
+        let value = reg_file.read_reg(COND_REG).unwrap();
         assert_eq!(CondFlag::NEG as u16, value);
     }
+
+    #[test]
+    fn psr_packing() {
+        let mut reg_file = RegFile::new();
+        reg_file.update_reg(2, 0x8000).unwrap();
+        reg_file.update_cond_flag(2).unwrap();
+        reg_file.priority = 4;
+        assert_eq!(reg_file.psr(), 0x8404);
+        reg_file.update_reg(PSR_REG, 0x0702).unwrap();
+        assert_eq!(reg_file.privilege, Privilege::Supervisor);
+        assert_eq!(reg_file.priority, 7);
+        assert_eq!(reg_file.read_reg(COND_REG).unwrap(), CondFlag::ZRO as u16);
+        assert_eq!(reg_file.read_reg(PSR_REG).unwrap(), 0x0702);
+    }
+
+    #[test]
+    fn stack_switching() {
+        let mut reg_file = RegFile::new();
+        reg_file.update_reg(6, 0xFDFF).unwrap();
+        reg_file.switch_to_supervisor();
+        assert_eq!(reg_file.privilege, Privilege::Supervisor);
+        assert_eq!(reg_file.read_reg(6).unwrap(), SSP_START);
+        assert_eq!(reg_file.saved_usp, 0xFDFF);
+        // already in supervisor mode, nothing to swap
+        reg_file.switch_to_supervisor();
+        assert_eq!(reg_file.read_reg(6).unwrap(), SSP_START);
+        reg_file.update_reg(6, 0x2FFE).unwrap();
+        reg_file.switch_to_user();
+        assert_eq!(reg_file.read_reg(6).unwrap(), 0xFDFF);
+        assert_eq!(reg_file.saved_ssp, 0x2FFE);
+    }
+
+    #[test]
+    fn psr_write_switches_stacks() {
+        let mut reg_file = RegFile::new();
+        reg_file.update_reg(6, 0xFDFF).unwrap();
+        reg_file.update_reg(PSR_REG, 0x0002).unwrap();
+        assert_eq!(reg_file.privilege, Privilege::Supervisor);
+        assert_eq!(reg_file.read_reg(6).unwrap(), SSP_START);
+        assert_eq!(reg_file.saved_usp, 0xFDFF);
+        reg_file.update_reg(PSR_REG, 0x8001).unwrap();
+        assert_eq!(reg_file.privilege, Privilege::User);
+        assert_eq!(reg_file.read_reg(6).unwrap(), 0xFDFF);
+        assert_eq!(reg_file.r_cond, 1);
+    }
+
+    #[test]
+    fn invalid_register() {
+        let mut reg_file = RegFile::new();
+        assert!(matches!(reg_file.update_reg(11, 1), Err(VmError::InvalidRegister { reg: 11 })));
+        assert!(reg_file.read_reg(42).is_err());
+    }
 }
\ No newline at end of file
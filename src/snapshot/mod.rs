@@ -0,0 +1,371 @@
+/*
+This is the LC-3 Snapshot Module, the complete state of a machine and the
+big-endian file format it is saved in
+*/
+use crate::device::Devices;
+use crate::interrupt::{InterruptController, Timer};
+use crate::machine::TrapMode;
+use crate::memory::{Memory, MEMORY_SIZE};
+use crate::register::{RegFile, PC_REG, PSR_REG};
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"LC3S";
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    // the file does not start with the snapshot magic
+    NotASnapshot,
+    // written by a newer simulator
+    UnsupportedVersion { version: u16 },
+    // the file ends in the middle of a field
+    Truncated,
+    // a field holds a value no machine state could have
+    Corrupt { field: &'static str },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "unable to access snapshot: {}", e),
+            SnapshotError::NotASnapshot => write!(f, "not an LC-3 snapshot"),
+            SnapshotError::UnsupportedVersion { version } => write!(f, "unsupported snapshot version {}", version),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::Corrupt { field } => write!(f, "snapshot has an invalid {}", field),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> SnapshotError {
+        SnapshotError::Io(e)
+    }
+}
+
+// everything needed to resume a machine exactly where it was
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub reg_file: RegFile,
+    pub mem: Memory,
+    pub devices: Devices,
+    pub interrupts: InterruptController,
+    pub trap_mode: TrapMode,
+    pub running: bool,
+    pub count: u64,
+    pub regions: Vec<Range<usize>>,
+    // keyboard input received but not read by the program yet
+    pub input: Vec<u8>,
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn word(&mut self, word: u16) {
+        self.bytes.extend_from_slice(&word.to_be_bytes());
+    }
+
+    fn long(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn quad(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.bytes.len() < n {
+            return Err(SnapshotError::Truncated);
+        }
+        let (head, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(head)
+    }
+
+    fn word(&mut self) -> Result<u16, SnapshotError> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn long(&mut self) -> Result<u32, SnapshotError> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn quad(&mut self) -> Result<u64, SnapshotError> {
+        let b = self.take(8)?;
+        let mut quad = [0; 8];
+        quad.copy_from_slice(b);
+        Ok(u64::from_be_bytes(quad))
+    }
+
+    fn flag(&mut self, field: &'static str) -> Result<bool, SnapshotError> {
+        match self.word()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SnapshotError::Corrupt { field }),
+        }
+    }
+}
+
+impl Snapshot {
+
+    /**
+     * serialize as: the magic and version, R0-R7, PC, PSR, saved USP / SSP,
+     * device registers, trap mode, running flag, instruction count, timer,
+     * pending interrupts, loaded regions, pending input, then memory as runs
+     * of non-zero words; every number is big-endian
+     */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer { bytes: Vec::new() };
+        w.bytes.extend_from_slice(MAGIC);
+        w.word(SNAPSHOT_VERSION);
+        for reg in 0..8 {
+            w.word(self.reg_file.read_reg(reg).expect("R0-R7 are valid registers"));
+        }
+        w.word(self.reg_file.r_pc);
+        w.word(self.reg_file.psr());
+        w.word(self.reg_file.saved_usp);
+        w.word(self.reg_file.saved_ssp);
+
+        w.word(self.devices.kbsr_ie as u16);
+        w.word(self.devices.kbdr);
+        w.word(self.devices.dsr_ie as u16);
+        w.word(self.devices.mcr);
+
+        w.word(match self.trap_mode {
+            TrapMode::Native => 0,
+            TrapMode::VectorTable => 1,
+        });
+        w.word(self.running as u16);
+        w.quad(self.count);
+
+        match &self.interrupts.timer {
+            Some(timer) => {
+                w.word(1);
+                w.quad(timer.interval);
+                w.word(timer.vector);
+                w.word(timer.priority);
+                w.quad(timer.remaining);
+            },
+            None => w.word(0),
+        }
+        let pending = self.interrupts.pending();
+        w.word(pending.len() as u16);
+        for irq in pending {
+            w.word(irq.vector);
+            w.word(irq.priority);
+        }
+
+        w.long(self.regions.len() as u32);
+        for region in self.regions.iter() {
+            w.long(region.start as u32);
+            w.long(region.end as u32);
+        }
+
+        w.long(self.input.len() as u32);
+        w.bytes.extend_from_slice(&self.input);
+
+        let mut runs = Vec::new();
+        let mut addr = 0;
+        while addr < MEMORY_SIZE {
+            if self.mem.cells[addr] == 0 {
+                addr += 1;
+                continue;
+            }
+            let start = addr;
+            while addr < MEMORY_SIZE && self.mem.cells[addr] != 0 {
+                addr += 1;
+            }
+            runs.push(start..addr);
+        }
+        w.long(runs.len() as u32);
+        for run in runs {
+            w.word(run.start as u16);
+            w.long(run.len() as u32);
+            for word in self.mem.cells[run].iter() {
+                w.word(*word);
+            }
+        }
+        w.bytes
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+        let mut r = Reader { bytes };
+        if bytes.len() < MAGIC.len() || r.take(MAGIC.len())? != MAGIC {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let version = r.word()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion { version });
+        }
+
+        let mut regs = [0; 8];
+        for reg in regs.iter_mut() {
+            *reg = r.word()?;
+        }
+        let mut reg_file = RegFile::new();
+        reg_file.update_reg(PC_REG, r.word()?).expect("PC is a valid register");
+        // a change of privilege swaps R6, so the saved R6 goes in afterwards
+        reg_file.update_reg(PSR_REG, r.word()?).expect("PSR is a valid register");
+        for (reg, value) in regs.iter().enumerate() {
+            reg_file.update_reg(reg as u16, *value).expect("R0-R7 are valid registers");
+        }
+        reg_file.saved_usp = r.word()?;
+        reg_file.saved_ssp = r.word()?;
+
+        let devices = Devices {
+            kbsr_ie: r.flag("keyboard status")?,
+            kbdr: r.word()?,
+            dsr_ie: r.flag("display status")?,
+            mcr: r.word()?,
+        };
+
+        let trap_mode = match r.word()? {
+            0 => TrapMode::Native,
+            1 => TrapMode::VectorTable,
+            _ => return Err(SnapshotError::Corrupt { field: "trap mode" }),
+        };
+        let running = r.flag("running flag")?;
+        let count = r.quad()?;
+
+        let mut interrupts = InterruptController::new();
+        if r.flag("timer flag")? {
+            let mut timer = Timer::new(r.quad()?, r.word()?, r.word()?);
+            timer.remaining = r.quad()?;
+            // a running timer counts down from its interval to 1, a stopped one stays at 0
+            if timer.remaining > timer.interval || (timer.remaining == 0) != (timer.interval == 0)
+                || timer.vector > 0xFF || timer.priority > 7 {
+                return Err(SnapshotError::Corrupt { field: "timer" });
+            }
+            interrupts.timer = Some(timer);
+        }
+        for _ in 0..r.word()? {
+            let vector = r.word()?;
+            interrupts.raise(vector, r.word()?);
+        }
+
+        let mut regions = Vec::new();
+        for _ in 0..r.long()? {
+            let (start, end) = (r.long()? as usize, r.long()? as usize);
+            if start > end || end > MEMORY_SIZE {
+                return Err(SnapshotError::Corrupt { field: "loaded region" });
+            }
+            regions.push(start..end);
+        }
+
+        let len = r.long()? as usize;
+        let input = r.take(len)?.to_vec();
+
+        let mut mem = Memory::new();
+        for _ in 0..r.long()? {
+            let start = r.word()? as usize;
+            let len = r.long()? as usize;
+            let end = match start.checked_add(len) {
+                Some(end) if end <= MEMORY_SIZE => end,
+                _ => return Err(SnapshotError::Corrupt { field: "memory run" }),
+            };
+            for cell in mem.cells[start..end].iter_mut() {
+                *cell = r.word()?;
+            }
+        }
+        if !r.bytes.is_empty() {
+            return Err(SnapshotError::Corrupt { field: "trailing data" });
+        }
+
+        Ok(Snapshot { reg_file, mem, devices, interrupts, trap_mode, running, count, regions, input })
+    }
+
+    pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Snapshot, SnapshotError> {
+        Snapshot::parse(&fs::read(path)?)
+    }
+
+    pub fn write_file<P: AsRef<Path>>(&self, path: P) -> Result<(), SnapshotError> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod snapshot_test {
+
+    use super::*;
+
+    fn sample() -> Snapshot {
+        let mut reg_file = RegFile::new();
+        reg_file.update_reg(3, 0xBEEF).unwrap();
+        reg_file.update_reg(6, 0x2FF0).unwrap();
+        reg_file.update_cond_flag(3).unwrap();
+        reg_file.switch_to_supervisor();
+        reg_file.update_reg(6, 0x2FFC).unwrap();
+        reg_file.priority = 4;
+        let mut mem = Memory::new();
+        mem.load_slice(0x3000, &[0x1261, 0x0000, 0xF025]);
+        mem.write(0xFFFF, 7);
+        let mut interrupts = InterruptController::new();
+        interrupts.timer = Some(Timer::new(100, 0x81, 6));
+        interrupts.raise(0x80, 4);
+        Snapshot {
+            reg_file,
+            mem,
+            devices: Devices { kbsr_ie: true, kbdr: 'a' as u16, dsr_ie: false, mcr: 0x8000 },
+            interrupts,
+            trap_mode: TrapMode::VectorTable,
+            running: true,
+            count: 1234,
+            regions: vec![0x0000..0x0200, 0x3000..0x3003],
+            input: b"rest".to_vec(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let snapshot = sample();
+        let bytes = snapshot.to_bytes();
+        assert_eq!(&bytes[..4], b"LC3S");
+        assert_eq!(Snapshot::parse(&bytes).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let bytes = sample().to_bytes();
+        assert!(matches!(Snapshot::parse(b"LC3"), Err(SnapshotError::NotASnapshot)));
+        assert!(matches!(Snapshot::parse(&bytes[..bytes.len() - 1]), Err(SnapshotError::Truncated)));
+        let mut newer = bytes.clone();
+        newer[5] = 9;
+        assert!(matches!(Snapshot::parse(&newer), Err(SnapshotError::UnsupportedVersion { version: 9 })));
+        let mut extra = bytes;
+        extra.push(0);
+        assert!(matches!(Snapshot::parse(&extra), Err(SnapshotError::Corrupt { .. })));
+    }
+
+    #[test]
+    fn rejects_impossible_state() {
+        let mut snapshot = sample();
+        snapshot.interrupts.timer.as_mut().unwrap().remaining = 0;
+        assert!(matches!(Snapshot::parse(&snapshot.to_bytes()), Err(SnapshotError::Corrupt { field: "timer" })));
+        snapshot.interrupts.timer.as_mut().unwrap().remaining = 101;
+        assert!(matches!(Snapshot::parse(&snapshot.to_bytes()), Err(SnapshotError::Corrupt { field: "timer" })));
+        // the last run, one word at xFFFF, made to run past the end of memory
+        let mut bytes = sample().to_bytes();
+        let len = bytes.len();
+        bytes[len - 6..len - 2].copy_from_slice(&2_u32.to_be_bytes());
+        bytes.extend_from_slice(&[0, 0]);
+        assert!(matches!(Snapshot::parse(&bytes), Err(SnapshotError::Corrupt { field: "memory run" })));
+    }
+}
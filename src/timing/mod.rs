@@ -0,0 +1,247 @@
+/*
+This is the LC-3 Timing Module, charging every instruction the cycles the
+textbook's state machine (Patt & Patel, appendix C) spends on it
+*/
+use crate::instruction::decode::*;
+use crate::instruction::opcode;
+use crate::machine::TrapMode;
+use crate::register::Privilege;
+
+use std::fmt::Write;
+
+// cycles a memory access takes unless configured otherwise, as in the textbook
+pub const DEFAULT_MEMORY_LATENCY: u64 = 5;
+
+const OPCODE_NAMES: [&str; 16] = [
+    "BR", "ADD", "LD", "ST", "JSR", "AND", "LDR", "STR", "RTI", "NOT", "LDI", "STI", "JMP", "RES", "LEA", "TRAP",
+];
+
+/**
+ * every state takes one cycle, except the states accessing memory which
+ * wait `memory_latency` cycles for the memory to become ready
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimingModel {
+    pub memory_latency: u64,
+}
+
+impl Default for TimingModel {
+    fn default() -> TimingModel {
+        TimingModel { memory_latency: DEFAULT_MEMORY_LATENCY }
+    }
+}
+
+impl TimingModel {
+
+    pub fn new(memory_latency: u64) -> TimingModel {
+        TimingModel { memory_latency }
+    }
+
+    // `states` one-cycle states plus `accesses` memory states
+    fn states(&self, states: u64, accesses: u64) -> u64 {
+        states + accesses * self.memory_latency
+    }
+
+    // states 18, 33 (memory), 35 and 32: fetch the instruction and decode it
+    pub fn fetch(&self) -> u64 {
+        self.states(3, 1)
+    }
+
+    /**
+     * states 49 / 13, 37, 41 (memory), 43, 47, 48 (memory), 50, 52 (memory)
+     * and 54: push PSR and PC on the supervisor stack and load PC from the
+     * interrupt vector table
+     */
+    pub fn service_entry(&self) -> u64 {
+        self.states(6, 3)
+    }
+
+    /**
+     * the states after decoding `word`, given the condition codes and
+     * privilege it executes with; an illegal opcode or an RTI in user mode
+     * goes on to enter the exception's service routine
+     */
+    pub fn execute(&self, word: u16, cond: u16, privilege: Privilege, trap_mode: TrapMode) -> u64 {
+        let instr = match decode(word) {
+            Ok(instr) => instr,
+            Err(_) => return self.service_entry(),
+        };
+        match instr {
+            // states 0 and, when taken, 22
+            Instruction::Br { n, z, p, .. } => {
+                let nzp = ((n as u16) << 2) | ((z as u16) << 1) | p as u16;
+                if nzp & cond != 0 { self.states(2, 0) } else { self.states(1, 0) }
+            },
+            // states 1, 5, 9, 12 and 14
+            Instruction::Add { .. } | Instruction::And { .. } | Instruction::Not { .. }
+            | Instruction::Jmp { .. } | Instruction::Ret | Instruction::Lea { .. } => self.states(1, 0),
+            // states 4 then 21 or 20
+            Instruction::Jsr { .. } | Instruction::Jsrr { .. } => self.states(2, 0),
+            // states 2 / 6, 25 (memory) and 27
+            Instruction::Ld { .. } | Instruction::Ldr { .. } => self.states(2, 1),
+            // states 10, 24 (memory), 26, 25 (memory) and 27
+            Instruction::Ldi { .. } => self.states(3, 2),
+            // states 3 / 7, 23 and 16 (memory)
+            Instruction::St { .. } | Instruction::Str { .. } => self.states(2, 1),
+            // states 11, 29 (memory), 31, 23 and 16 (memory)
+            Instruction::Sti { .. } => self.states(3, 2),
+            // state 15 only, the service routine runs in the host and takes no cycles
+            Instruction::Trap { .. } if trap_mode == TrapMode::Native => self.states(1, 0),
+            // states 15, 37, 41 (memory), 43, 47, 48 (memory), 46, 28 (memory) and 30
+            Instruction::Trap { .. } => self.service_entry(),
+            // states 8, 36 (memory), 38, 39, 40 (memory), 42, 34 and 51 / 59
+            Instruction::Rti if privilege == Privilege::Supervisor => self.states(6, 2),
+            Instruction::Rti => self.states(1, 0) + self.service_entry(),
+        }
+    }
+}
+
+// what a single step was charged
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StepCycles {
+    // entering an interrupt's service routine before the fetch, 0 if none was taken
+    pub interrupt: u64,
+    // the opcode of the instruction, `None` if it did not complete
+    pub opcode: Option<u16>,
+    pub instruction: u64,
+}
+
+// instructions and cycles spent on one opcode (or on taking interrupts)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CycleCount {
+    pub count: u64,
+    pub cycles: u64,
+}
+
+impl CycleCount {
+    // cycles per instruction, 0 when nothing was executed
+    pub fn cpi(&self) -> f64 {
+        if self.count == 0 { 0.0 } else { self.cycles as f64 / self.count as f64 }
+    }
+}
+
+// the cycle counter of a machine with its breakdown per opcode
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Timing {
+    pub model: TimingModel,
+    opcodes: [CycleCount; 16],
+    interrupts: CycleCount,
+}
+
+impl Timing {
+
+    pub fn new(model: TimingModel) -> Timing {
+        Timing { model, ..Default::default() }
+    }
+
+    /**
+     * charge a step: the interrupt taken before it, if any, and the fetch
+     * and execution of `word` when the instruction completed
+     */
+    pub fn charge(&mut self, trap_mode: TrapMode, interrupted: bool, completed: Option<(u16, u16, Privilege)>) -> StepCycles {
+        let step = StepCycles {
+            interrupt: if interrupted { self.model.service_entry() } else { 0 },
+            opcode: completed.map(|(word, _, _)| opcode(word)),
+            instruction: match completed {
+                Some((word, cond, privilege)) => self.model.fetch() + self.model.execute(word, cond, privilege, trap_mode),
+                None => 0,
+            },
+        };
+        self.add(step, 1);
+        step
+    }
+
+    // take back a step, when the machine steps backwards
+    pub fn uncharge(&mut self, step: StepCycles) {
+        self.add(step, -1);
+    }
+
+    fn add(&mut self, step: StepCycles, sign: i64) {
+        let apply = |count: &mut CycleCount, cycles: u64| {
+            count.count = (count.count as i64 + sign) as u64;
+            count.cycles = (count.cycles as i64 + sign * cycles as i64) as u64;
+        };
+        if step.interrupt > 0 {
+            apply(&mut self.interrupts, step.interrupt);
+        }
+        if let Some(op) = step.opcode {
+            apply(&mut self.opcodes[op as usize], step.instruction);
+        }
+    }
+
+    // cycles elapsed
+    pub fn cycles(&self) -> u64 {
+        self.interrupts.cycles + self.opcodes.iter().map(|op| op.cycles).sum::<u64>()
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.opcodes.iter().map(|op| op.count).sum()
+    }
+
+    // cycles per instruction over the whole run, interrupts included
+    pub fn cpi(&self) -> f64 {
+        CycleCount { count: self.instructions(), cycles: self.cycles() }.cpi()
+    }
+
+    pub fn opcode(&self, op: u16) -> CycleCount {
+        self.opcodes[op as usize & 0xF]
+    }
+
+    pub fn interrupts(&self) -> CycleCount {
+        self.interrupts
+    }
+
+    // a table of instructions, cycles and CPI for every opcode executed
+    pub fn report(&self) -> String {
+        let mut out = format!("{:<10} {:>12} {:>14} {:>8}\n", "opcode", "instructions", "cycles", "CPI");
+        for (op, count) in self.opcodes.iter().enumerate().filter(|(_, count)| count.count > 0) {
+            writeln!(out, "{:<10} {:>12} {:>14} {:>8.2}", OPCODE_NAMES[op], count.count, count.cycles, count.cpi()).unwrap();
+        }
+        if self.interrupts.count > 0 {
+            writeln!(out, "{:<10} {:>12} {:>14} {:>8}", "interrupt", self.interrupts.count, self.interrupts.cycles, "-").unwrap();
+        }
+        writeln!(
+            out, "{:<10} {:>12} {:>14} {:>8.2}\nmemory latency: {} cycle{}",
+            "total", self.instructions(), self.cycles(), self.cpi(), self.model.memory_latency,
+            if self.model.memory_latency == 1 { "" } else { "s" },
+        ).unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod timing_test {
+
+    use super::*;
+
+    #[test]
+    fn state_machine_cycles() {
+        let model = TimingModel::default();
+        assert_eq!(model.fetch(), 8);
+        assert_eq!(model.execute(0x1261, 0, Privilege::User, TrapMode::VectorTable), 1); // ADD
+        assert_eq!(model.execute(0x2201, 0, Privilege::User, TrapMode::VectorTable), 7); // LD
+        assert_eq!(model.execute(0xA201, 0, Privilege::User, TrapMode::VectorTable), 13); // LDI
+        assert_eq!(model.execute(0xB201, 0, Privilege::User, TrapMode::VectorTable), 13); // STI
+        assert_eq!(model.execute(0x0401, 2, Privilege::User, TrapMode::VectorTable), 2); // BRz, taken
+        assert_eq!(model.execute(0x0401, 1, Privilege::User, TrapMode::VectorTable), 1); // BRz, not taken
+        assert_eq!(model.execute(0xD000, 0, Privilege::User, TrapMode::VectorTable), 21); // illegal opcode
+        assert_eq!(model.execute(0xF025, 0, Privilege::User, TrapMode::VectorTable), 21); // TRAP x25
+        assert_eq!(model.execute(0xF025, 0, Privilege::User, TrapMode::Native), 1);
+        assert_eq!(TimingModel::new(1).execute(0xA201, 0, Privilege::User, TrapMode::VectorTable), 5);
+    }
+
+    #[test]
+    fn cycles_per_opcode() {
+        let mut timing = Timing::new(TimingModel::new(2));
+        timing.charge(TrapMode::VectorTable, false, Some((0x1261, 0, Privilege::User)));
+        let step = timing.charge(TrapMode::VectorTable, true, Some((0x2201, 0, Privilege::User)));
+        assert_eq!(step, StepCycles { interrupt: 12, opcode: Some(0x2), instruction: 9 });
+        assert_eq!(timing.opcode(0x1), CycleCount { count: 1, cycles: 6 });
+        assert_eq!(timing.cycles(), 27);
+        assert_eq!(timing.instructions(), 2);
+        assert!(timing.report().contains("LD                    1              9     9.00\n"));
+        timing.uncharge(step);
+        assert_eq!(timing.cycles(), 6);
+        assert_eq!(timing.interrupts(), CycleCount::default());
+    }
+}
@@ -0,0 +1,207 @@
+/*
+Comparing two execution traces, e.g. a student's run against a reference
+solution, and reporting where they first part ways
+*/
+use super::{cc_name, TraceRecord};
+
+use std::fmt;
+
+// a trace file that could not be read back
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for TraceParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for TraceParseError {}
+
+// read a whole trace, text or JSON Lines, skipping blank lines
+pub fn read_trace(text: &str) -> Result<Vec<TraceRecord>, TraceParseError> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| TraceRecord::parse(line).map_err(|message| TraceParseError { line: i + 1, message }))
+        .collect()
+}
+
+// the first step at which two traces disagree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    // 1-based position of the step in both traces
+    pub step: usize,
+    // `None` when that trace ended before this step
+    pub expected: Option<TraceRecord>,
+    pub actual: Option<TraceRecord>,
+    // the steps leading up to the divergence, which both traces agree on
+    pub context: Vec<TraceRecord>,
+    // one line per difference, e.g. "R1: expected x0003, found x0004"
+    pub differences: Vec<String>,
+}
+
+fn word_or_unchanged(value: Option<u16>) -> String {
+    match value {
+        Some(value) => format!("x{:04X}", value),
+        None => "unchanged".to_string(),
+    }
+}
+
+fn accesses(arrow: &str, accesses: &[(u16, u16)]) -> String {
+    if accesses.is_empty() {
+        return "none".to_string();
+    }
+    accesses.iter().map(|(address, value)| format!("[x{:04X}]{}x{:04X}", address, arrow, value)).collect::<Vec<_>>().join(" ")
+}
+
+fn compare_accesses(kind: &str, arrow: &str, expected: &[(u16, u16)], actual: &[(u16, u16)], out: &mut Vec<String>) {
+    if expected == actual {
+        return;
+    }
+    let same_addresses = expected.len() == actual.len()
+        && expected.iter().zip(actual.iter()).all(|(e, a)| e.0 == a.0);
+    if same_addresses {
+        for ((address, e), (_, a)) in expected.iter().zip(actual.iter()).filter(|(e, a)| e.1 != a.1) {
+            out.push(format!("{} x{:04X}: expected x{:04X}, found x{:04X}", kind, address, e, a));
+        }
+    } else {
+        out.push(format!("{}s: expected {}, found {}", kind, accesses(arrow, expected), accesses(arrow, actual)));
+    }
+}
+
+/**
+ * what differs between two records of the same step; the disassembly and
+ * the count are ignored since they depend on symbols and where tracing began
+ */
+pub fn compare(expected: &TraceRecord, actual: &TraceRecord) -> Vec<String> {
+    let mut out = Vec::new();
+    if expected.pc != actual.pc {
+        out.push(format!("PC: expected x{:04X}, found x{:04X}", expected.pc, actual.pc));
+    }
+    if expected.word != actual.word {
+        out.push(format!(
+            "instruction: expected x{:04X} ({}), found x{:04X} ({})",
+            expected.word, expected.asm, actual.word, actual.asm,
+        ));
+    }
+    for reg in 0..8 {
+        let find = |record: &TraceRecord| record.registers.iter().find(|(r, _)| *r == reg).map(|(_, value)| *value);
+        let (e, a) = (find(expected), find(actual));
+        if e != a {
+            out.push(format!("R{}: expected {}, found {}", reg, word_or_unchanged(e), word_or_unchanged(a)));
+        }
+    }
+    compare_accesses("load", "->", &expected.reads, &actual.reads, &mut out);
+    compare_accesses("store", "<-", &expected.writes, &actual.writes, &mut out);
+    if expected.cc != actual.cc {
+        let name = |cc: Option<u16>| cc.map_or("unchanged", cc_name);
+        out.push(format!("CC: expected {}, found {}", name(expected.cc), name(actual.cc)));
+    }
+    out
+}
+
+/**
+ * find the first step at which `actual` differs from `expected`, with up to
+ * `context` of the steps before it; `None` if the traces are the same
+ */
+pub fn diff_traces(expected: &[TraceRecord], actual: &[TraceRecord], context: usize) -> Option<Divergence> {
+    let len = expected.len().max(actual.len());
+    (0..len).find_map(|i| {
+        let differences = match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) => compare(e, a),
+            (Some(_), None) => vec![format!("actual trace ended after {} steps", actual.len())],
+            (None, Some(_)) => vec![format!("expected trace ended after {} steps", expected.len())],
+            (None, None) => Vec::new(),
+        };
+        if differences.is_empty() {
+            return None;
+        }
+        Some(Divergence {
+            step: i + 1,
+            expected: expected.get(i).cloned(),
+            actual: actual.get(i).cloned(),
+            context: expected[i.saturating_sub(context)..i].to_vec(),
+            differences,
+        })
+    })
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "traces diverge at step {}", self.step)?;
+        if !self.context.is_empty() {
+            writeln!(f, "preceding steps:")?;
+            for record in self.context.iter() {
+                writeln!(f, "  {}", record)?;
+            }
+        }
+        let line = |record: &Option<TraceRecord>| match record {
+            Some(record) => record.to_string(),
+            None => "(end of trace)".to_string(),
+        };
+        writeln!(f, "expected:\n  {}", line(&self.expected))?;
+        writeln!(f, "actual:\n  {}", line(&self.actual))?;
+        writeln!(f, "differences:")?;
+        for difference in self.differences.iter() {
+            writeln!(f, "  {}", difference)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod diff_test {
+
+    use super::*;
+
+    const EXPECTED: &str = "\
+       1  x3000  x5260  AND R1, R1, #0           R1=x0000 CC=Z
+       2  x3001  x1263  ADD R1, R1, #3           R1=x0003 CC=P
+       3  x3002  x3201  ST R1, x3004             [x3004]<-x0003
+       4  x3003  xF025  HALT
+";
+
+    #[test]
+    fn identical_traces() {
+        let trace = read_trace(EXPECTED).unwrap();
+        assert_eq!(trace.len(), 4);
+        assert_eq!(diff_traces(&trace, &trace, 3), None);
+    }
+
+    #[test]
+    fn first_divergence() {
+        let expected = read_trace(EXPECTED).unwrap();
+        let actual = read_trace(&EXPECTED.replace("#3           R1=x0003", "#4           R1=x0004")
+            .replace("x1263", "x1264")
+            .replace("<-x0003", "<-x0004")).unwrap();
+        let divergence = diff_traces(&expected, &actual, 1).unwrap();
+        assert_eq!(divergence.step, 2);
+        assert_eq!(divergence.context, expected[..1].to_vec());
+        assert_eq!(divergence.differences, vec![
+            "instruction: expected x1263 (ADD R1, R1, #3), found x1264 (ADD R1, R1, #4)".to_string(),
+            "R1: expected x0003, found x0004".to_string(),
+        ]);
+        let report = divergence.to_string();
+        assert!(report.starts_with("traces diverge at step 2\npreceding steps:\n         1  x3000"));
+        assert!(report.ends_with("differences:\n  instruction: expected x1263 (ADD R1, R1, #3), found x1264 (ADD R1, R1, #4)\n  R1: expected x0003, found x0004\n"));
+    }
+
+    #[test]
+    fn stores_and_early_end() {
+        let expected = read_trace(EXPECTED).unwrap();
+        let mut actual = expected.clone();
+        actual[2].writes = vec![(0x3005, 0x0003)];
+        let divergence = diff_traces(&expected, &actual, 5).unwrap();
+        assert_eq!(divergence.context.len(), 2);
+        assert_eq!(divergence.differences, vec!["stores: expected [x3004]<-x0003, found [x3005]<-x0003".to_string()]);
+        let divergence = diff_traces(&expected, &expected[..3], 0).unwrap();
+        assert_eq!(divergence.step, 4);
+        assert_eq!(divergence.actual, None);
+        assert_eq!(divergence.differences, vec!["actual trace ended after 3 steps".to_string()]);
+        assert_eq!(read_trace("1 x3000 x0000\nbad").unwrap_err().line, 2);
+    }
+}
@@ -0,0 +1,531 @@
+/*
+This is the LC-3 Tracer Module, recording what every executed instruction did
+as compact text or JSON Lines
+*/
+pub mod diff;
+
+use crate::assembler::SymbolTable;
+use crate::disassembler::disassemble_word;
+use crate::error::VmError;
+use crate::instruction::decode::*;
+use crate::machine::{Machine, StepOutcome};
+use crate::memory::AccessKind;
+use crate::register::RegFile;
+use crate::utils::json_escape;
+
+use std::fmt;
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Text,
+    Json,
+}
+
+// what a single executed instruction did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    // 1-based position of the instruction in the run
+    pub count: u64,
+    pub pc: u16,
+    pub word: u16,
+    pub asm: String,
+    // (R0-R7, new value) for every register the instruction wrote
+    pub registers: Vec<(u16, u16)>,
+    // (address, value) of every data load and store, in order
+    pub reads: Vec<(u16, u16)>,
+    pub writes: Vec<(u16, u16)>,
+    // the NZP bits, when the instruction set or changed them
+    pub cc: Option<u16>,
+}
+
+fn cc_name(cc: u16) -> &'static str {
+    match cc {
+        1 => "P",
+        2 => "Z",
+        4 => "N",
+        _ => "-",
+    }
+}
+
+fn cc_value(name: &str) -> Option<u16> {
+    match name {
+        "P" => Some(1),
+        "Z" => Some(2),
+        "N" => Some(4),
+        "-" => Some(0),
+        _ => None,
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    u16::from_str_radix(text.strip_prefix('x')?, 16).ok()
+}
+
+// the value of a JSON document, just enough to read traces back
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Number(u64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+struct JsonParser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl<'a> JsonParser<'a> {
+    fn skip_space(&mut self) {
+        while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_space();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(format!("expected `{}`, found `{}`", expected, c)),
+            None => Err(format!("expected `{}`, found the end of the line", expected)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_space();
+        match self.chars.peek().copied() {
+            Some('n') => {
+                for c in "null".chars() {
+                    self.expect(c)?;
+                }
+                Ok(Json::Null)
+            },
+            Some('"') => self.string().map(Json::Str),
+            Some('[') => {
+                self.chars.next();
+                let mut items = Vec::new();
+                self.skip_space();
+                if self.chars.peek() == Some(&']') {
+                    self.chars.next();
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_space();
+                    match self.chars.next() {
+                        Some(',') => continue,
+                        Some(']') => return Ok(Json::Array(items)),
+                        _ => return Err("expected `,` or `]`".to_string()),
+                    }
+                }
+            },
+            Some('{') => {
+                self.chars.next();
+                let mut fields = Vec::new();
+                self.skip_space();
+                if self.chars.peek() == Some(&'}') {
+                    self.chars.next();
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.skip_space();
+                    let key = self.string()?;
+                    self.expect(':')?;
+                    fields.push((key, self.value()?));
+                    self.skip_space();
+                    match self.chars.next() {
+                        Some(',') => continue,
+                        Some('}') => return Ok(Json::Object(fields)),
+                        _ => return Err("expected `,` or `}`".to_string()),
+                    }
+                }
+            },
+            Some(c) if c.is_ascii_digit() => {
+                let mut digits = String::new();
+                while let Some(c) = self.chars.peek().copied().filter(char::is_ascii_digit) {
+                    digits.push(c);
+                    self.chars.next();
+                }
+                digits.parse().map(Json::Number).map_err(|_| format!("number `{}` is too large", digits))
+            },
+            Some(c) => Err(format!("unexpected `{}`", c)),
+            None => Err("unexpected end of the line".to_string()),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(out),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => out.push('\n'),
+                    Some('r') => out.push('\r'),
+                    Some('t') => out.push('\t'),
+                    Some('u') => {
+                        let hex: String = self.chars.by_ref().take(4).collect();
+                        let c = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32);
+                        out.push(c.ok_or_else(|| format!("invalid escape `\\u{}`", hex))?);
+                    },
+                    Some(c) => out.push(c),
+                    None => return Err("unterminated string".to_string()),
+                },
+                Some(c) => out.push(c),
+                None => return Err("unterminated string".to_string()),
+            }
+        }
+    }
+}
+
+impl Json {
+    fn field(&self, name: &str) -> Result<&Json, String> {
+        match self {
+            Json::Object(fields) => fields.iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value)
+                .ok_or_else(|| format!("missing field `{}`", name)),
+            _ => Err("expected an object".to_string()),
+        }
+    }
+
+    fn number(&self, name: &str) -> Result<u64, String> {
+        match self {
+            Json::Number(n) => Ok(*n),
+            _ => Err(format!("`{}` should be a number", name)),
+        }
+    }
+
+    fn word(&self, name: &str) -> Result<u16, String> {
+        match self.number(name)? {
+            n if n <= 0xFFFF => Ok(n as u16),
+            _ => Err(format!("`{}` should be a 16-bit value", name)),
+        }
+    }
+
+    fn pairs(&self, name: &str) -> Result<Vec<(u16, u16)>, String> {
+        match self {
+            Json::Array(items) => items.iter()
+                .map(|item| match item {
+                    Json::Array(pair) if pair.len() == 2 => Ok((pair[0].word(name)?, pair[1].word(name)?)),
+                    _ => Err(format!("`{}` should hold [address, value] pairs", name)),
+                })
+                .collect(),
+            _ => Err(format!("`{}` should be an array", name)),
+        }
+    }
+}
+
+// the register named in the instruction word, reported even when the value
+// does not change; everything else an instruction writes (R7 on TRAP, R6 on
+// a switch of stacks, R0 from GETC and IN) shows up in the register diff
+fn destination(instr: &Instruction) -> Option<u16> {
+    match *instr {
+        Instruction::Add { dr, .. } | Instruction::And { dr, .. } | Instruction::Not { dr, .. }
+        | Instruction::Ld { dr, .. } | Instruction::Ldi { dr, .. } | Instruction::Ldr { dr, .. }
+        | Instruction::Lea { dr, .. } => Some(dr),
+        Instruction::Jsr { .. } | Instruction::Jsrr { .. } => Some(7),
+        _ => None,
+    }
+}
+
+fn sets_cc(instr: &Instruction) -> bool {
+    matches!(
+        instr,
+        Instruction::Add { .. } | Instruction::And { .. } | Instruction::Not { .. } | Instruction::Ld { .. }
+        | Instruction::Ldi { .. } | Instruction::Ldr { .. } | Instruction::Lea { .. }
+    )
+}
+
+impl TraceRecord {
+
+    /**
+     * describe the step the machine just made, given the registers from
+     * before it; `None` if the step did not complete an instruction
+     */
+    pub fn capture(machine: &Machine, before: &RegFile, symbols: Option<&SymbolTable>) -> Option<TraceRecord> {
+        let (pc, word) = machine.last_instruction()?;
+        let after = &machine.reg_file;
+        let decoded = decode(word).ok();
+        let mut registers: Vec<(u16, u16)> = (0..8)
+            .filter(|reg| before.read_reg(*reg).ok() != after.read_reg(*reg).ok())
+            .chain(decoded.as_ref().and_then(destination))
+            .map(|reg| (reg, after.read_reg(reg).expect("R0-R7 are valid registers")))
+            .collect();
+        registers.sort_unstable();
+        registers.dedup();
+        let accesses = machine.last_accesses();
+        let of_kind = |kind: AccessKind| -> Vec<(u16, u16)> {
+            accesses.iter().filter(|a| a.kind == kind).map(|a| (a.address, a.value)).collect()
+        };
+        let cc_set = decoded.as_ref().map(sets_cc).unwrap_or(false);
+        Some(TraceRecord {
+            count: machine.instruction_count(),
+            pc,
+            word,
+            asm: disassemble_word(pc, word, symbols).text,
+            registers,
+            reads: of_kind(AccessKind::Read),
+            writes: of_kind(AccessKind::Write),
+            cc: if cc_set || before.r_cond != after.r_cond { Some(after.r_cond) } else { None },
+        })
+    }
+
+    // one JSON object, all numbers are plain integers
+    pub fn to_json(&self) -> String {
+        let pairs = |pairs: &[(u16, u16)]| -> String {
+            pairs.iter().map(|(a, v)| format!("[{},{}]", a, v)).collect::<Vec<_>>().join(",")
+        };
+        let registers = self.registers.iter()
+            .map(|(reg, value)| format!("\"R{}\":{}", reg, value))
+            .collect::<Vec<_>>().join(",");
+        format!(
+            "{{\"count\":{},\"pc\":{},\"word\":{},\"asm\":\"{}\",\"registers\":{{{}}},\"reads\":[{}],\"writes\":[{}],\"cc\":{}}}",
+            self.count, self.pc, self.word, json_escape(&self.asm), registers,
+            pairs(&self.reads), pairs(&self.writes),
+            match self.cc {
+                Some(cc) => format!("\"{}\"", cc_name(cc)),
+                None => "null".to_string(),
+            },
+        )
+    }
+
+    // read back a line written by `to_json`
+    pub fn from_json(line: &str) -> Result<TraceRecord, String> {
+        let mut parser = JsonParser { chars: line.chars().peekable() };
+        let json = parser.value()?;
+        parser.skip_space();
+        if parser.chars.next().is_some() {
+            return Err("unexpected text after the record".to_string());
+        }
+        let registers = match json.field("registers")? {
+            Json::Object(fields) => fields.iter()
+                .map(|(name, value)| match name.strip_prefix('R').and_then(|n| n.parse::<u16>().ok()) {
+                    Some(reg) if reg < 8 => Ok((reg, value.word(name)?)),
+                    _ => Err(format!("unknown register `{}`", name)),
+                })
+                .collect::<Result<Vec<_>, String>>()?,
+            _ => return Err("`registers` should be an object".to_string()),
+        };
+        Ok(TraceRecord {
+            count: json.field("count")?.number("count")?,
+            pc: json.field("pc")?.word("pc")?,
+            word: json.field("word")?.word("word")?,
+            asm: match json.field("asm")? {
+                Json::Str(asm) => asm.clone(),
+                _ => return Err("`asm` should be a string".to_string()),
+            },
+            registers,
+            reads: json.field("reads")?.pairs("reads")?,
+            writes: json.field("writes")?.pairs("writes")?,
+            cc: match json.field("cc")? {
+                Json::Null => None,
+                Json::Str(name) => Some(cc_value(name).ok_or_else(|| format!("unknown condition code `{}`", name))?),
+                _ => return Err("`cc` should be a string or null".to_string()),
+            },
+        })
+    }
+
+    /**
+     * read back a line in the text form; the disassembly comes back with
+     * single spaces between its words
+     */
+    pub fn from_text(line: &str) -> Result<TraceRecord, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.len() < 3 {
+            return Err("expected a count, PC and instruction word".to_string());
+        }
+        let count = words[0].parse().map_err(|_| format!("invalid count `{}`", words[0]))?;
+        let pc = parse_hex(words[1]).ok_or_else(|| format!("invalid PC `{}`", words[1]))?;
+        let word = parse_hex(words[2]).ok_or_else(|| format!("invalid instruction word `{}`", words[2]))?;
+        let mut record = TraceRecord {
+            count, pc, word, asm: String::new(), registers: Vec::new(), reads: Vec::new(), writes: Vec::new(), cc: None,
+        };
+        // effects follow the disassembly, so take them from the end
+        let mut end = words.len();
+        while end > 3 {
+            let effect = words[end - 1];
+            if let Some(cc) = effect.strip_prefix("CC=").and_then(cc_value) {
+                record.cc = Some(cc);
+            } else if let Some((reg, value)) = effect.strip_prefix('R').and_then(|e| e.split_once('=')) {
+                match (reg.parse::<u16>(), parse_hex(value)) {
+                    (Ok(reg), Some(value)) if reg < 8 => record.registers.insert(0, (reg, value)),
+                    _ => break,
+                }
+            } else if let Some((address, value)) = effect.strip_prefix('[').and_then(|e| e.split_once("]->")) {
+                match (parse_hex(address), parse_hex(value)) {
+                    (Some(address), Some(value)) => record.reads.insert(0, (address, value)),
+                    _ => break,
+                }
+            } else if let Some((address, value)) = effect.strip_prefix('[').and_then(|e| e.split_once("]<-")) {
+                match (parse_hex(address), parse_hex(value)) {
+                    (Some(address), Some(value)) => record.writes.insert(0, (address, value)),
+                    _ => break,
+                }
+            } else {
+                break;
+            }
+            end -= 1;
+        }
+        record.asm = words[3..end].join(" ");
+        Ok(record)
+    }
+
+    // read back either form, JSON objects start with `{`
+    pub fn parse(line: &str) -> Result<TraceRecord, String> {
+        if line.trim_start().starts_with('{') {
+            TraceRecord::from_json(line)
+        } else {
+            TraceRecord::from_text(line)
+        }
+    }
+}
+
+// the compact text form: count, PC, word, disassembly, then the effects
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut line = format!("{:>8}  x{:04X}  x{:04X}  {:<24}", self.count, self.pc, self.word, self.asm);
+        for (reg, value) in self.registers.iter() {
+            line += &format!(" R{}=x{:04X}", reg, value);
+        }
+        for (address, value) in self.reads.iter() {
+            line += &format!(" [x{:04X}]->x{:04X}", address, value);
+        }
+        for (address, value) in self.writes.iter() {
+            line += &format!(" [x{:04X}]<-x{:04X}", address, value);
+        }
+        if let Some(cc) = self.cc {
+            line += &format!(" CC={}", cc_name(cc));
+        }
+        f.write_str(line.trim_end())
+    }
+}
+
+// steps a machine, writing a trace record for every instruction it executes
+pub struct Tracer<W: Write> {
+    out: W,
+    format: TraceFormat,
+    symbols: Option<SymbolTable>,
+}
+
+impl<W: Write> Tracer<W> {
+
+    pub fn new(out: W, format: TraceFormat) -> Tracer<W> {
+        Tracer { out, format, symbols: None }
+    }
+
+    // label branch targets in the disassembly
+    pub fn with_symbols(mut self, symbols: SymbolTable) -> Tracer<W> {
+        self.symbols = Some(symbols);
+        self
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    // step the machine once and trace the instruction it executed
+    pub fn step(&mut self, machine: &mut Machine) -> Result<StepOutcome, VmError> {
+        let before = machine.reg_file.clone();
+        let outcome = machine.step()?;
+        if outcome != StepOutcome::WaitingForInput {
+            if let Some(record) = TraceRecord::capture(machine, &before, self.symbols.as_ref()) {
+                match self.format {
+                    TraceFormat::Text => writeln!(self.out, "{}", record)?,
+                    TraceFormat::Json => writeln!(self.out, "{}", record.to_json())?,
+                }
+            }
+        }
+        Ok(outcome)
+    }
+
+    // like `Machine::run`, tracing every instruction
+    pub fn run(&mut self, machine: &mut Machine) -> Result<StepOutcome, VmError> {
+        loop {
+            let outcome = self.step(machine)?;
+            if outcome != StepOutcome::Continue {
+                self.out.flush()?;
+                return Ok(outcome);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tracer_test {
+
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::console::BufferConsole;
+
+    fn trace(format: TraceFormat) -> String {
+        let program = assemble("
+            .ORIG x3000
+            LD R1, VALUE
+            STR R1, R1, #0
+            JSR DONE
+            VALUE .FILL x4000
+            DONE HALT
+            .END
+        ").unwrap();
+        let mut machine = Machine::with_console(Box::new(BufferConsole::new("")));
+        machine.load_image(&program.image).unwrap();
+        let mut tracer = Tracer::new(Vec::new(), format).with_symbols(program.symbols);
+        assert_eq!(tracer.run(&mut machine).unwrap(), StepOutcome::Halted);
+        String::from_utf8(tracer.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn text_trace() {
+        let text = trace(TraceFormat::Text);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "       1  x3000  x2202  LD R1, x3003 ; VALUE     R1=x4000 [x3003]->x4000 CC=P");
+        assert_eq!(lines[1], "       2  x3001  x7240  STR R1, R1, #0           [x4000]<-x4000");
+        assert_eq!(lines[2], "       3  x3002  x4801  JSR x3004 ; DONE         R7=x3003");
+        assert_eq!(lines[3], "       4  x3004  xF025  HALT");
+    }
+
+    #[test]
+    fn json_trace() {
+        let text = trace(TraceFormat::Json);
+        assert_eq!(
+            text.lines().next().unwrap(),
+            "{\"count\":1,\"pc\":12288,\"word\":8706,\"asm\":\"LD R1, x3003 ; VALUE\",\"registers\":{\"R1\":16384},\"reads\":[[12291,16384]],\"writes\":[],\"cc\":\"P\"}",
+        );
+        assert!(text.lines().nth(1).unwrap().contains("\"writes\":[[16384,16384]],\"cc\":null"));
+    }
+
+    #[test]
+    fn trap_through_the_bundled_os() {
+        let program = assemble(".ORIG x3000\nLD R0, CHAR\nOUT\nHALT\nCHAR .FILL x41\n.END").unwrap();
+        let mut machine = Machine::with_console(Box::new(BufferConsole::new("")));
+        machine.load_os().unwrap();
+        machine.load_image(&program.image).unwrap();
+        let mut tracer = Tracer::new(Vec::new(), TraceFormat::Text);
+        assert_eq!(tracer.run(&mut machine).unwrap(), StepOutcome::Halted);
+        let text = String::from_utf8(tracer.into_inner()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[1], "       2  x3001  xF021  OUT                      R6=x2FFE R7=x3002 [x0021]->x0204 [x0021]->x0204 [x2FFF]<-x8001 [x2FFE]<-x3002");
+        assert_eq!(lines[2], "       3  x0204  x3E5D  ST R7, x0262             [x0262]<-x3002");
+        assert_eq!(lines[11], "      12  x0207  x8000  RTI                      R6=x0000 [x2FFE]->x3002 [x2FFF]->x8001");
+        assert!(lines[12].starts_with("      13  x3002  xF025  HALT                     R6=x2FFE R7=x3003"));
+    }
+
+    #[test]
+    fn read_back() {
+        for format in [TraceFormat::Text, TraceFormat::Json].iter() {
+            let records: Vec<TraceRecord> = trace(*format).lines().map(|line| TraceRecord::parse(line).unwrap()).collect();
+            assert_eq!(records.len(), 4);
+            assert_eq!(records[0].asm, "LD R1, x3003 ; VALUE");
+            assert_eq!(records[0].registers, vec![(1, 0x4000)]);
+            assert_eq!(records[0].reads, vec![(0x3003, 0x4000)]);
+            assert_eq!(records[0].cc, Some(1));
+            assert_eq!(records[1].writes, vec![(0x4000, 0x4000)]);
+            assert_eq!(records[3].word, 0xF025);
+        }
+        assert!(TraceRecord::parse("{\"count\":1}").is_err());
+        assert!(TraceRecord::parse("1 x3000").is_err());
+    }
+}
@@ -0,0 +1,220 @@
+/*
+This is the LC-3 Watch Module, watchpoints stopping the machine when memory
+is accessed or a register changes or starts matching a condition
+*/
+use crate::assembler::lexer::parse_number;
+use crate::memory::{AccessKind, MemoryAccess};
+use crate::register::{RegFile, COND_REG, PC_REG, PSR_REG};
+
+use std::fmt;
+
+// which memory accesses a memory watchpoint reacts to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchAccess {
+    Read,
+    Write,
+    Any,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+
+    pub fn parse(text: &str) -> Option<Comparison> {
+        match text {
+            "==" | "=" => Some(Comparison::Eq),
+            "!=" => Some(Comparison::Ne),
+            "<" => Some(Comparison::Lt),
+            "<=" => Some(Comparison::Le),
+            ">" => Some(Comparison::Gt),
+            ">=" => Some(Comparison::Ge),
+            _ => None,
+        }
+    }
+
+    // compare two words as unsigned values
+    pub fn holds(self, left: u16, right: u16) -> bool {
+        match self {
+            Comparison::Eq => left == right,
+            Comparison::Ne => left != right,
+            Comparison::Lt => left < right,
+            Comparison::Le => left <= right,
+            Comparison::Gt => left > right,
+            Comparison::Ge => left >= right,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            Comparison::Eq => "==",
+            Comparison::Ne => "!=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Watchpoint {
+    // loads and / or stores to any address in first..=last
+    Memory { first: u16, last: u16, access: WatchAccess },
+    // a register (R0-R7, PC, COND or PSR) changing value, or starting to satisfy a condition
+    Register { reg: u16, condition: Option<(Comparison, u16)> },
+}
+
+fn register_name(reg: u16) -> String {
+    match reg {
+        PC_REG => "PC".to_string(),
+        COND_REG => "COND".to_string(),
+        PSR_REG => "PSR".to_string(),
+        reg => format!("R{}", reg),
+    }
+}
+
+// R0-R7, PC, COND (or CC) and PSR
+pub fn register_index(name: &str) -> Option<u16> {
+    match name.to_uppercase().as_str() {
+        "PC" => Some(PC_REG),
+        "COND" | "CC" => Some(COND_REG),
+        "PSR" => Some(PSR_REG),
+        name => match name.strip_prefix('R')?.parse::<u16>() {
+            Ok(reg) if reg < 8 => Some(reg),
+            _ => None,
+        },
+    }
+}
+
+impl Watchpoint {
+
+    /**
+     * parse a register watch: `R6` stops whenever R6 changes, `R6 < x2F00`
+     * stops when the condition becomes true
+     */
+    pub fn parse_register(text: &str) -> Result<Watchpoint, String> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let reg_of = |name: &str| register_index(name).ok_or_else(|| format!("unknown register `{}`", name));
+        match words.as_slice() {
+            [reg] => Ok(Watchpoint::Register { reg: reg_of(reg)?, condition: None }),
+            [reg, op, value] => {
+                let comparison = Comparison::parse(op).ok_or_else(|| format!("unknown comparison `{}`", op))?;
+                let value = match parse_number(value) {
+                    Some(value) if (-0x8000..=0xFFFF).contains(&value) => value as u16,
+                    _ => return Err(format!("expected a 16-bit value, found `{}`", value)),
+                };
+                Ok(Watchpoint::Register { reg: reg_of(reg)?, condition: Some((comparison, value)) })
+            },
+            _ => Err(format!("expected `<reg>` or `<reg> <op> <value>`, found `{}`", text)),
+        }
+    }
+
+    /**
+     * whether the last instruction, which changed the registers from `before`
+     * to `after` and made `accesses`, triggers this watchpoint; the returned
+     * message says what happened
+     */
+    pub fn check(&self, before: &RegFile, after: &RegFile, accesses: &[MemoryAccess]) -> Option<String> {
+        match *self {
+            Watchpoint::Memory { first, last, access } => {
+                accesses.iter()
+                    .filter(|a| a.address >= first && a.address <= last)
+                    .find(|a| match (access, a.kind) {
+                        (WatchAccess::Any, _) => true,
+                        (WatchAccess::Read, kind) => kind == AccessKind::Read,
+                        (WatchAccess::Write, kind) => kind == AccessKind::Write,
+                    })
+                    .map(|a| match a.kind {
+                        AccessKind::Read => format!("read x{:04X} = x{:04X}", a.address, a.value),
+                        AccessKind::Write => format!("write x{:04X}: x{:04X} -> x{:04X}", a.address, a.old, a.value),
+                    })
+            },
+            Watchpoint::Register { reg, condition } => {
+                let old = before.read_reg(reg).ok()?;
+                let new = after.read_reg(reg).ok()?;
+                let name = register_name(reg);
+                match condition {
+                    None if old != new => Some(format!("{}: x{:04X} -> x{:04X}", name, old, new)),
+                    None => None,
+                    Some((comparison, value)) if comparison.holds(new, value) && !comparison.holds(old, value) => {
+                        Some(format!("{} (now x{:04X})", self, new))
+                    },
+                    Some(_) => None,
+                }
+            },
+        }
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Watchpoint::Memory { first, last, access } => {
+                let kind = match access {
+                    WatchAccess::Read => "read",
+                    WatchAccess::Write => "write",
+                    WatchAccess::Any => "access",
+                };
+                if first == last {
+                    write!(f, "{} x{:04X}", kind, first)
+                } else {
+                    write!(f, "{} x{:04X}-x{:04X}", kind, first, last)
+                }
+            },
+            Watchpoint::Register { reg, condition: None } => write!(f, "{} changes", register_name(reg)),
+            Watchpoint::Register { reg, condition: Some((comparison, value)) } => {
+                write!(f, "{} {} x{:04X}", register_name(reg), comparison.symbol(), value)
+            },
+        }
+    }
+}
+
+// a watchpoint that stopped the machine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchHit {
+    pub index: usize,
+    pub message: String,
+}
+
+#[cfg(test)]
+mod watch_test {
+
+    use super::*;
+
+    #[test]
+    fn memory_watch() {
+        let regs = RegFile::new();
+        let watch = Watchpoint::Memory { first: 0x2F00, last: 0x2FFF, access: WatchAccess::Write };
+        let read = MemoryAccess { kind: AccessKind::Read, address: 0x2F10, value: 1, old: 1 };
+        let write = MemoryAccess { kind: AccessKind::Write, address: 0x2F10, value: 2, old: 1 };
+        assert_eq!(watch.check(&regs, &regs, &[read]), None);
+        assert_eq!(watch.check(&regs, &regs, &[read, write]), Some("write x2F10: x0001 -> x0002".to_string()));
+        assert_eq!(watch.to_string(), "write x2F00-x2FFF");
+    }
+
+    #[test]
+    fn register_conditions() {
+        let watch = Watchpoint::parse_register("R6 < x2F00").unwrap();
+        assert_eq!(watch.to_string(), "R6 < x2F00");
+        let mut before = RegFile::new();
+        before.update_reg(6, 0x2F00).unwrap();
+        let mut after = before.clone();
+        after.update_reg(6, 0x2EFF).unwrap();
+        assert_eq!(watch.check(&before, &after, &[]), Some("R6 < x2F00 (now x2EFF)".to_string()));
+        // only when the condition becomes true
+        assert_eq!(watch.check(&after, &after, &[]), None);
+        let changes = Watchpoint::parse_register("cc").unwrap();
+        after.update_reg(COND_REG, 4).unwrap();
+        assert_eq!(changes.check(&before, &after, &[]), Some("COND: x0000 -> x0004".to_string()));
+        assert!(Watchpoint::parse_register("R9").is_err());
+        assert!(Watchpoint::parse_register("R1 ~ 3").is_err());
+    }
+}
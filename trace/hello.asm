@@ -0,0 +1,11 @@
+; print a greeting a few times
+        .ORIG x3000
+        AND R1, R1, #0
+        ADD R1, R1, #3      ; loop counter
+LOOP    LEA R0, MSG
+        PUTS
+        ADD R1, R1, #-1
+        BRp LOOP
+        HALT
+MSG     .STRINGZ "Hello, LC-3!\n"
+        .END
@@ -2,4 +2,5 @@
 ADD R1, R1, #1
 ADD R2, R2, #2
 ADD R3, R3, #3
+HALT
 .END